@@ -0,0 +1,510 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::history::{History, WindowStats};
+use crate::sink::{ReadingBatch, Result, Sink};
+use crate::sun2000::{DeviceInfo, HealthLimits, Parameter, Sun2000Stats};
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Map, Value};
+use simplelog::*;
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+pub const API_STREAM_BUFFER: usize = 16; //polls buffered for a slow stream client before skipping
+pub const API_STREAM_KEEPALIVE_SECS: u64 = 15;
+
+/// Self-contained dashboard page, embedded to work without the internet access
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+/// Latest poll results, returned by the API
+#[derive(Default)]
+struct ApiState {
+    device: Arc<DeviceInfo>,
+    params: BTreeMap<String, Parameter>,
+    /// live stream of the polls, dropped on shutdown to end the streams
+    events: Option<broadcast::Sender<Arc<ReadingBatch>>>,
+}
+
+pub struct ApiSink {
+    pub name: String,
+    pub listen: SocketAddr,
+    pub stats: Arc<Sun2000Stats>,
+    pub history: Arc<History>,
+    pub health_limits: HealthLimits,
+    state: Arc<Mutex<ApiState>>,
+    server: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+}
+
+fn parameter_json(p: &Parameter) -> Value {
+    let mut value = json!({
+        "value": p.get_json_value(),
+        "unit": p.unit,
+        "description": p.desc,
+        "time": p.time as u64,
+    });
+    if let Some(decoded) = p.get_decoded_value() {
+        value["decoded"] = json!(decoded);
+    }
+    value
+}
+
+fn device_json(device: &DeviceInfo) -> Value {
+    json!({
+        "name": device.name,
+        "model_name": device.model_name,
+        "serial_number": device.serial_number,
+        "product_number": device.product_number,
+        "software_version": device.software_version,
+    })
+}
+
+/// Active alarms of all alarm registers
+fn alarms_json(state: &ApiState) -> Value {
+    let alarms: Vec<&str> = state
+        .params
+        .values()
+        .filter(|p| p.unit == Some("alarm_bitfield16"))
+        .filter_map(|p| p.get_decoded_value())
+        .flatten()
+        .collect();
+    json!(alarms)
+}
+
+fn status_json(state: &ApiState, stats: &Sun2000Stats) -> Value {
+    let last_error = match stats.last_error.lock() {
+        Ok(e) => match &*e {
+            Some((time, message)) => json!({"time": *time as u64, "message": message}),
+            None => Value::Null,
+        },
+        Err(_) => Value::Null,
+    };
+    let register_errors: Map<String, Value> = match stats.register_errors.lock() {
+        Ok(errors) => errors.iter().map(|(k, v)| (k.clone(), json!(v))).collect(),
+        Err(_) => Map::new(),
+    };
+    let last_poll = match stats.last_poll_ms.load(Ordering::Relaxed) {
+        0 => Value::Null,
+        time => json!(time),
+    };
+    json!({
+        "connected": stats.connected.load(Ordering::Relaxed),
+        "device_status": state.params.get("device_status").and_then(|p| p.get_decoded_value()).and_then(|d| d.first().copied()),
+        "alarms": alarms_json(state),
+        "poll_ok": stats.poll_ok.load(Ordering::Relaxed),
+        "poll_errors": stats.poll_errors.load(Ordering::Relaxed),
+        "reconnects": stats.reconnects.load(Ordering::Relaxed),
+        "query_time_ms": stats.query_time_ms.load(Ordering::Relaxed),
+        "last_poll": last_poll,
+        "last_error": last_error,
+        "register_errors": register_errors,
+    })
+}
+
+fn stats_json(stats: &WindowStats) -> Value {
+    json!({
+        "min": stats.min,
+        "max": stats.max,
+        "avg": stats.avg,
+        "last": stats.last,
+        "count": stats.count,
+    })
+}
+
+/// 200 when healthy, 503 with the reason otherwise
+fn health_response(stats: &Sun2000Stats, limits: &HealthLimits) -> Response<Body> {
+    let (status, reason) = match stats.check_health(limits) {
+        Ok(()) => (StatusCode::OK, Value::Null),
+        Err(reason) => (StatusCode::SERVICE_UNAVAILABLE, json!(reason)),
+    };
+    let last_poll = match stats.last_poll_ms.load(Ordering::Relaxed) {
+        0 => Value::Null,
+        time => json!(time),
+    };
+    json_response(
+        status,
+        &json!({
+            "healthy": status == StatusCode::OK,
+            "reason": reason,
+            "connected": stats.connected.load(Ordering::Relaxed),
+            "last_poll": last_poll,
+            "sink_backlog": stats.sink_backlog.load(Ordering::Relaxed),
+        }),
+    )
+}
+
+/// `/api/history`: stats of all the parameters, `/api/history/<name>`: stats and the samples
+/// (time, avg, min, max) of a single one; `?window=<secs>` limits the range
+fn history_response(name: Option<&str>, query: Option<&str>, history: &History) -> Response<Body> {
+    let window = query
+        .and_then(|q| q.split('&').find_map(|kv| kv.strip_prefix("window=")))
+        .and_then(|w| w.parse().ok())
+        .map(Duration::from_secs);
+    match name {
+        None => {
+            let all: Map<String, Value> = history
+                .names()
+                .into_iter()
+                .filter_map(|name| history.stats(&name, window).map(|s| (name, stats_json(&s))))
+                .collect();
+            json_response(StatusCode::OK, &Value::Object(all))
+        }
+        Some(name) => match history.stats(name, window) {
+            Some(stats) => {
+                let samples: Vec<Value> = history
+                    .buckets(name, window)
+                    .iter()
+                    .map(|b| json!([b.time, b.avg(), b.min, b.max]))
+                    .collect();
+                json_response(
+                    StatusCode::OK,
+                    &json!({
+                        "resolution": history.resolution.as_secs(),
+                        "stats": stats_json(&stats),
+                        "samples": samples,
+                    }),
+                )
+            }
+            None => json_response(StatusCode::NOT_FOUND, &json!({"error": "not found"})),
+        },
+    }
+}
+
+/// Single server-sent event with the requested parameters of the poll
+fn stream_event(batch: &ReadingBatch, filter: &Option<Vec<String>>) -> String {
+    let params: Map<String, Value> = batch
+        .parameters
+        .iter()
+        .filter(|p| filter.as_ref().is_none_or(|f| f.contains(&p.name)))
+        .map(|p| (p.name.clone(), json!({"value": p.get_json_value(), "unit": p.unit})))
+        .collect();
+    let data = json!({
+        "time": batch.timestamp as u64,
+        "inverter": batch.device.name,
+        "initial_read": batch.metadata.initial_read,
+        "parameters": params,
+    });
+    format!("event: reading\ndata: {}\n\n", data)
+}
+
+/// Streams the polls to a single client; a slow client is skipping the polls
+/// instead of slowing down the others
+async fn stream(
+    mut rx: broadcast::Receiver<Arc<ReadingBatch>>,
+    mut body: hyper::body::Sender,
+    filter: Option<Vec<String>>,
+) {
+    loop {
+        let message = tokio::select! {
+            res = rx.recv() => match res {
+                Ok(batch) => stream_event(&batch, &filter),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    format!("event: lagged\ndata: {{\"skipped\": {}}}\n\n", skipped)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = tokio::time::sleep(Duration::from_secs(API_STREAM_KEEPALIVE_SECS)) => ":keepalive\n\n".to_string(),
+        };
+        if body.send_data(message.into()).await.is_err() {
+            //client disconnected
+            break;
+        }
+    }
+}
+
+fn stream_response(state: &ApiState, query: Option<&str>) -> Response<Body> {
+    let rx = match &state.events {
+        Some(tx) => tx.subscribe(),
+        None => return json_response(StatusCode::SERVICE_UNAVAILABLE, &json!({"error": "shutting down"})),
+    };
+    //?params=name1,name2 to receive only the selected parameters
+    let filter = query
+        .and_then(|q| q.split('&').find_map(|kv| kv.strip_prefix("params=")))
+        .map(|list| list.split(',').map(|s| s.to_string()).collect());
+    let (body_tx, body) = Body::channel();
+    tokio::spawn(stream(rx, body_tx, filter));
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(body)
+        .unwrap_or_default()
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(value.to_string()))
+        .unwrap_or_default()
+}
+
+fn route(
+    path: &str,
+    query: Option<&str>,
+    state: &ApiState,
+    stats: &Sun2000Stats,
+    history: &History,
+    health_limits: &HealthLimits,
+) -> Response<Body> {
+    match path.trim_end_matches('/') {
+        "/healthz" => health_response(stats, health_limits),
+        "" | "/dashboard" => Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(DASHBOARD_HTML))
+            .unwrap_or_default(),
+        "/api/history" => history_response(None, query, history),
+        "/api/stream" => stream_response(state, query),
+        "/api/parameters" => {
+            let params: Map<String, Value> =
+                state.params.iter().map(|(name, p)| (name.clone(), parameter_json(p))).collect();
+            json_response(StatusCode::OK, &Value::Object(params))
+        }
+        "/api/device" => json_response(StatusCode::OK, &device_json(&state.device)),
+        "/api/alarms" => json_response(StatusCode::OK, &alarms_json(state)),
+        "/api/status" => json_response(StatusCode::OK, &status_json(state, stats)),
+        p => match p.strip_prefix("/api/history/") {
+            Some(name) => history_response(Some(name), query, history),
+            None => match p.strip_prefix("/api/parameters/").and_then(|name| state.params.get(name)) {
+                Some(param) => json_response(StatusCode::OK, &parameter_json(param)),
+                None => json_response(StatusCode::NOT_FOUND, &json!({"error": "not found"})),
+            },
+        },
+    }
+}
+
+async fn handle(
+    req: Request<Body>,
+    state: Arc<Mutex<ApiState>>,
+    stats: Arc<Sun2000Stats>,
+    history: Arc<History>,
+    health_limits: HealthLimits,
+) -> std::result::Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET {
+        return Ok(json_response(StatusCode::METHOD_NOT_ALLOWED, &json!({"error": "method not allowed"})));
+    }
+    Ok(match state.lock() {
+        Ok(state) => route(req.uri().path(), req.uri().query(), &state, &stats, &history, &health_limits),
+        Err(_) => json_response(StatusCode::INTERNAL_SERVER_ERROR, &json!({"error": "state unavailable"})),
+    })
+}
+
+impl ApiSink {
+    pub fn new(
+        name: String,
+        listen: SocketAddr,
+        stats: Arc<Sun2000Stats>,
+        history: Arc<History>,
+        health_limits: HealthLimits,
+    ) -> Self {
+        Self {
+            name,
+            listen,
+            stats,
+            history,
+            health_limits,
+            state: Arc::new(Mutex::new(ApiState {
+                events: Some(broadcast::channel(API_STREAM_BUFFER).0),
+                ..Default::default()
+            })),
+            server: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for ApiSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    //the latest values with their timestamps and every poll in the stream
+    fn deadband(&self) -> bool {
+        false
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        let service_state = self.state.clone();
+        let service_stats = self.stats.clone();
+        let service_history = self.history.clone();
+        let health_limits = self.health_limits;
+        let make_service = make_service_fn(move |_conn| {
+            let state = service_state.clone();
+            let stats = service_stats.clone();
+            let history = service_history.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle(req, state.clone(), stats.clone(), history.clone(), health_limits)
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&self.listen)?.serve(make_service);
+        info!(
+            "<i>{}</>: listening on <u>http://{}/api/</>, dashboard: <u>http://{}/</>",
+            self.name, self.listen, self.listen
+        );
+        let (tx_shutdown, rx_shutdown) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async move {
+            let _ = rx_shutdown.await;
+        });
+        let server_name = self.name.clone();
+        let server_future = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("<i>{}</>: server error: <b>{}</>", server_name, e);
+            }
+        });
+        self.server = Some((tx_shutdown, server_future));
+        Ok(())
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        if let Ok(mut state) = self.state.lock() {
+            state.device = batch.device.clone();
+            for p in &batch.parameters {
+                state.params.insert(p.name.clone(), p.clone());
+            }
+            if let Some(tx) = &state.events {
+                //no receivers is not an error
+                let _ = tx.send(Arc::new(batch.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        //end the live streams, the graceful shutdown is waiting for them
+        if let Ok(mut state) = self.state.lock() {
+            state.events = None;
+        }
+        if let Some((tx_shutdown, mut server_future)) = self.server.take() {
+            let _ = tx_shutdown.send(());
+            if timeout(Duration::from_secs(2), &mut server_future).await.is_err() {
+                debug!("{}: server not finished in time", self.name);
+                server_future.abort();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{BatchMetadata, SinkDispatcher};
+    use crate::sun2000::ParamKind;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    async fn get(url: &str) -> (u16, Value) {
+        let response = reqwest::get(url).await.unwrap();
+        let status = response.status().as_u16();
+        (status, serde_json::from_str(&response.text().await.unwrap()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn healthz() {
+        //a free port
+        let listen = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stats = Arc::new(Sun2000Stats::default());
+        let limits = HealthLimits {
+            poll_interval: Duration::from_secs(10),
+            max_missed_polls: 3,
+            max_sink_backlog: 5,
+        };
+        let history = Arc::new(History::new(Duration::from_secs(60), Duration::from_secs(10)));
+        let mut api = ApiSink::new("api".into(), listen, stats.clone(), history, limits);
+        api.start().await.unwrap();
+        let url = format!("http://{}/healthz", listen);
+
+        let (status, body) = get(&url).await;
+        assert_eq!(status, 503);
+        assert_eq!(body["healthy"], false);
+        assert_eq!(body["reason"], "no successful poll yet");
+
+        //the inverter is asleep at night
+        stats.night.store(true, Ordering::Relaxed);
+        assert_eq!(get(&url).await.0, 200);
+        stats.night.store(false, Ordering::Relaxed);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        stats.last_poll_ms.store(now - 5_000, Ordering::Relaxed);
+        stats.connected.store(true, Ordering::Relaxed);
+        let (status, body) = get(&url).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["healthy"], true);
+        assert_eq!(body["connected"], true);
+        assert_eq!(body["last_poll"], now - 5_000);
+
+        //3 missed polls
+        stats.last_poll_ms.store(now - 31_000, Ordering::Relaxed);
+        let (status, body) = get(&url).await;
+        assert_eq!(status, 503);
+        assert_eq!(body["reason"], "no successful poll for 31 secs");
+
+        stats.last_poll_ms.store(now, Ordering::Relaxed);
+        stats.sink_backlog.store(6, Ordering::Relaxed);
+        let (status, body) = get(&url).await;
+        assert_eq!(status, 503);
+        assert_eq!(body["reason"], "sink backlog of 6 batches");
+        assert_eq!(body["sink_backlog"], 6);
+
+        api.stop().await.unwrap();
+        assert!(reqwest::get(&url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn deadband_bypassed() {
+        let listen = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let limits = HealthLimits {
+            poll_interval: Duration::from_secs(10),
+            max_missed_polls: 3,
+            max_sink_backlog: 5,
+        };
+        let history = Arc::new(History::new(Duration::from_secs(60), Duration::from_secs(10)));
+        let api = ApiSink::new("api".into(), listen, Arc::new(Sun2000Stats::default()), history, limits);
+        let dispatcher = SinkDispatcher::default();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let task = dispatcher.spawn(Box::new(api), cancel.clone());
+
+        //the server is started by the sink task
+        let url = format!("http://{}/api/parameters/active_power", listen);
+        let mut stream = loop {
+            match reqwest::get(format!("http://{}/api/stream", listen)).await {
+                Ok(response) => break response,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        for time in [1000, 2000] {
+            let batch = ReadingBatch {
+                device: Arc::new(DeviceInfo::default()),
+                timestamp: time,
+                parameters: vec![Parameter::new("active_power", ParamKind::NumberI32(Some(4250)), time, None, Some("W"), 1, 32080, 2, false, true)],
+                metadata: BatchMetadata::default(),
+                events: vec![],
+            };
+            //the unchanged value is filtered out by the deadband of the other sinks
+            let filtered = if time == 1000 { None } else { Some(vec![]) };
+            dispatcher.dispatch("test", batch, filtered);
+        }
+        let mut events = String::new();
+        while events.matches("event: reading").count() < 2 {
+            let chunk = timeout(Duration::from_secs(5), stream.chunk()).await.unwrap().unwrap().unwrap();
+            events.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        assert_eq!(events.matches("\"active_power\"").count(), 2, "{}", events);
+        let (_, parameter) = get(&url).await;
+        assert_eq!(parameter["time"], 2000);
+
+        dispatcher.remove("api");
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::history::History;
+use crate::sink::{Result, SinkDispatcher};
+use crate::daemon;
+use crate::notify::NotifySettings;
+use crate::sun2000::{Parameter, PollSettings, PollTier, Sun2000, Sun2000Stats, PERIODIC_TIERS};
+use clap::{Parser, Subcommand};
+use serde_json::{json, Map, Value};
+use simplelog::*;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tokio_modbus::prelude::*;
+
+pub const CLI_TIMEOUT_SECS: u64 = 5;
+pub const DUMP_CHUNK_REGISTERS: u16 = 64; //max registers in a single read request
+
+#[derive(Parser)]
+#[command(version, about = "hard - home automation rust-daemon")]
+pub struct Cli {
+    /// config file
+    #[arg(short, long, default_value = "hard.conf")]
+    pub config: String,
+    /// console and log file level: off, error, warn, info, debug, trace (overrides the config)
+    #[arg(short, long)]
+    pub log_level: Option<LevelFilter>,
+    /// log to the console only, ignoring the log file from the config (eg. when running under systemd)
+    #[arg(short, long)]
+    pub foreground: bool,
+    /// validate the config file and exit
+    #[arg(long)]
+    pub check_config: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// run the daemon (default)
+    Run,
+    /// poll the inverter once and print all the parameters
+    Read {
+        /// print json instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// look for the inverters responding on the Slave IDs of the configured host
+    Scan {
+        #[arg(long, default_value_t = 0)]
+        from: u8,
+        #[arg(long, default_value_t = 16)]
+        to: u8,
+    },
+    /// write the values to the holding registers starting at the address and read them back
+    Write {
+        register: u16,
+        #[arg(required = true)]
+        values: Vec<u16>,
+        /// Slave ID, by default the one implied by the connection type
+        #[arg(long)]
+        slave: Option<u8>,
+    },
+    /// print the raw values of the holding registers
+    DumpRegisters {
+        start: u16,
+        #[arg(default_value_t = 1)]
+        count: u16,
+        /// Slave ID, by default the one implied by the connection type
+        #[arg(long)]
+        slave: Option<u8>,
+    },
+    /// print the config with the environment and secret file overrides applied, secrets redacted
+    DumpConfig,
+}
+
+/// Inverter client for the one-shot commands, with the settings from the config
+fn sun2000(config: &Config) -> Result<Sun2000> {
+    let host = config.sun2000.host.clone().ok_or("[sun2000] host is not configured")?;
+    Ok(Sun2000 {
+        name: "sun2000".to_string(),
+        host_port: host,
+        stats: Arc::new(Sun2000Stats::default()),
+        mode_change_script: None,
+        dongle_connection: config.sun2000.dongle_connection,
+        sinks: Arc::new(SinkDispatcher::default()),
+        history: Arc::new(History::new(Duration::ZERO, Duration::from_secs(1))),
+        settings: watch::channel(PollSettings {
+            //everything is read, regardless of the configured tiers
+            disabled_params: vec![],
+            param_tiers: BTreeMap::new(),
+            night: None,
+            rules: vec![],
+            notify: NotifySettings::default(),
+            ..daemon::poll_settings(config)
+        })
+        .1,
+    })
+}
+
+/// Runs the one-shot command
+pub async fn execute(command: Command, config: &Config) -> Result<()> {
+    match command {
+        Command::Run => Ok(()),
+        Command::Read { json } => read(config, json).await,
+        Command::Scan { from, to } => scan(config, from, to).await,
+        Command::Write { register, values, slave } => write(config, register, &values, slave).await,
+        Command::DumpRegisters { start, count, slave } => dump_registers(config, start, count, slave).await,
+        Command::DumpConfig => {
+            print!("{}", config.dump());
+            Ok(())
+        }
+    }
+}
+
+async fn read(config: &Config, json: bool) -> Result<()> {
+    let mut sun2000 = sun2000(config)?;
+    let ctx = sun2000.connect(None).await?;
+    let parameters = Sun2000::param_table();
+    let (ctx, mut params) = sun2000.read_params(ctx, &parameters, &[PollTier::OnConnect]).await?;
+    let (_, periodic) = sun2000.read_params(ctx, &parameters, &PERIODIC_TIERS).await?;
+    params.extend(periodic);
+
+    if json {
+        let object: Map<String, Value> = params
+            .iter()
+            .filter(|p| p.has_value())
+            .map(|p| (p.name.clone(), json!({"value": p.get_json_value(), "unit": p.unit})))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&Value::Object(object))?);
+    } else {
+        print_table(&params);
+    }
+    Ok(())
+}
+
+fn print_table(params: &[Parameter]) {
+    let width = params.iter().map(|p| p.name.len()).max().unwrap_or_default();
+    for p in params.iter().filter(|p| p.has_value()) {
+        let mut value = p.get_text_value();
+        if let Some(unit) = p.unit.filter(|u| !u.contains('_')) {
+            value = format!("{} {}", value, unit);
+        }
+        if let Some(decoded) = p.get_decoded_value() {
+            value = format!("{} ({})", value, decoded.join(", "));
+        }
+        println!("{:width$}  {}", p.name, value, width = width);
+    }
+}
+
+async fn scan(config: &Config, from: u8, to: u8) -> Result<()> {
+    let sun2000 = sun2000(config)?;
+    let mut ctx = sun2000.connect(Some(from)).await?;
+    let mut found = 0;
+    for id in from..=to {
+        ctx.set_slave(Slave(id));
+        //model name, every inverter has it
+        match timeout(Duration::from_secs(CLI_TIMEOUT_SECS), ctx.read_holding_registers(30000, 15)).await {
+            Ok(Ok(registers)) => {
+                let bytes: Vec<u8> = registers.iter().flat_map(|r| r.to_be_bytes()).collect();
+                let model = String::from_utf8_lossy(&bytes).trim_end_matches('\0').trim().to_string();
+                println!("Slave ID {}: {}", id, model);
+                found += 1;
+            }
+            Ok(Err(e)) => debug!("Slave ID {}: {}", id, e),
+            Err(_) => debug!("Slave ID {}: timeout", id),
+        }
+    }
+    println!("{} inverter(s) found", found);
+    Ok(())
+}
+
+/// The modbus addresses are 16-bit, the range cannot go past the last register
+fn check_range(start: u16, count: usize) -> Result<()> {
+    if start as usize + count > u16::MAX as usize + 1 {
+        return Err(format!("{} registers from {} are past the last register {}", count, start, u16::MAX).into());
+    }
+    Ok(())
+}
+
+async fn write(config: &Config, register: u16, values: &[u16], slave: Option<u8>) -> Result<()> {
+    check_range(register, values.len())?;
+    let sun2000 = sun2000(config)?;
+    let mut ctx = sun2000.connect(slave).await?;
+    //the inverter only accepts the Write Multiple Registers (0x10) function
+    timeout(Duration::from_secs(CLI_TIMEOUT_SECS), ctx.write_multiple_registers(register, values)).await??;
+    let registers =
+        timeout(Duration::from_secs(CLI_TIMEOUT_SECS), ctx.read_holding_registers(register, values.len() as u16))
+            .await??;
+    for (i, value) in registers.iter().enumerate() {
+        println!("{}: {} (0x{:04x})", register as usize + i, value, value);
+    }
+    Ok(())
+}
+
+async fn dump_registers(config: &Config, start: u16, count: u16, slave: Option<u8>) -> Result<()> {
+    check_range(start, count as usize)?;
+    let sun2000 = sun2000(config)?;
+    let mut ctx = sun2000.connect(slave).await?;
+    let end = start as u32 + count as u32;
+    let mut address = start as u32;
+    while address < end {
+        let len = (end - address).min(DUMP_CHUNK_REGISTERS as u32) as u16;
+        match timeout(Duration::from_secs(CLI_TIMEOUT_SECS), ctx.read_holding_registers(address as u16, len)).await {
+            Ok(Ok(registers)) => {
+                for (i, value) in registers.iter().enumerate() {
+                    let bytes = value.to_be_bytes();
+                    let text: String =
+                        bytes.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+                    println!("{:5}: {:5} 0x{:04x} {:6} {}", address as usize + i, value, value, *value as i16, text);
+                }
+            }
+            Ok(Err(e)) => println!("{:5}-{}: {}", address, address + len as u32 - 1, e),
+            Err(_) => println!("{:5}-{}: timeout", address, address + len as u32 - 1),
+        }
+        address += len as u32;
+    }
+    Ok(())
+}
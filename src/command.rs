@@ -0,0 +1,49 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use simplelog::*;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+/// Splits the command line on whitespace (there is no shell involved)
+/// and replaces the placeholders in every argument
+pub fn expand(command: &str, placeholders: &[(&str, &str)]) -> Vec<String> {
+    command
+        .split_whitespace()
+        .map(|arg| {
+            placeholders
+                .iter()
+                .fold(arg.to_string(), |arg, (placeholder, value)| arg.replace(placeholder, value))
+        })
+        .collect()
+}
+
+/// Runs the command, writing its output to the log; `label` is naming it in the log lines
+pub async fn run(name: String, label: &'static str, args: Vec<String>, limit: Duration) {
+    let (program, args) = match args.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    debug!("<i>{}</>: running {}: <u>{}</> {:?}", name, label, program, args);
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        //the command is killed when the timeout drops the future
+        .kill_on_drop(true);
+    match timeout(limit, command.output()).await {
+        Ok(Ok(output)) => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                info!("<i>{}</>: {}: {}", name, label, line);
+            }
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                warn!("<i>{}</>: {}: {}", name, label, line);
+            }
+            if !output.status.success() {
+                error!("<i>{}</>: {} <u>{}</> failed: <b>{}</>", name, label, program, output.status);
+            }
+        }
+        Ok(Err(e)) => error!("<i>{}</>: cannot run {} <u>{}</>: <b>{}</>", name, label, program, e),
+        Err(_) => error!("<i>{}</>: {} <u>{}</> killed after {:?} timeout", name, label, program, limit),
+    }
+}
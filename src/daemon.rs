@@ -0,0 +1,616 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ::influxdb::WriteQuery;
+use async_channel::{Receiver, Sender};
+use futures::future::join_all;
+use simplelog::*;
+use tokio::sync::watch;
+use tokio::task::{self, JoinHandle};
+use tokio::time::timeout;
+use tokio_compat_02::FutureExt;
+
+use crate::config::Config;
+use crate::deadband::DeadbandSettings;
+use crate::history::History;
+use crate::mode_change::ModeChangeScript;
+use crate::notify::{Channel, NotifySettings};
+use crate::rules::Rule;
+use crate::influxdb::{InfluxdbQueue, OverflowPolicy, QueueStats};
+use crate::sink::{Result, Sink, SinkDispatcher};
+use crate::smtp::SmtpSettings;
+use crate::sun2000::{HealthLimits, NightMode, NightSettings, PollSettings, PollTier, Sun2000, Sun2000Stats};
+use crate::{api, file, influxdb, mqtt, postgres, prometheus, pvoutput, sqlite, tsdb, webhook};
+
+pub const DAEMON_TASK_STOP_TIMEOUT_SECS: u64 = 10; //max wait for a task stopped on reload
+
+/// Influxdb writer tasks, only running when `threaded_influxdb` is enabled
+struct InfluxdbWriters {
+    queue: Option<InfluxdbQueue>,
+    cancel_flag: Arc<AtomicBool>,
+    tasks: Vec<JoinHandle<Result<()>>>,
+}
+
+impl InfluxdbWriters {
+    fn start(config: &Config) -> Self {
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let mut tasks = vec![];
+        if !config.influxdb.threaded_influxdb {
+            return Self { queue: None, cancel_flag, tasks };
+        }
+
+        let (tx, rx): (Sender<Vec<WriteQuery>>, Receiver<Vec<WriteQuery>>) =
+            async_channel::bounded(config.influxdb.thread_buffer_size);
+
+        let policy = config.influxdb.overflow_policy;
+        let spill_path = config.influxdb.spill_path.clone();
+        if policy == OverflowPolicy::Spill && spill_path.is_none() {
+            warn!("influxdb: overflow_policy=spill requires spill_path, points will be dropped instead");
+        }
+
+        for i in 0..config.influxdb.thread_number {
+            let worker_cancel_flag = cancel_flag.clone();
+            let mut influxdb = influxdb::InfluxdbWriter {
+                name: format!("influxdb-{}", i),
+                influxdb_url: config.influxdb.influxdb_url.clone(),
+                influxdb_token: config.influxdb.influxdb_token.clone(),
+                influxdb_database: config.influxdb.database.clone(),
+                rx_influxdb: rx.clone(),
+                spill_path: spill_path.clone(),
+            };
+            tasks.push(task::spawn(async move { influxdb.worker(worker_cancel_flag).compat().await }));
+        }
+
+        Self {
+            queue: Some(InfluxdbQueue {
+                tx,
+                rx,
+                policy,
+                block_timeout: Duration::from_secs_f32(config.influxdb.overflow_block_timeout),
+                spill_path,
+                stats: Arc::new(QueueStats::default()),
+            }),
+            cancel_flag,
+            tasks,
+        }
+    }
+
+    fn log_dropped(&self) {
+        if let Some(queue) = &self.queue {
+            if queue.stats.dropped() > 0 {
+                warn!("influxdb: {} points were dropped due to full channel", queue.stats.dropped());
+            }
+        }
+    }
+
+    async fn stop(mut self) {
+        self.cancel_flag.store(true, Ordering::SeqCst);
+        for (i, handle) in self.tasks.drain(..).enumerate() {
+            join_task(&format!("influxdb-{}", i), handle).await;
+        }
+        self.log_dropped();
+    }
+}
+
+struct Sun2000Task {
+    cancel_flag: Arc<AtomicBool>,
+    handle: JoinHandle<Result<()>>,
+}
+
+/// All the tasks created from the config. A reloaded config is applied in place:
+/// only the tasks whose settings were changed are restarted.
+pub struct Daemon {
+    pub config: Config,
+    stats: Arc<Sun2000Stats>,
+    history: Arc<History>,
+    sinks: Arc<SinkDispatcher>,
+    sink_tasks: HashMap<String, JoinHandle<Result<()>>>,
+    influxdb: InfluxdbWriters,
+    sun2000: Option<Sun2000Task>,
+    poll_settings: watch::Sender<PollSettings>,
+    /// stops the sink tasks on shutdown
+    cancel_flag: Arc<AtomicBool>,
+}
+
+fn health_limits(config: &Config) -> HealthLimits {
+    HealthLimits {
+        poll_interval: Duration::from_secs_f32(config.sun2000.poll_interval),
+        max_missed_polls: config.general.health_max_missed_polls,
+        max_sink_backlog: config.general.health_max_sink_backlog,
+    }
+}
+
+pub fn poll_settings(config: &Config) -> PollSettings {
+    let s = &config.sun2000;
+    let mut param_tiers = BTreeMap::new();
+    for (tier, names) in [
+        (PollTier::OnConnect, &s.on_connect_params),
+        (PollTier::Fast, &s.fast_params),
+        (PollTier::Normal, &s.normal_params),
+        (PollTier::Slow, &s.slow_params),
+    ] {
+        for name in names.iter().flatten() {
+            param_tiers.insert(name.clone(), tier);
+        }
+    }
+    PollSettings {
+        poll_interval_sec: s.poll_interval,
+        poll_interval_normal_sec: s.poll_interval_normal,
+        poll_interval_slow_sec: s.poll_interval_slow,
+        partial: s.partial,
+        reconnect_params_threshold: s.reconnect_params_threshold,
+        reconnect_params_wait: s.reconnect_params_wait,
+        disabled_params: s.disabled_params.clone().unwrap_or_default(),
+        param_tiers,
+        deadband: DeadbandSettings {
+            default: config.deadband.default,
+            params: config.deadband.params.clone(),
+            heartbeat: Duration::from_secs_f32(config.deadband.heartbeat),
+        },
+        night: match (s.night_mode, config.general.lat, config.general.lon) {
+            (NightMode::Off, _, _) | (_, None, _) | (_, _, None) => None,
+            (mode, Some(lat), Some(lon)) => Some(NightSettings {
+                lat,
+                lon,
+                mode,
+                poll_interval_sec: s.night_poll_interval,
+                margin: Duration::from_secs_f32(s.night_margin * 60.0),
+            }),
+        },
+        at_night: false,
+        rules: config
+            .rules
+            .iter()
+            .filter_map(|r| {
+                Some(Rule {
+                    name: r.name.clone(),
+                    condition: r.condition.clone()?,
+                    hysteresis: r.hysteresis,
+                    actions: r.actions.clone(),
+                    command: r.command.clone(),
+                    command_timeout: Duration::from_secs_f32(r.command_timeout),
+                    webhook: r.webhook.clone(),
+                    message: r.message.clone(),
+                })
+            })
+            .collect(),
+        notify: notify_settings(config),
+    }
+}
+
+fn notify_settings(config: &Config) -> NotifySettings {
+    let mut channels = vec![];
+    let e = &config.email;
+    if let (Some(host), Some(from), Some(to)) = (&e.host, &e.from, &e.to) {
+        channels.push(Channel::Email(SmtpSettings {
+            host: host.clone(),
+            security: e.security,
+            username: e.username.clone(),
+            password: e.password.clone(),
+            from: from.clone(),
+            to: to.clone(),
+        }));
+    }
+    if let Some(topic) = &config.ntfy.topic {
+        channels.push(Channel::Ntfy {
+            url: config.ntfy.url.clone(),
+            topic: topic.clone(),
+            token: config.ntfy.token.clone(),
+            priority: config.ntfy.priority,
+        });
+    }
+    if let (Some(bot_token), Some(chat_id)) = (&config.telegram.bot_token, &config.telegram.chat_id) {
+        channels.push(Channel::Telegram {
+            api_url: config.telegram.api_url.clone(),
+            bot_token: bot_token.clone(),
+            chat_id: chat_id.clone(),
+        });
+    }
+    if let (Some(url), Some(token)) = (&config.gotify.url, &config.gotify.token) {
+        channels.push(Channel::Gotify {
+            url: url.clone(),
+            token: token.clone(),
+            priority: config.gotify.priority,
+        });
+    }
+    let n = &config.notifications;
+    NotifySettings {
+        channels,
+        faults: n.faults,
+        connection_lost: Duration::from_secs_f32(n.connection_lost * 60.0),
+        title: n.title.clone(),
+        message: n.message.clone(),
+    }
+}
+
+pub fn mode_change_script(config: &Config) -> Option<ModeChangeScript> {
+    let s = &config.sun2000;
+    let command = s.mode_change_script.clone().filter(|command| !command.trim().is_empty())?;
+    Some(ModeChangeScript {
+        command,
+        timeout: Duration::from_secs_f32(s.mode_change_script_timeout),
+        max_runs: s.mode_change_script_max_runs,
+    })
+}
+
+/// Whether the sink has to be (re)started or stopped when going from `old` to `new` config
+fn sink_changed(name: &str, old: &Config, new: &Config) -> bool {
+    old.sink_enabled(name) != new.sink_enabled(name)
+        || match name {
+            "influxdb" => old.influxdb != new.influxdb || old.sun2000.bulk_insert != new.sun2000.bulk_insert,
+            "mqtt" => old.mqtt != new.mqtt,
+            "prometheus" => old.prometheus != new.prometheus,
+            "api" => old.api != new.api || health_limits(old) != health_limits(new),
+            "postgres" => old.postgres != new.postgres,
+            "sqlite" => old.sqlite != new.sqlite,
+            "file" => old.file != new.file,
+            "pvoutput" => old.pvoutput != new.pvoutput,
+            "webhook" => old.webhook != new.webhook,
+            "graphite" => old.graphite != new.graphite,
+            "opentsdb" => old.opentsdb != new.opentsdb,
+            _ => true,
+        }
+}
+
+/// Waits for the stopping task, aborting it when it is not finishing in time
+async fn join_task(name: &str, mut handle: JoinHandle<Result<()>>) {
+    if timeout(Duration::from_secs(DAEMON_TASK_STOP_TIMEOUT_SECS), &mut handle).await.is_err() {
+        warn!("<i>{}</>: task not stopped in time, aborting", name);
+        handle.abort();
+    }
+}
+
+impl Daemon {
+    pub fn start(config: Config) -> Self {
+        let (poll_settings, _) = watch::channel(poll_settings(&config));
+        let mut daemon = Self {
+            stats: Arc::new(Sun2000Stats::default()),
+            history: Arc::new(History::new(
+                Duration::from_secs(config.general.history_duration * 60),
+                Duration::from_secs(config.general.history_resolution),
+            )),
+            sinks: Arc::new(SinkDispatcher::default()),
+            sink_tasks: HashMap::new(),
+            influxdb: InfluxdbWriters::start(&config),
+            sun2000: None,
+            poll_settings,
+            cancel_flag: Arc::new(AtomicBool::new(false)),
+            config,
+        };
+
+        for sink in daemon.create_sinks(&daemon.config) {
+            daemon.spawn_sink(sink);
+        }
+        if daemon.sinks.is_empty() {
+            warn!("no output sinks configured, poll results will be discarded");
+        }
+        daemon.sun2000 = daemon.start_sun2000(&daemon.config);
+        daemon
+    }
+
+    /// Applies the changed config: poll settings are passed to the running poller,
+    /// the changed sinks are restarted and the poller is reconnecting only when
+    /// the connection settings were changed
+    pub async fn reload(&mut self, config: Config) {
+        let old = &self.config;
+        if old.general.log != config.general.log
+            || old.general.history_duration != config.general.history_duration
+            || old.general.history_resolution != config.general.history_resolution
+        {
+            warn!("Config: [general] log and history settings are applied after a restart only");
+        }
+        let influxdb_changed = old.influxdb != config.influxdb;
+        let sun2000_changed = old.sun2000.host != config.sun2000.host
+            || old.sun2000.dongle_connection != config.sun2000.dongle_connection
+            || mode_change_script(old) != mode_change_script(&config);
+
+        //stop the sinks which are changed or removed
+        let changed: Vec<String> = self
+            .sink_tasks
+            .keys()
+            .filter(|name| sink_changed(name, &self.config, &config))
+            .cloned()
+            .collect();
+        for name in changed {
+            info!("<i>{}</>: stopping, the sink settings were changed", name);
+            self.sinks.remove(&name);
+            if let Some(handle) = self.sink_tasks.remove(&name) {
+                join_task(&name, handle).await;
+            }
+        }
+        if influxdb_changed {
+            let writers = std::mem::replace(&mut self.influxdb, InfluxdbWriters::start(&config));
+            writers.stop().await;
+        }
+        //and start the new ones
+        for sink in self.create_sinks(&config) {
+            if !self.sink_tasks.contains_key(sink.name()) {
+                self.spawn_sink(sink);
+            }
+        }
+
+        let settings = poll_settings(&config);
+        let changed = self.poll_settings.borrow().changed_fields(&settings);
+        if !changed.is_empty() {
+            info!("sun2000: poll settings changed: <b>{}</>", changed.join(", "));
+            self.poll_settings.send_replace(settings);
+        }
+        if sun2000_changed {
+            if let Some(sun2000) = self.sun2000.take() {
+                info!("sun2000: restarting, the connection settings were changed");
+                sun2000.cancel_flag.store(true, Ordering::SeqCst);
+                join_task("sun2000", sun2000.handle).await;
+            }
+            self.sun2000 = self.start_sun2000(&config);
+        }
+
+        self.config = config;
+        info!("Config reloaded, running sinks: <b>{}</>", self.sink_names().join(", "));
+    }
+
+    /// Stops all the tasks, returns after all of them are finished
+    pub async fn stop(mut self) {
+        self.cancel_flag.store(true, Ordering::SeqCst);
+        self.influxdb.cancel_flag.store(true, Ordering::SeqCst);
+        let mut futures: Vec<JoinHandle<Result<()>>> = self.sink_tasks.into_values().collect();
+        if let Some(sun2000) = self.sun2000 {
+            sun2000.cancel_flag.store(true, Ordering::SeqCst);
+            futures.push(sun2000.handle);
+        }
+        futures.append(&mut self.influxdb.tasks);
+        let _ = join_all(futures).await;
+        self.influxdb.log_dropped();
+    }
+
+    fn sink_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.sink_tasks.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    fn spawn_sink(&mut self, sink: Box<dyn Sink>) {
+        let name = sink.name().to_string();
+        let handle = self.sinks.spawn(sink, self.cancel_flag.clone());
+        self.sink_tasks.insert(name, handle);
+    }
+
+    fn start_sun2000(&self, config: &Config) -> Option<Sun2000Task> {
+        let host = config.sun2000.host.clone()?;
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let worker_cancel_flag = cancel_flag.clone();
+        let mut sun2000 = Sun2000 {
+            name: "sun2000".to_string(),
+            host_port: host,
+            stats: self.stats.clone(),
+            mode_change_script: mode_change_script(config),
+            dongle_connection: config.sun2000.dongle_connection,
+            sinks: self.sinks.clone(),
+            history: self.history.clone(),
+            settings: self.poll_settings.subscribe(),
+        };
+        let handle = task::spawn(async move { sun2000.worker(worker_cancel_flag).compat().await });
+        Some(Sun2000Task { cancel_flag, handle })
+    }
+
+    /// Sinks fed with the sun2000 poll results, not started yet
+    fn create_sinks(&self, config: &Config) -> Vec<Box<dyn Sink>> {
+        let mut sinks: Vec<Box<dyn Sink>> = vec![];
+        //optional list of enabled sinks, all configured sinks are used by default
+        let sink_enabled = |name: &str| config.sink_enabled(name);
+
+        if config.influxdb.influxdb_url.is_some() && sink_enabled("influxdb") {
+            sinks.push(Box::new(influxdb::InfluxdbSink {
+                name: "influxdb".to_string(),
+                influxdb_url: config.influxdb.influxdb_url.clone(),
+                influxdb_token: config.influxdb.influxdb_token.clone(),
+                influxdb_database: config.influxdb.database.clone(),
+                bulk_insert: config.sun2000.bulk_insert,
+                tx_influxdb: self.influxdb.queue.clone(),
+            }));
+        }
+
+        if let Some(host) = config.mqtt.host.clone().filter(|_| sink_enabled("mqtt")) {
+            let (host, port) = match host.rsplit_once(':') {
+                Some((h, p)) => (h.to_string(), p.parse().unwrap_or(1883)),
+                None => (host, 1883),
+            };
+            let qos = match config.mqtt.qos {
+                1 => rumqttc::QoS::AtLeastOnce,
+                2 => rumqttc::QoS::ExactlyOnce,
+                _ => rumqttc::QoS::AtMostOnce,
+            };
+            let settings = mqtt::MqttSettings {
+                host,
+                port,
+                client_id: config.mqtt.client_id.clone(),
+                username: config.mqtt.username.clone(),
+                password: config.mqtt.password.clone(),
+                topic_prefix: config.mqtt.topic_prefix.clone(),
+                qos,
+                retain: config.mqtt.retain,
+                discovery: config.mqtt.discovery,
+                discovery_prefix: config.mqtt.discovery_prefix.clone(),
+            };
+            sinks.push(Box::new(mqtt::MqttSink::new("mqtt".to_string(), settings)));
+        }
+
+        if let Some(listen) = config.prometheus.listen.filter(|_| sink_enabled("prometheus")) {
+            sinks.push(Box::new(prometheus::PrometheusSink::new(
+                "prometheus".to_string(),
+                listen,
+                self.stats.clone(),
+            )));
+        }
+
+        if let Some(listen) = config.api.listen.filter(|_| sink_enabled("api")) {
+            sinks.push(Box::new(api::ApiSink::new(
+                "api".to_string(),
+                listen,
+                self.stats.clone(),
+                self.history.clone(),
+                health_limits(config),
+            )));
+        }
+
+        if let Some(host) = config.postgres.host.clone().filter(|_| sink_enabled("postgres")) {
+            let (host, port) = match host.rsplit_once(':') {
+                Some((h, p)) => (h.to_string(), p.parse().unwrap_or(5432)),
+                None => (host, 5432),
+            };
+            let settings = postgres::PostgresSettings {
+                host,
+                port,
+                dbname: config.postgres.dbname.clone(),
+                username: config.postgres.username.clone(),
+                password: config.postgres.password.clone(),
+                timescaledb: config.postgres.timescaledb,
+                batch_size: config.postgres.batch_size,
+                flush_interval: Duration::from_secs_f32(config.postgres.flush_interval),
+            };
+            sinks.push(Box::new(postgres::PostgresSink::new("postgres".to_string(), settings)));
+        }
+
+        if let Some(path) = config.sqlite.path.clone().filter(|_| sink_enabled("sqlite")) {
+            sinks.push(Box::new(sqlite::SqliteSink::new(
+                "sqlite".to_string(),
+                path,
+                config.sqlite.raw_retention_days,
+                config.sqlite.five_min_retention_days,
+                config.sqlite.daily_retention_days,
+            )));
+        }
+
+        if let Some(dir) = config.file.dir.clone().filter(|_| sink_enabled("file")) {
+            sinks.push(Box::new(file::FileSink::new(
+                "file".to_string(),
+                dir,
+                config.file.prefix.clone(),
+                config.file.format,
+                config.file.compress,
+            )));
+        }
+
+        if let (Some(api_key), Some(system_id)) = (config.pvoutput.api_key.clone(), config.pvoutput.system_id.clone()) {
+            if sink_enabled("pvoutput") {
+                let settings = pvoutput::PvoutputSettings {
+                    base_url: config.pvoutput.base_url.trim_end_matches('/').to_string(),
+                    api_key,
+                    system_id,
+                    interval: Duration::from_secs(config.pvoutput.interval * 60),
+                };
+                sinks.push(Box::new(pvoutput::PvoutputSink::new("pvoutput".to_string(), settings)));
+            }
+        }
+
+        if let Some(url) = config.webhook.url.clone().filter(|_| sink_enabled("webhook")) {
+            let auth = match (config.webhook.username.clone(), config.webhook.token.clone()) {
+                (Some(username), _) => Some(webhook::WebhookAuth::Basic {
+                    username,
+                    password: config.webhook.password.clone(),
+                }),
+                (None, Some(token)) => Some(webhook::WebhookAuth::Bearer(token)),
+                (None, None) => None,
+            };
+            let settings = webhook::WebhookSettings {
+                url,
+                format: config.webhook.format,
+                headers: config.webhook.headers.as_deref().map(webhook::parse_headers).unwrap_or_default(),
+                auth,
+                template: config.webhook.template.clone(),
+                node: config.webhook.node.clone(),
+            };
+            match webhook::WebhookSink::new("webhook".to_string(), settings) {
+                Ok(webhook) => sinks.push(Box::new(webhook)),
+                Err(e) => error!("webhook: invalid configuration: {}", e),
+            }
+        }
+
+        if let Some(host) = config.graphite.host.clone().filter(|_| sink_enabled("graphite")) {
+            sinks.push(Box::new(tsdb::GraphiteSink::new(
+                "graphite".to_string(),
+                host,
+                config.graphite.transport,
+                config.graphite.prefix.clone(),
+            )));
+        }
+
+        if let Some(host) = config.opentsdb.host.clone().filter(|_| sink_enabled("opentsdb")) {
+            sinks.push(Box::new(tsdb::OpenTsdbSink::new(
+                "opentsdb".to_string(),
+                host,
+                config.opentsdb.transport,
+                config.opentsdb.prefix.clone(),
+            )));
+        }
+
+        sinks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::task::Id;
+
+    fn config(dir: &std::path::Path) -> Config {
+        let mut config = Config::default();
+        config.file.dir = Some(dir.join("csv"));
+        config.sqlite.path = Some(dir.join("hard.db").to_string_lossy().into());
+        //nothing is listening there, the poller keeps reconnecting
+        config.sun2000.host = Some("127.0.0.1:1".into());
+        config
+    }
+
+    fn task_id(daemon: &Daemon, name: &str) -> Id {
+        daemon.sink_tasks[name].id()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut daemon = Daemon::start(config(dir.path()));
+        assert_eq!(daemon.sink_names(), vec!["file", "sqlite"]);
+        let file = task_id(&daemon, "file");
+        let sqlite = daemon.sink_tasks["sqlite"].abort_handle();
+        let sun2000 = daemon.sun2000.as_ref().unwrap().cancel_flag.clone();
+        let mut settings = daemon.poll_settings.subscribe();
+
+        //file changed, sqlite removed
+        let mut new = config(dir.path());
+        new.file.prefix = "inverter".into();
+        new.sqlite.path = None;
+        daemon.reload(new).await;
+        assert_eq!(daemon.sink_names(), vec!["file"]);
+        assert_ne!(task_id(&daemon, "file"), file);
+        assert!(sqlite.is_finished());
+        assert!(!settings.has_changed().unwrap());
+
+        //nothing changed for the running sink
+        let file = task_id(&daemon, "file");
+        let mut new = config(dir.path());
+        new.file.prefix = "inverter".into();
+        new.sqlite.path = None;
+        new.sun2000.poll_interval = 5.0;
+        daemon.reload(new).await;
+        assert_eq!(task_id(&daemon, "file"), file);
+        assert!(!daemon.sink_tasks["file"].is_finished());
+
+        //the poll interval is passed to the running poller
+        assert!(settings.has_changed().unwrap());
+        assert_eq!(settings.borrow_and_update().poll_interval_sec, 5.0);
+        let running = daemon.sun2000.as_ref().unwrap();
+        assert!(Arc::ptr_eq(&running.cancel_flag, &sun2000));
+        assert!(!sun2000.load(Ordering::SeqCst));
+        assert!(!running.handle.is_finished());
+
+        //but reconnecting to the new host
+        let mut new = config(dir.path());
+        new.sun2000.host = Some("127.0.0.1:2".into());
+        daemon.reload(new).await;
+        assert!(sun2000.load(Ordering::SeqCst));
+        assert!(!Arc::ptr_eq(&daemon.sun2000.as_ref().unwrap().cancel_flag, &sun2000));
+        assert_eq!(daemon.sink_names(), vec!["file", "sqlite"]);
+    }
+}
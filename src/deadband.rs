@@ -0,0 +1,216 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::sun2000::Parameter;
+
+/// When a new value of a parameter is passed to the sinks
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DeadbandFilter {
+    /// every value
+    #[default]
+    Off,
+    /// only the values differing from the last written one
+    Change,
+    /// values differing from the last written one by more than the given amount
+    Absolute(f64),
+    /// values differing from the last written one by more than the given percentage of it
+    Percent(f64),
+}
+
+impl FromStr for DeadbandFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "off" => Ok(DeadbandFilter::Off),
+            "change" => Ok(DeadbandFilter::Change),
+            other => {
+                let (number, percent) = match other.strip_suffix('%') {
+                    Some(number) => (number.trim_end(), true),
+                    None => (other, false),
+                };
+                match number.parse::<f64>() {
+                    Ok(band) if band >= 0.0 && band.is_finite() => Ok(if percent {
+                        DeadbandFilter::Percent(band)
+                    } else {
+                        DeadbandFilter::Absolute(band)
+                    }),
+                    _ => Err(format!(
+                        "expected off, change, a non-negative number or percentage (eg. 20 or 1%), got {:?}",
+                        s
+                    )),
+                }
+            }
+        }
+    }
+}
+
+/// Deadband filters of the parameters, can be changed on config reload
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeadbandSettings {
+    /// filter of the parameters not listed in `params`
+    pub default: DeadbandFilter,
+    pub params: BTreeMap<String, DeadbandFilter>,
+    /// the filtered values are written at least this often anyway, zero means never
+    pub heartbeat: Duration,
+}
+
+impl DeadbandSettings {
+    /// Whether every value is passing
+    pub fn is_off(&self) -> bool {
+        self.default == DeadbandFilter::Off && self.params.values().all(|f| *f == DeadbandFilter::Off)
+    }
+
+    fn filter(&self, name: &str) -> DeadbandFilter {
+        self.params.get(name).copied().unwrap_or(self.default)
+    }
+}
+
+/// Last value of a parameter passed to the sinks
+struct Written {
+    text: String,
+    number: Option<f64>,
+    time: u128,
+}
+
+/// Drops the values which are not worth writing, remembering the last written ones
+#[derive(Default)]
+pub struct Deadband {
+    written: HashMap<String, Written>,
+}
+
+impl Deadband {
+    /// Keeps the parameters passing their filter or due to the heartbeat
+    pub fn filter(&mut self, settings: &DeadbandSettings, parameters: Vec<Parameter>) -> Vec<Parameter> {
+        parameters.into_iter().filter(|p| self.pass(settings, p)).collect()
+    }
+
+    fn pass(&mut self, settings: &DeadbandSettings, p: &Parameter) -> bool {
+        let filter = settings.filter(&p.name);
+        if filter == DeadbandFilter::Off || !p.has_value() {
+            return true;
+        }
+        let text = p.get_text_value();
+        let number = p.get_float_value();
+        let pass = match self.written.get(&p.name) {
+            None => true,
+            Some(last) => {
+                let heartbeat =
+                    !settings.heartbeat.is_zero() && p.time.saturating_sub(last.time) >= settings.heartbeat.as_millis();
+                heartbeat
+                    || match (filter, number, last.number) {
+                        (DeadbandFilter::Absolute(band), Some(value), Some(last)) => (value - last).abs() > band,
+                        (DeadbandFilter::Percent(band), Some(value), Some(last)) => {
+                            (value - last).abs() > last.abs() * band / 100.0
+                        }
+                        //text values are only compared for a change
+                        _ => text != last.text,
+                    }
+            }
+        };
+        if pass {
+            self.written.insert(p.name.clone(), Written { text, number, time: p.time });
+        }
+        pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sun2000::ParamKind;
+
+    fn param(name: &'static str, value: i32, time: u128) -> Parameter {
+        Parameter::new(name, ParamKind::NumberI32(Some(value)), time, None, Some("W"), 10, 32080, 2, false, true)
+    }
+
+    fn text(value: &str, time: u128) -> Parameter {
+        Parameter::new("model_name", ParamKind::Text(Some(value.into())), time, None, None, 1, 30000, 15, false, true)
+    }
+
+    fn settings(default: &str) -> DeadbandSettings {
+        DeadbandSettings {
+            default: default.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    /// Values (the raw ones, the gain is 10) passed by the filter, one every second
+    fn passing(settings: &DeadbandSettings, values: &[i32]) -> Vec<i32> {
+        let mut deadband = Deadband::default();
+        values
+            .iter()
+            .enumerate()
+            .filter(|(i, v)| !deadband.filter(settings, vec![param("active_power", **v, *i as u128 * 1000)]).is_empty())
+            .map(|(_, v)| *v)
+            .collect()
+    }
+
+    #[test]
+    fn from_str() {
+        assert_eq!("off".parse(), Ok(DeadbandFilter::Off));
+        assert_eq!("change".parse(), Ok(DeadbandFilter::Change));
+        assert_eq!("20".parse(), Ok(DeadbandFilter::Absolute(20.0)));
+        assert_eq!("0.5".parse(), Ok(DeadbandFilter::Absolute(0.5)));
+        assert_eq!("1%".parse(), Ok(DeadbandFilter::Percent(1.0)));
+        assert_eq!("2.5 %".parse(), Ok(DeadbandFilter::Percent(2.5)));
+        for s in ["", "-1", "nan", "inf", "x%", "on"] {
+            assert!(s.parse::<DeadbandFilter>().is_err(), "{:?} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn off() {
+        assert_eq!(passing(&settings("off"), &[10, 10, 10]), vec![10, 10, 10]);
+        assert!(settings("off").is_off());
+        assert!(!settings("change").is_off());
+    }
+
+    #[test]
+    fn change() {
+        assert_eq!(passing(&settings("change"), &[10, 10, 11, 11, 10]), vec![10, 11, 10]);
+    }
+
+    #[test]
+    fn absolute() {
+        //compared to the last written value, not to the previous one
+        assert_eq!(passing(&settings("2"), &[100, 110, 120, 121, 100, 79]), vec![100, 121, 100, 79]);
+    }
+
+    #[test]
+    fn percent() {
+        assert_eq!(passing(&settings("10%"), &[1000, 1100, 1101, 1212, -1000, -1099]), vec![1000, 1101, 1212, -1000]);
+    }
+
+    #[test]
+    fn per_parameter() {
+        let mut settings = settings("change");
+        settings.params.insert("active_power".into(), DeadbandFilter::Off);
+        let mut deadband = Deadband::default();
+        let passed: Vec<String> = (0..3)
+            .flat_map(|i| deadband.filter(&settings, vec![param("active_power", 1, i), param("input_power", 1, i)]))
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(passed, vec!["active_power", "input_power", "active_power", "active_power"]);
+    }
+
+    #[test]
+    fn heartbeat() {
+        let mut settings = settings("change");
+        settings.heartbeat = Duration::from_secs(3);
+        //the same value is written again at 3s and 6s
+        assert_eq!(passing(&settings, &[1, 1, 1, 1, 1, 1, 1]), vec![1, 1, 1]);
+        //the heartbeat is counted from the last written value
+        assert_eq!(passing(&settings, &[1, 1, 2, 2, 2, 2, 2]), vec![1, 2, 2]);
+    }
+
+    #[test]
+    fn text_values() {
+        let settings = settings("20");
+        let mut deadband = Deadband::default();
+        assert_eq!(deadband.filter(&settings, vec![text("SUN2000-10KTL", 0)]).len(), 1);
+        assert_eq!(deadband.filter(&settings, vec![text("SUN2000-10KTL", 1)]).len(), 0);
+        assert_eq!(deadband.filter(&settings, vec![text("SUN2000-8KTL", 2)]).len(), 1);
+    }
+}
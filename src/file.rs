@@ -0,0 +1,292 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::sink::{ReadingBatch, Result, Sink};
+use crate::sun2000::Sun2000;
+use async_trait::async_trait;
+use chrono::{Local, LocalResult, NaiveDate, TimeZone};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Map, Value};
+use simplelog::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileFormat {
+    Csv,
+    Jsonl,
+}
+
+impl FileFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "csv" => Ok(FileFormat::Csv),
+            "jsonl" | "json" => Ok(FileFormat::Jsonl),
+            other => Err(format!("unknown file format: {:?}", other)),
+        }
+    }
+}
+
+/// Column of the CSV file
+struct Column {
+    /// header name, suffixed with the register when the parameter name is not unique
+    name: String,
+    unit: Option<&'static str>,
+    reg_address: u16,
+}
+
+pub struct FileSink {
+    pub name: String,
+    pub dir: PathBuf,
+    pub prefix: String,
+    pub format: FileFormat,
+    pub compress: bool,
+    columns: Vec<Column>,
+    current: Option<(NaiveDate, PathBuf, BufWriter<File>)>,
+}
+
+fn local_date(time: u128) -> NaiveDate {
+    match Local.timestamp_millis_opt(time as i64) {
+        LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt.date_naive(),
+        LocalResult::None => Local::now().date_naive(),
+    }
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Compresses the file to `<path>.gz` and removes the original
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+impl FileSink {
+    pub fn new(name: String, dir: PathBuf, prefix: String, format: FileFormat, compress: bool) -> Self {
+        //every periodically polled parameter gets its own column
+        let parameters: Vec<_> = Sun2000::param_table()
+            .into_iter()
+            .flat_map(|pb| pb.parameters)
+            .filter(|p| !p.initial_read && p.save_to_influx)
+            .collect();
+        let columns = parameters
+            .iter()
+            .map(|p| Column {
+                name: match parameters.iter().filter(|o| o.name == p.name).count() {
+                    1 => p.name.clone(),
+                    _ => format!("{}_{}", p.name, p.reg_address),
+                },
+                //skip the internal markers like `epoch` or `status_enum`
+                unit: p.unit.filter(|u| *u != "epoch" && !u.contains('_')),
+                reg_address: p.reg_address,
+            })
+            .collect();
+        Self {
+            name,
+            dir,
+            prefix,
+            format,
+            compress,
+            columns,
+            current: None,
+        }
+    }
+
+    fn file_path(&self, date: NaiveDate) -> PathBuf {
+        self.dir.join(format!("{}-{}.{}", self.prefix, date.format("%Y-%m-%d"), self.format.extension()))
+    }
+
+    fn csv_header(&self) -> String {
+        let mut header = vec!["time".to_string()];
+        for c in &self.columns {
+            header.push(csv_escape(&match c.unit {
+                Some(unit) => format!("{} [{}]", c.name, unit),
+                None => c.name.clone(),
+            }));
+        }
+        header.join(",")
+    }
+
+    fn csv_row(&self, batch: &ReadingBatch) -> String {
+        let time = match Local.timestamp_millis_opt(batch.timestamp as i64) {
+            LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt.format("%F %T").to_string(),
+            LocalResult::None => batch.timestamp.to_string(),
+        };
+        let mut row = vec![time];
+        for c in &self.columns {
+            //missing values (eg. in partial mode) are left empty
+            row.push(match batch.parameters.iter().find(|p| p.reg_address == c.reg_address) {
+                Some(p) => csv_escape(&p.get_text_value()),
+                None => String::new(),
+            });
+        }
+        row.join(",")
+    }
+
+    fn json_row(&self, batch: &ReadingBatch) -> String {
+        let mut object = Map::new();
+        object.insert("time".into(), json!(batch.timestamp as u64));
+        object.insert("inverter".into(), json!(batch.device.name));
+        for p in batch.parameters.iter().filter(|p| p.save_to_influx) {
+            object.insert(p.name.clone(), p.get_json_value());
+        }
+        Value::Object(object).to_string()
+    }
+
+    /// Opens the file for the given day, rotating the previous one
+    fn open(&mut self, date: NaiveDate) -> io::Result<()> {
+        if let Some((_, path, mut writer)) = self.current.take() {
+            writer.flush()?;
+            drop(writer);
+            info!("<i>{}</>: rotating <u>{}</>", self.name, path.display());
+            if self.compress {
+                compress_file(&path)?;
+            }
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.file_path(date);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if empty && self.format == FileFormat::Csv {
+            writeln!(writer, "{}", self.csv_header())?;
+        }
+        debug!("{}: writing to {}", self.name, path.display());
+        self.current = Some((date, path, writer));
+        Ok(())
+    }
+
+    /// Compresses the files left uncompressed from the previous days (eg. when the daemon was stopped at midnight)
+    fn compress_leftovers(&self) -> io::Result<()> {
+        let today = self.file_path(Local::now().date_naive());
+        let suffix = format!(".{}", self.format.extension());
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let file_name = path.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+            if path != today && file_name.starts_with(&self.prefix) && file_name.ends_with(&suffix) {
+                info!("<i>{}</>: compressing <u>{}</>", self.name, path.display());
+                compress_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, batch: &ReadingBatch) -> io::Result<()> {
+        let date = local_date(batch.timestamp);
+        if self.current.as_ref().is_none_or(|(d, _, _)| *d != date) {
+            self.open(date)?;
+        }
+        let line = match self.format {
+            FileFormat::Csv => self.csv_row(batch),
+            FileFormat::Jsonl => self.json_row(batch),
+        };
+        if let Some((_, _, writer)) = &mut self.current {
+            writeln!(writer, "{}", line)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    //the rows are a complete record of the polls
+    fn deadband(&self) -> bool {
+        false
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        info!("<i>{}</>: writing {:?} files to <u>{}</>", self.name, self.format, self.dir.display());
+        if self.compress {
+            if let Err(e) = tokio::task::block_in_place(|| self.compress_leftovers()) {
+                error!("<i>{}</>: compression error: <b>{}</>", self.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        //identity and limits are not changing, they are not a part of the rows
+        if batch.metadata.initial_read {
+            return Ok(());
+        }
+        tokio::task::block_in_place(|| self.store(batch))?;
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        if let Some((_, _, writer)) = &mut self.current {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::BatchMetadata;
+    use crate::sun2000::{DeviceInfo, ParamKind, Parameter};
+    use std::sync::Arc;
+
+    fn working_mode(reg_address: u16, mode: u16) -> Parameter {
+        let value = ParamKind::NumberU16(Some(mode));
+        Parameter::new("storage_working_mode", value, 0, None, Some("working_mode"), 1, reg_address, 1, false, true)
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let sink = FileSink::new("file".into(), PathBuf::new(), "hard".into(), FileFormat::Csv, false);
+        let header = sink.csv_header();
+        let header: Vec<_> = header.split(',').collect();
+        let position = |name: &str| header.iter().position(|h| *h == name);
+        assert!(position("storage_working_mode").is_none());
+        assert!(position("storage_working_mode_37006").is_some());
+        let first = position("storage_working_mode_47004").expect("47004 column") - 1;
+        let second = position("storage_working_mode_47086").expect("47086 column") - 1;
+        assert!(position("active_power [W]").is_some());
+
+        //the values are in the columns of their registers, whatever the order
+        let batch = ReadingBatch {
+            device: Arc::new(DeviceInfo::default()),
+            timestamp: 0,
+            parameters: vec![working_mode(47086, 5), working_mode(47004, 2)],
+            metadata: BatchMetadata::default(),
+            events: vec![],
+        };
+        let row = sink.csv_row(&batch);
+        let row: Vec<_> = row.split(',').skip(1).collect();
+        assert_eq!(row.len(), header.len() - 1);
+        assert_eq!(row[first], "2");
+        assert_eq!(row[second], "5");
+        assert_eq!(row.iter().filter(|v| !v.is_empty()).count(), 2);
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::sun2000::Parameter;
+
+/// Aggregated values of a single resolution step
+#[derive(Clone, Copy, Debug)]
+pub struct Bucket {
+    /// step start (ms)
+    pub time: u64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u32,
+}
+
+impl Bucket {
+    pub fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WindowStats {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub last: f64,
+    pub count: u32,
+}
+
+/// Bounded in-memory history of the numeric parameters, shared by the poller and its readers
+pub struct History {
+    pub duration: Duration,
+    pub resolution: Duration,
+    series: Mutex<HashMap<String, VecDeque<Bucket>>>,
+    last: Mutex<HashMap<String, f64>>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+impl History {
+    pub fn new(duration: Duration, resolution: Duration) -> Self {
+        Self {
+            duration,
+            //zero resolution would store every poll in its own bucket
+            resolution: resolution.max(Duration::from_secs(1)),
+            series: Mutex::new(HashMap::new()),
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the numeric values of a poll, the text, timestamp and enum/bitfield values are skipped
+    pub fn record(&self, time: u128, parameters: &[Parameter]) {
+        let time = time as u64;
+        let step = self.resolution.as_millis() as u64;
+        let bucket_time = time - time % step;
+        let oldest = time.saturating_sub(self.duration.as_millis() as u64);
+        let (mut series, mut last) = match (self.series.lock(), self.last.lock()) {
+            (Ok(series), Ok(last)) => (series, last),
+            _ => return,
+        };
+        for p in parameters {
+            if p.unit.is_some_and(|u| u == "epoch" || u.contains('_')) {
+                continue;
+            }
+            let value = match p.get_float_value() {
+                Some(value) => value,
+                None => continue,
+            };
+            last.insert(p.name.clone(), value);
+            let buckets = series.entry(p.name.clone()).or_default();
+            match buckets.back_mut() {
+                Some(b) if b.time == bucket_time => {
+                    b.min = b.min.min(value);
+                    b.max = b.max.max(value);
+                    b.sum += value;
+                    b.count += 1;
+                }
+                _ => buckets.push_back(Bucket {
+                    time: bucket_time,
+                    min: value,
+                    max: value,
+                    sum: value,
+                    count: 1,
+                }),
+            }
+            while buckets.front().is_some_and(|b| b.time < oldest) {
+                buckets.pop_front();
+            }
+        }
+    }
+
+    /// Names of the recorded parameters
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = match self.series.lock() {
+            Ok(series) => series.keys().cloned().collect(),
+            Err(_) => vec![],
+        };
+        names.sort();
+        names
+    }
+
+    /// Buckets of the last `window` (the whole history when `None`)
+    pub fn buckets(&self, name: &str, window: Option<Duration>) -> Vec<Bucket> {
+        let since = window.map(|w| now_ms().saturating_sub(w.as_millis() as u64)).unwrap_or_default();
+        match self.series.lock() {
+            Ok(series) => series
+                .get(name)
+                .map(|buckets| buckets.iter().filter(|b| b.time >= since).copied().collect())
+                .unwrap_or_default(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Min/max/avg of the last `window` (the whole history when `None`)
+    pub fn stats(&self, name: &str, window: Option<Duration>) -> Option<WindowStats> {
+        let buckets = self.buckets(name, window);
+        let last = *self.last.lock().ok()?.get(name)?;
+        let count: u32 = buckets.iter().map(|b| b.count).sum();
+        if count == 0 {
+            return None;
+        }
+        Some(WindowStats {
+            min: buckets.iter().map(|b| b.min).fold(f64::INFINITY, f64::min),
+            max: buckets.iter().map(|b| b.max).fold(f64::NEG_INFINITY, f64::max),
+            avg: buckets.iter().map(|b| b.sum).sum::<f64>() / count as f64,
+            last,
+            count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sun2000::ParamKind;
+
+    fn param(name: &'static str, value: i32, unit: &'static str) -> Parameter {
+        Parameter::new(name, ParamKind::NumberI32(Some(value)), 0, None, Some(unit), 10, 32080, 2, false, true)
+    }
+
+    #[test]
+    fn buckets() {
+        let history = History::new(Duration::from_secs(3600), Duration::from_secs(60));
+        //the first one is at the start of a minute
+        for (time, value) in [(0, 10), (30_000, 30), (59_999, 20), (60_000, 50), (180_000, -10)] {
+            history.record(1_000_000_020_000 + time, &[param("active_power", value, "kW")]);
+        }
+        let buckets = history.buckets("active_power", None);
+        let summary: Vec<(u64, f64, f64, f64, u32)> =
+            buckets.iter().map(|b| (b.time, b.min, b.max, b.avg(), b.count)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1_000_000_020_000, 1.0, 3.0, 2.0, 3),
+                (1_000_000_080_000, 5.0, 5.0, 5.0, 1),
+                (1_000_000_200_000, -1.0, -1.0, -1.0, 1),
+            ]
+        );
+        let stats = history.stats("active_power", None).unwrap();
+        assert_eq!((stats.min, stats.max, stats.avg, stats.last, stats.count), (-1.0, 5.0, 2.0, -1.0, 5));
+        assert!(history.stats("input_power", None).is_none());
+    }
+
+    #[test]
+    fn skipped_units() {
+        let history = History::new(Duration::from_secs(3600), Duration::from_secs(60));
+        let parameters = [
+            param("active_power", 10, "kW"),
+            param("alarm_1", 1, "alarm_bitfield16"),
+            param("startup_time", 1, "epoch"),
+            Parameter::new("model_name", ParamKind::Text(Some("SUN2000".into())), 0, None, None, 1, 30000, 15, false, true),
+        ];
+        history.record(0, &parameters);
+        assert_eq!(history.names(), vec!["active_power"]);
+    }
+
+    #[test]
+    fn duration_and_window() {
+        let history = History::new(Duration::from_secs(600), Duration::from_secs(60));
+        let now = now_ms();
+        //one value every minute for the last 20 minutes
+        for minute in (0..20).rev() {
+            history.record((now - minute * 60_000) as u128, &[param("active_power", minute as i32 * 10, "kW")]);
+        }
+        //the buckets older than the history duration are dropped
+        let stats = history.stats("active_power", None).unwrap();
+        assert!((10..=11).contains(&stats.count), "{} values kept", stats.count);
+        assert_eq!(stats.last, 0.0);
+        let stats = history.stats("active_power", Some(Duration::from_secs(150))).unwrap();
+        assert!((2..=3).contains(&stats.count), "{} values in the window", stats.count);
+        assert_eq!(stats.min, 0.0);
+        assert!(stats.max <= 2.0);
+    }
+
+    #[test]
+    fn zero_resolution() {
+        let history = History::new(Duration::from_secs(60), Duration::ZERO);
+        assert_eq!(history.resolution, Duration::from_secs(1));
+        history.record(1_000, &[param("active_power", 10, "kW")]);
+        history.record(1_999, &[param("active_power", 20, "kW")]);
+        assert_eq!(history.buckets("active_power", None).len(), 1);
+    }
+}
@@ -1,18 +1,247 @@
-use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, time::Duration};
+use std::{fs::{self, OpenOptions}, io::Write, path::Path, str::FromStr, sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}}, time::{Duration, Instant}};
 
-use async_channel::Receiver;
-use influxdb::{WriteQuery, Client};
+use async_channel::{Receiver, Sender, TrySendError};
+use async_trait::async_trait;
+use influxdb::{WriteQuery, Client, InfluxDbWriteable, Query, QueryType, Timestamp, Type, ValidQuery};
 use simplelog::*;
+use tokio::time::timeout;
 
-// Just a generic Result type to ease error handling for us. Errors in multithreaded
-// async contexts needs some extra restrictions
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+use crate::rules::RuleAction;
+use crate::sink::{ReadingBatch, Result, Sink};
+
+pub const INFLUXDB_SPILL_REPLAY_CHUNK: usize = 5000; //max line protocol lines sent per replay write
+pub const INFLUXDB_SPILL_REPLAY_INTERVAL_SECS: f32 = 10.0; //secs between spill file replay attempts
+
+/// What to do with new points when the InfluxDB channel is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// wait for a free slot, but no longer than the configured block timeout
+    Block,
+    /// discard the oldest queued batch to make room for the new one
+    DropOldest,
+    /// discard the new batch
+    DropNewest,
+    /// append the new batch to a spill file, replayed when the channel drains
+    Spill,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            "spill" => Ok(OverflowPolicy::Spill),
+            other => Err(format!("unknown overflow policy: {:?}", other)),
+        }
+    }
+}
+
+/// Counters of points which didn't make it to the channel
+#[derive(Default)]
+pub struct QueueStats {
+    pub dropped_oldest: AtomicU64,
+    pub dropped_newest: AtomicU64,
+    pub spilled: AtomicU64,
+}
+
+impl QueueStats {
+    pub fn dropped(&self) -> u64 {
+        self.dropped_oldest.load(Ordering::Relaxed) + self.dropped_newest.load(Ordering::Relaxed)
+    }
+}
+
+/// Producer side of the InfluxDB channel. `push()` never panics and never waits longer
+/// than `block_timeout`, so the modbus poll loop is not stalled by a slow database.
+#[derive(Clone)]
+pub struct InfluxdbQueue {
+    pub tx: Sender<Vec<WriteQuery>>,
+    pub rx: Receiver<Vec<WriteQuery>>,
+    pub policy: OverflowPolicy,
+    pub block_timeout: Duration,
+    pub spill_path: Option<String>,
+    pub stats: Arc<QueueStats>,
+}
+
+impl InfluxdbQueue {
+    pub async fn push(&self, thread_name: &str, query: Vec<WriteQuery>) {
+        let points = query.len() as u64;
+        if self.tx.is_closed() {
+            debug!("{}: influxdb channel closed, dropping {} points", thread_name, points);
+            self.stats.dropped_newest.fetch_add(points, Ordering::Relaxed);
+            return;
+        }
+
+        match self.policy {
+            OverflowPolicy::Block => {
+                match timeout(self.block_timeout, self.tx.send(query)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) | Err(_) => {
+                        warn!("<i>{}</>: influxdb channel full, dropping {} points", thread_name, points);
+                        self.stats.dropped_newest.fetch_add(points, Ordering::Relaxed);
+                    }
+                }
+            }
+            OverflowPolicy::DropNewest => {
+                if self.tx.try_send(query).is_err() {
+                    debug!("{}: influxdb channel full, dropping {} newest points", thread_name, points);
+                    self.stats.dropped_newest.fetch_add(points, Ordering::Relaxed);
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                let mut query = query;
+                loop {
+                    match self.tx.try_send(query) {
+                        Ok(()) => break,
+                        Err(TrySendError::Full(q)) => {
+                            query = q;
+                            if let Ok(oldest) = self.rx.try_recv() {
+                                debug!("{}: influxdb channel full, dropping {} oldest points", thread_name, oldest.len());
+                                self.stats.dropped_oldest.fetch_add(oldest.len() as u64, Ordering::Relaxed);
+                            }
+                        }
+                        Err(TrySendError::Closed(_)) => {
+                            self.stats.dropped_newest.fetch_add(points, Ordering::Relaxed);
+                            break;
+                        }
+                    }
+                }
+            }
+            OverflowPolicy::Spill => {
+                if let Err(TrySendError::Full(q)) | Err(TrySendError::Closed(q)) = self.tx.try_send(query) {
+                    match self.spill(&q) {
+                        Ok(()) => {
+                            self.stats.spilled.fetch_add(points, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            error!("<i>{}</>: influxdb spill error: <b>{}</>", thread_name, e);
+                            self.stats.dropped_newest.fetch_add(points, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn spill(&self, query: &[WriteQuery]) -> Result<()> {
+        let path = self.spill_path.as_ref().ok_or("spill_path is not configured")?;
+        spill_to_file(path, query)
+    }
+}
+
+fn spill_to_file(path: &str, query: &[WriteQuery]) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for q in query {
+        writeln!(file, "{}", q.build()?.get())?;
+    }
+    Ok(())
+}
+
+/// Already built line protocol, used for replaying spilled points
+struct RawWriteQuery(String);
+
+impl Query for RawWriteQuery {
+    fn build(&self) -> std::result::Result<ValidQuery, influxdb::Error> {
+        Ok(ValidQuery::from(self.0.clone()))
+    }
+
+    fn get_type(&self) -> QueryType {
+        QueryType::WriteQuery("ms".to_string())
+    }
+}
+
+/// Sink saving the parameters to InfluxDB, either directly or using
+/// the writer tasks when `threaded_influxdb` is enabled
+pub struct InfluxdbSink {
+    pub name: String,
+    pub influxdb_url: Option<String>,
+    pub influxdb_token: Option<String>,
+    pub influxdb_database: String,
+    pub bulk_insert: bool,
+    pub tx_influxdb: Option<InfluxdbQueue>,
+}
+
+impl InfluxdbSink {
+    async fn save(&self, client: &Client, query: Vec<WriteQuery>) {
+        match &self.tx_influxdb {
+            Some(tx) => tx.push(&self.name, query).await,
+            None => {
+                let _ = save_multiple_to_influxdb(client.clone(), &self.name, &query).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxdbSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        // connect to influxdb
+        let client = match &self.influxdb_url {
+            Some(url) => match &self.influxdb_token {
+                Some(token) => Client::new(url, &self.influxdb_database).with_token(token),
+                None => Client::new(url, &self.influxdb_database),
+            },
+            None => return Ok(()),
+        };
+
+        if !batch.metadata.initial_read {
+            let query = batch
+                .parameters
+                .iter()
+                .filter(|p| p.save_to_influx && p.has_value())
+                .map(|p| Timestamp::Milliseconds(p.time).into_query(&p.name).add_field("value", p.get_influx_value()));
+            if self.bulk_insert {
+                self.save(&client, query.collect()).await;
+            } else {
+                for q in query {
+                    self.save(&client, vec![q]).await;
+                }
+            }
+        }
+
+        //rule state changes as events
+        let events: Vec<WriteQuery> = batch
+            .events
+            .iter()
+            .filter(|e| e.actions.contains(&RuleAction::Influxdb))
+            .map(|e| {
+                Timestamp::Milliseconds(e.time)
+                    .into_query("alerts")
+                    .add_tag("rule", e.rule.clone())
+                    .add_tag("param", e.param.clone())
+                    .add_tag("state", e.state.to_string())
+                    .add_field("value", e.value)
+                    .add_field("message", e.message.clone())
+            })
+            .collect();
+        if !events.is_empty() {
+            self.save(&client, events).await;
+        }
+
+        //save query time
+        let query = Timestamp::Milliseconds(batch.timestamp)
+            .into_query("inverter_query_time")
+            .add_field("value", Type::SignedInteger(batch.metadata.query_time_ms as i64))
+            .add_field("param_count", batch.parameters.len() as u8);
+        self.save(&client, vec![query]).await;
+
+        Ok(())
+    }
+}
 
 pub struct InfluxdbWriter {
     pub name: String,
     pub influxdb_url: Option<String>,
     pub influxdb_token: Option<String>,
+    pub influxdb_database: String,
     pub rx_influxdb: Receiver<Vec<WriteQuery>>,
+    pub spill_path: Option<String>,
 }
 
 impl InfluxdbWriter {
@@ -20,6 +249,7 @@ impl InfluxdbWriter {
         info!("{}: Starting task", self.name);
 
         // let mut terminated = false;
+        let mut replay_interval = Instant::now();
 
         loop {
             if worker_cancel_flag.load(Ordering::SeqCst) {
@@ -28,11 +258,11 @@ impl InfluxdbWriter {
 
             let client = match &self.influxdb_url {
                 Some(url) => match &self.influxdb_token {
-                    Some(token) => Some(Client::new(url, "sun2000").with_token(token)),
-                    None => Some(Client::new(url, "sun2000")),
+                    Some(token) => Some(Client::new(url, &self.influxdb_database).with_token(token)),
+                    None => Some(Client::new(url, &self.influxdb_database)),
                 },
                 None => None,
-            };    
+            };
 
             let task = self.rx_influxdb.try_recv();
             if let Ok(t) = task {
@@ -42,7 +272,21 @@ impl InfluxdbWriter {
                 );
 
                 if let Some(c) = client.clone() {
-                    let _ = save_multiple_to_influxdb(c, &self.name, t).await;
+                    if let Err(e) = save_multiple_to_influxdb(c, &self.name, &t).await {
+                        //keep the points for later if we are allowed to
+                        if let Some(path) = &self.spill_path {
+                            match spill_to_file(path, &t) {
+                                Ok(()) => debug!("{}: spilled {} points after write error: {}", self.name, t.len(), e),
+                                Err(e) => error!("<i>{}</>: influxdb spill error: <b>{}</>", self.name, e),
+                            }
+                        }
+                    }
+                }
+            } else if let (Some(c), Some(path)) = (client, &self.spill_path) {
+                //channel drained: time to catch up with the spilled points
+                if replay_interval.elapsed() > Duration::from_secs_f32(INFLUXDB_SPILL_REPLAY_INTERVAL_SECS) {
+                    replay_interval = Instant::now();
+                    let _ = self.replay_spill(c, path).await;
                 }
             }
 
@@ -52,12 +296,39 @@ impl InfluxdbWriter {
         info!("{}: task stopped", self.name);
         Ok(())
     }
+
+    async fn replay_spill(&self, client: influxdb::Client, path: &str) -> Result<()> {
+        //take over the spill file, so the producer starts a new one
+        //and other writer tasks won't replay the same points;
+        //a replay file left by an interrupted replay goes first, the spill file waits for the next round
+        let replay_path = format!("{}.{}", path, self.name);
+        if !Path::new(&replay_path).exists() && fs::rename(path, &replay_path).is_err() {
+            return Ok(());
+        }
+        let content = fs::read_to_string(&replay_path)?;
+        let lines: Vec<&str> = content.lines().filter(|l| !l.is_empty()).collect();
+        info!("{}: replaying {} spilled points", self.name, lines.len());
+
+        for (i, chunk) in lines.chunks(INFLUXDB_SPILL_REPLAY_CHUNK).enumerate() {
+            if let Err(e) = client.query(RawWriteQuery(chunk.join("\n"))).await {
+                error!("<i>{}</>: influxdb spill replay error: <b>{:?}</>", self.name, e);
+                //put the remaining points back to the spill file
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                for line in &lines[i * INFLUXDB_SPILL_REPLAY_CHUNK..] {
+                    writeln!(file, "{}", line)?;
+                }
+                break;
+            }
+        }
+        fs::remove_file(&replay_path)?;
+        Ok(())
+    }
 }
 
 async fn save_multiple_to_influxdb(
     client: influxdb::Client,
     thread_name: &String,
-    query: Vec<WriteQuery>,
+    query: &Vec<WriteQuery>,
 ) -> Result<()> {
     match client.query(query).await {
         Ok(msg) => {
@@ -69,8 +340,175 @@ async fn save_multiple_to_influxdb(
         }
         Err(e) => {
             error!("<i>{}</>: influxdb write error: <b>{:?}</>", thread_name, e);
+            return Err(Box::new(e));
         }
-    }        
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{http_server, next};
+
+    fn point(value: i64) -> WriteQuery {
+        Timestamp::Milliseconds(1000).into_query("active_power").add_field("value", value)
+    }
+
+    fn queue(policy: OverflowPolicy, spill_path: Option<String>) -> InfluxdbQueue {
+        let (tx, rx) = async_channel::bounded(1);
+        InfluxdbQueue {
+            tx,
+            rx,
+            policy,
+            block_timeout: Duration::from_millis(50),
+            spill_path,
+            stats: Arc::new(QueueStats::default()),
+        }
+    }
+
+    /// Values of the queued points
+    fn queued(queue: &InfluxdbQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.rx.try_recv().ok())
+            .flatten()
+            .map(|q| q.build().unwrap().get())
+            .collect()
+    }
+
+    fn writer(url: String, spill_path: &str) -> InfluxdbWriter {
+        InfluxdbWriter {
+            name: "influxdb-1".into(),
+            influxdb_url: Some(url),
+            influxdb_token: None,
+            influxdb_database: "test".into(),
+            rx_influxdb: async_channel::bounded(1).1,
+            spill_path: Some(spill_path.into()),
+        }
+    }
+
+    #[test]
+    fn policy_from_str() {
+        assert_eq!("drop_oldest".parse(), Ok(OverflowPolicy::DropOldest));
+        assert!("drop".parse::<OverflowPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn block() {
+        let queue = queue(OverflowPolicy::Block, None);
+        queue.push("test", vec![point(1)]).await;
+        let start = Instant::now();
+        queue.push("test", vec![point(2), point(3)]).await;
+        assert!(start.elapsed() >= queue.block_timeout);
+        assert_eq!(queue.stats.dropped_newest.load(Ordering::Relaxed), 2);
+        assert_eq!(queued(&queue), vec!["active_power value=1i 1000"]);
+    }
+
+    #[tokio::test]
+    async fn block_until_received() {
+        let mut queue = queue(OverflowPolicy::Block, None);
+        queue.block_timeout = Duration::from_secs(5);
+        queue.push("test", vec![point(1)]).await;
+        let rx = queue.rx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            rx.recv().await
+        });
+        queue.push("test", vec![point(2)]).await;
+        assert_eq!(queue.stats.dropped(), 0);
+        assert_eq!(queued(&queue), vec!["active_power value=2i 1000"]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        let queue = queue(OverflowPolicy::DropNewest, None);
+        queue.push("test", vec![point(1)]).await;
+        queue.push("test", vec![point(2)]).await;
+        assert_eq!(queue.stats.dropped_newest.load(Ordering::Relaxed), 1);
+        assert_eq!(queued(&queue), vec!["active_power value=1i 1000"]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let queue = queue(OverflowPolicy::DropOldest, None);
+        queue.push("test", vec![point(1), point(2)]).await;
+        queue.push("test", vec![point(3)]).await;
+        assert_eq!(queue.stats.dropped_oldest.load(Ordering::Relaxed), 2);
+        assert_eq!(queued(&queue), vec!["active_power value=3i 1000"]);
+    }
+
+    #[tokio::test]
+    async fn spill() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spill").to_str().unwrap().to_string();
+        let queue = queue(OverflowPolicy::Spill, Some(path.clone()));
+        queue.push("test", vec![point(1)]).await;
+        queue.push("test", vec![point(2), point(3)]).await;
+        queue.push("test", vec![point(4)]).await;
+        assert_eq!(queue.stats.spilled.load(Ordering::Relaxed), 3);
+        assert_eq!(queued(&queue), vec!["active_power value=1i 1000"]);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "active_power value=2i 1000\nactive_power value=3i 1000\nactive_power value=4i 1000\n"
+        );
+
+        //without a spill file the points are lost
+        let queue = self::queue(OverflowPolicy::Spill, None);
+        queue.push("test", vec![point(1)]).await;
+        queue.push("test", vec![point(2)]).await;
+        assert_eq!((queue.stats.spilled.load(Ordering::Relaxed), queue.stats.dropped()), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn closed() {
+        let queue = queue(OverflowPolicy::DropOldest, None);
+        queue.tx.close();
+        queue.push("test", vec![point(1)]).await;
+        assert_eq!(queue.stats.dropped_newest.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn replay_spill() {
+        let (address, mut requests) = http_server(204, "");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spill").to_str().unwrap().to_string();
+        let writer = writer(format!("http://{}", address), &path);
+        let client = Client::new(writer.influxdb_url.clone().unwrap(), "test");
+        let replay_path = format!("{}.{}", path, writer.name);
+
+        //the replay file left by an interrupted replay goes first
+        fs::write(&replay_path, "leftover value=1i 1000\n").unwrap();
+        fs::write(&path, "spilled value=2i 1000\n").unwrap();
+        writer.replay_spill(client.clone(), &path).await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!(request.method, "POST");
+        assert!(request.uri.starts_with("/write?"), "{}", request.uri);
+        assert!(request.uri.contains("db=test"), "{}", request.uri);
+        assert_eq!(request.body, "leftover value=1i 1000");
+        assert!(!Path::new(&replay_path).exists());
+
+        writer.replay_spill(client.clone(), &path).await.unwrap();
+        assert_eq!(next(&mut requests).await.body, "spilled value=2i 1000");
+        assert!(!Path::new(&path).exists());
+
+        //nothing to replay
+        writer.replay_spill(client, &path).await.unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_spill_error() {
+        let (address, mut requests) = http_server(500, r#"{"error":"database is down"}"#);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spill").to_str().unwrap().to_string();
+        let writer = writer(format!("http://{}", address), &path);
+        let client = Client::new(writer.influxdb_url.clone().unwrap(), "test");
+
+        fs::write(&path, "a value=1i 1000\nb value=2i 1000\n").unwrap();
+        writer.replay_spill(client, &path).await.unwrap();
+        next(&mut requests).await;
+        //the points are back in the spill file
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a value=1i 1000\nb value=2i 1000\n");
+        assert!(!Path::new(&format!("{}.{}", path, writer.name)).exists());
+    }
+}
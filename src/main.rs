@@ -1,69 +1,43 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 
-extern crate ctrlc;
 extern crate simplelog;
-use async_channel::{Sender, Receiver};
-use ::influxdb::WriteQuery;
+use clap::Parser;
 use simplelog::*;
 
-extern crate ini;
-
-use self::ini::Ini;
-
-use futures::future::join_all;
 use humantime::format_duration;
 use std::env;
 use std::fs::OpenOptions;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::task;
-use tokio_compat_02::FutureExt;
+use std::time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
 
 mod sun2000;
+mod sink;
 mod influxdb;
-
-fn get_config_string(option_name: &str, section: Option<&str>) -> Option<String> {
-    let conf = Ini::load_from_file("hard.conf").expect("Cannot open config file");
-    conf.section(Some(section.unwrap_or("general").to_owned()))
-        .and_then(|x| x.get(option_name).cloned())
-}
-
-fn get_config_bool(option_name: &str, section: Option<&str>) -> bool {
-    let conf = Ini::load_from_file("hard.conf").expect("Cannot open config file");
-    let value = conf
-        .section(Some(section.unwrap_or("general").to_owned()))
-        .and_then(|x| x.get(option_name).cloned());
-    match value {
-        Some(val) => matches!(val.trim(), "yes" | "true" | "1"),
-        _ => false,
-    }
-}
-
-fn get_config_int(option_name: &str, section: Option<&str>) -> usize {
-    let conf = Ini::load_from_file("hard.conf").expect("Cannot open config file");
-    let value = conf
-        .section(Some(section.unwrap_or("general").to_owned()))
-        .and_then(|x| x.get(option_name).cloned());
-    match value {
-        Some(val) => val.parse().unwrap(),
-        _ => 1,
-    }
-}
-
-fn get_config_float32(option_name: &str, section: Option<&str>) -> f32 {
-    let conf = Ini::load_from_file("hard.conf").expect("Cannot open config file");
-    let value = conf
-        .section(Some(section.unwrap_or("general").to_owned()))
-        .and_then(|x| x.get(option_name).cloned());
-    match value {
-        Some(val) => val.parse().unwrap(),
-        _ => 0.0,
-    }
-}
-
-
-fn logging_init() {
+mod mqtt;
+mod prometheus;
+mod postgres;
+mod sqlite;
+mod file;
+mod pvoutput;
+mod webhook;
+mod tsdb;
+mod api;
+mod history;
+mod systemd;
+mod config;
+mod cli;
+mod command;
+mod daemon;
+mod deadband;
+mod mode_change;
+mod notify;
+mod rules;
+mod smtp;
+mod solar;
+#[cfg(test)]
+mod testutil;
+
+fn logging_init(log_path: Option<&str>, level: LevelFilter, terminal_mode: TerminalMode) {
     let conf = ConfigBuilder::new()
         .set_time_format("%F, %H:%M:%S%.3f".to_string())
         .set_write_log_enable_colors(true)
@@ -72,21 +46,22 @@ fn logging_init() {
 
     let mut loggers = vec![];
 
+    //the loggers are passing everything, the level is set globally, so it can be changed on reload
     let console_logger: Box<dyn SharedLogger> = TermLogger::new(
-        LevelFilter::Info,
+        LevelFilter::Trace,
         conf.clone(),
-        TerminalMode::Mixed,
+        terminal_mode,
         ColorChoice::Auto,
     );
     loggers.push(console_logger);
 
     let mut logfile_error: Option<String> = None;
     
-    if let Some(ref log_path) = get_config_string("log", None) {
+    if let Some(log_path) = log_path {
         let logfile = OpenOptions::new().create(true).append(true).open(log_path);
         match logfile {
             Ok(logfile) => {
-                loggers.push(WriteLogger::new(LevelFilter::Info, conf, logfile));
+                loggers.push(WriteLogger::new(LevelFilter::Trace, conf, logfile));
             }
             Err(e) => {
                 logfile_error = Some(format!(
@@ -98,99 +73,92 @@ fn logging_init() {
     };
 
     CombinedLogger::init(loggers).expect("Cannot initialize logging subsystem");
-    if logfile_error.is_some() {
-        error!("{}", logfile_error.unwrap());
+    log::set_max_level(level);
+    if let Some(e) = logfile_error {
+        error!("{}", e);
         warn!("Will do console logging only...");
     }
 }
 
+/// Logs the problems found in the config and the values not coming from the config file
+fn log_config(config: &config::Config) {
+    for warning in &config.warnings {
+        warn!("Config: {}", warning);
+    }
+    for o in &config.overrides {
+        debug!(
+            "Config: [{}] {} = {:?} from <u>{}</>",
+            o.section,
+            o.key,
+            config.redacted_value(&o.section, &o.key).unwrap_or_default(),
+            o.source
+        );
+    }
+}
+
 #[tokio::main]
 async fn main() {
     env::set_var("RUST_BACKTRACE", "full");
     let started = Instant::now();
-    logging_init();
-    info!("🛡️ Welcome to hard (home automation rust-daemon)");
-
-    //Ctrl-C / SIGTERM support
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C handler");
-
-    //common thread stuff
-    let tx_influxdb;
-    
-    let threaded_influxdb = get_config_bool("threaded_influxdb", Some("influxdb"));
-    let influxdb_url = get_config_string("influxdb_url", Some("influxdb"));
-    let influxdb_token = get_config_string("influxdb_token", Some("influxdb"));
-    let mut futures = vec![];
-    let cancel_flag = Arc::new(AtomicBool::new(false));
-
-    if threaded_influxdb {
-        // let (tx2, rx2): (Sender<Vec<WriteQuery>>, Receiver<Vec<WriteQuery>>) = mpsc::channel(get_config_int("thread_buffer_size", Some("influxdb")));
-        let (tx2, rx2): (Sender<Vec<WriteQuery>>, Receiver<Vec<WriteQuery>>) = async_channel::bounded(get_config_int("thread_buffer_size", Some("influxdb")));
-        
-        tx_influxdb = Some(tx2);
-
-        let thread_number = get_config_int("thread_number", Some("influxdb"));
-
-        for i in 0..thread_number {
-            let worker_cancel_flag = cancel_flag.clone();
-            let mut influxdb = influxdb::InfluxdbWriter {
-                name: format!("influxdb-{}", i),
-                influxdb_url: influxdb_url.clone(),
-                influxdb_token: influxdb_token.clone(),
-                rx_influxdb: rx2.clone(),
-            };
-            let influxdb_future =
-                task::spawn(async move { influxdb.worker(worker_cancel_flag).compat().await });
-            futures.push(influxdb_future);    
+    let args = cli::Cli::parse();
+    //the one-shot commands are printing their results to stdout
+    let run_daemon = matches!(args.command, None | Some(cli::Command::Run)) && !args.check_config;
+    let terminal_mode = if run_daemon { TerminalMode::Mixed } else { TerminalMode::Stderr };
+    let config = match config::Config::load(&args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            logging_init(None, args.log_level.unwrap_or(LevelFilter::Info), terminal_mode);
+            error!("Config error: <b>{}</>", e);
+            std::process::exit(1);
         }
-    } else {
-        tx_influxdb  = None;
+    };
+    let log_path = config.general.log.as_deref().filter(|_| run_daemon && !args.foreground);
+    logging_init(log_path, args.log_level.unwrap_or(config.general.log_level), terminal_mode);
+    log_config(&config);
+    if args.check_config {
+        info!("Config <u>{}</> is valid", args.config);
+        return;
     }
-
-    //sun2000 async task
-    if let Some(host) = get_config_string("host", Some("sun2000")) {
-        let worker_cancel_flag = cancel_flag.clone();
-        let mut sun2000 = sun2000::Sun2000 {
-            name: "sun2000".to_string(),
-            host_port: host,
-            poll_ok: 0,
-            poll_errors: 0,
-            influxdb_url: influxdb_url.clone(),
-            influxdb_token: influxdb_token.clone(),
-            partial: get_config_bool("partial", Some("sun2000")),
-            bulk_insert: get_config_bool("bulk_insert", Some("sun2000")),
-            mode_change_script: get_config_string("mode_change_script", Some("sun2000")),
-            dongle_connection: get_config_bool("dongle_connection", Some("sun2000")),
-            tx_influxdb,
-            poll_interval_sec: get_config_float32("poll_interval", Some("sun2000")),
-            reconnect_params_threshold: get_config_int("reconnect_params_threshold", Some("sun2000")),
-            reconnect_params_wait: get_config_float32("reconnect_params_wait", Some("sun2000")),
-        };
-        let sun2000_future =
-            task::spawn(async move { sun2000.worker(worker_cancel_flag).compat().await });
-        futures.push(sun2000_future);
+    if let Some(command) = args.command.filter(|_| !run_daemon) {
+        if let Err(e) = cli::execute(command, &config).await {
+            error!("<b>{}</>", e);
+            std::process::exit(1);
+        }
+        return;
     }
+    info!("🛡️ Welcome to hard (home automation rust-daemon)");
 
+    //Ctrl-C / SIGTERM / SIGHUP (config reload) support
+    let mut sigterm = signal(SignalKind::terminate()).expect("Error setting SIGTERM handler");
+    let mut sighup = signal(SignalKind::hangup()).expect("Error setting SIGHUP handler");
+
+    let mut daemon = daemon::Daemon::start(config);
+
+    systemd::notify("READY=1\nSTATUS=Started");
     debug!("Entering main loop...");
     loop {
-        if !running.load(Ordering::SeqCst) {
-            info!("🛑 Ctrl-C or SIGTERM signal detected, exiting...");
-            break;
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = sigterm.recv() => break,
+            _ = sighup.recv() => {
+                info!("🔄 SIGHUP signal detected, reloading config <u>{}</>...", args.config);
+                match config::Config::load(&args.config) {
+                    Ok(config) => {
+                        log_config(&config);
+                        log::set_max_level(args.log_level.unwrap_or(config.general.log_level));
+                        daemon.reload(config).await;
+                    }
+                    Err(e) => error!("Config error: <b>{}</>, keeping the current config", e),
+                }
+            }
         }
-
-        tokio::time::sleep(Duration::from_millis(50)).await;
     }
+    info!("🛑 Ctrl-C or SIGTERM signal detected, exiting...");
 
     info!("🏁 Stopping all threads...");
-    //inform all threads about termination
-    cancel_flag.store(true, Ordering::SeqCst);
-    //wait for tokio async tasks
-    let _ = join_all(futures).await;
+    systemd::notify("STOPPING=1");
+    //inform all threads about termination and wait for them
+    daemon.stop().await;
 
     info!(
         "🚩 hard terminated, daemon running time: {}",
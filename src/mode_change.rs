@@ -0,0 +1,175 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use simplelog::*;
+
+use crate::command;
+use crate::sun2000::Parameter;
+
+/// Parameters whose transitions are running the script
+const MODE_PARAMS: [&str; 4] = ["device_status", "storage_working_mode", "storage_status", "storage1_status"];
+const MODE_CHANGE_SCRIPT_WINDOW_SECS: u64 = 3600; //window of the max_runs limit
+
+/// `mode_change_script` of the config
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeChangeScript {
+    /// program and arguments, split on whitespace, with the placeholders
+    pub command: String,
+    /// the script is killed after this time
+    pub timeout: Duration,
+    /// runs allowed within the window, the transitions above it are only logged
+    pub max_runs: usize,
+}
+
+/// Detects the state transitions between the polls and runs the script on them
+pub struct ModeChange {
+    name: String,
+    script: ModeChangeScript,
+    /// last mode by the register address, some names are used by several registers
+    modes: HashMap<u16, String>,
+    /// start times of the runs within the window
+    runs: VecDeque<Instant>,
+}
+
+/// Human readable state, when the parameter can be decoded
+fn mode_name(p: &Parameter) -> String {
+    match p.get_decoded_value() {
+        Some(decoded) if !decoded.is_empty() => decoded.join(", "),
+        _ => p.get_text_value(),
+    }
+}
+
+impl ModeChange {
+    pub fn new(name: String, script: ModeChangeScript) -> Self {
+        Self {
+            name,
+            script,
+            modes: HashMap::new(),
+            runs: VecDeque::new(),
+        }
+    }
+
+    /// Runs the script for every watched parameter which changed since the previous poll
+    pub fn check(&mut self, parameters: &[Parameter]) {
+        for args in self.transitions(parameters) {
+            tokio::spawn(command::run(self.name.clone(), "mode_change_script", args, self.script.timeout));
+        }
+    }
+
+    /// Expanded script commands of the transitions which are allowed to run
+    fn transitions(&mut self, parameters: &[Parameter]) -> Vec<Vec<String>> {
+        let mut scripts = vec![];
+        for p in parameters.iter().filter(|p| MODE_PARAMS.contains(&p.name.as_str()) && p.has_value()) {
+            let mode = mode_name(p);
+            let old_mode = match self.modes.insert(p.reg_address, mode.clone()) {
+                Some(old_mode) if old_mode != mode => old_mode,
+                //first reading or no change
+                _ => continue,
+            };
+            info!(
+                "<i>{}</>: <b>{}</> changed from <b>{}</> to <b>{}</>",
+                self.name, p.name, old_mode, mode
+            );
+            if !self.allow_run() {
+                warn!(
+                    "<i>{}</>: mode_change_script skipped, it was already run {} times within {} secs",
+                    self.name, self.script.max_runs, MODE_CHANGE_SCRIPT_WINDOW_SECS
+                );
+                continue;
+            }
+            let value = p.get_text_value();
+            scripts.push(command::expand(
+                &self.script.command,
+                &[("%old_mode%", &old_mode), ("%mode%", &mode), ("%param%", &p.name), ("%value%", &value)],
+            ));
+        }
+        scripts
+    }
+
+    fn allow_run(&mut self) -> bool {
+        let window = Duration::from_secs(MODE_CHANGE_SCRIPT_WINDOW_SECS);
+        while self.runs.front().is_some_and(|run| run.elapsed() > window) {
+            self.runs.pop_front();
+        }
+        if self.runs.len() >= self.script.max_runs {
+            return false;
+        }
+        self.runs.push_back(Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sun2000::ParamKind;
+
+    fn mode_change(max_runs: usize) -> ModeChange {
+        let script = ModeChangeScript {
+            command: "/usr/local/bin/mode.sh %param% %old_mode%->%mode% %value%".into(),
+            timeout: Duration::from_secs(10),
+            max_runs,
+        };
+        ModeChange::new("sun2000".into(), script)
+    }
+
+    fn device_status(status: u16) -> Parameter {
+        Parameter::new("device_status", ParamKind::NumberU16(Some(status)), 0, None, Some("status_enum"), 1, 32089, 1, false, true)
+    }
+
+    fn working_mode(reg_address: u16, mode: u16) -> Parameter {
+        let value = ParamKind::NumberU16(Some(mode));
+        Parameter::new("storage_working_mode", value, 0, None, Some("working_mode"), 1, reg_address, 1, false, true)
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn transitions() {
+        let mut mode_change = mode_change(10);
+        //the first reading is not a transition
+        assert!(mode_change.transitions(&[device_status(0x0000)]).is_empty());
+        assert!(mode_change.transitions(&[device_status(0x0000)]).is_empty());
+        assert_eq!(
+            mode_change.transitions(&[device_status(0x0200)]),
+            vec![args(&["/usr/local/bin/mode.sh", "device_status", "Standby: initializing->On-grid", "512"])]
+        );
+        //not watched
+        let power = Parameter::new("active_power", ParamKind::NumberI32(Some(1)), 0, None, Some("W"), 1, 32080, 2, false, true);
+        assert!(mode_change.transitions(std::slice::from_ref(&power)).is_empty());
+        let mut power = power;
+        power.value = ParamKind::NumberI32(Some(2));
+        assert!(mode_change.transitions(&[power]).is_empty());
+    }
+
+    #[test]
+    fn same_name_registers() {
+        let mut mode_change = mode_change(10);
+        assert!(mode_change.transitions(&[working_mode(47004, 2), working_mode(47086, 5)]).is_empty());
+        //each register is compared to its own previous value
+        assert!(mode_change.transitions(&[working_mode(47004, 2), working_mode(47086, 5)]).is_empty());
+        assert_eq!(
+            mode_change.transitions(&[working_mode(47004, 2), working_mode(47086, 2)]),
+            vec![args(&["/usr/local/bin/mode.sh", "storage_working_mode", "5->2", "2"])]
+        );
+    }
+
+    #[test]
+    fn max_runs() {
+        let mut mode_change = mode_change(2);
+        mode_change.transitions(&[device_status(0x0000)]);
+        assert_eq!(mode_change.transitions(&[device_status(0x0200)]).len(), 1);
+        assert_eq!(mode_change.transitions(&[device_status(0x0000)]).len(), 1);
+        //the third one within the window is skipped, but remembered
+        assert!(mode_change.transitions(&[device_status(0x0200)]).is_empty());
+        assert!(mode_change.transitions(&[device_status(0x0200)]).is_empty());
+
+        //the runs are leaving the window
+        let old = Instant::now() - Duration::from_secs(MODE_CHANGE_SCRIPT_WINDOW_SECS + 1);
+        mode_change.runs = VecDeque::from(vec![old, old]);
+        assert_eq!(mode_change.transitions(&[device_status(0x0300)]).len(), 1);
+        assert_eq!(mode_change.runs.len(), 1);
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::rules::RuleAction;
+use crate::sink::{ReadingBatch, Result, Sink};
+use crate::sun2000::{DeviceInfo, Parameter};
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::json;
+use simplelog::*;
+use tokio::task::JoinHandle;
+
+pub const MQTT_CLIENT_CAPACITY: usize = 1000; //max requests queued for the mqtt event loop
+pub const MQTT_KEEP_ALIVE_SECS: u64 = 30;
+
+#[derive(Clone)]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic_prefix: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub discovery: bool,
+    pub discovery_prefix: String,
+}
+
+/// Last seen values, needed for repeating the discovery when Home Assistant restarts
+#[derive(Default)]
+struct MqttState {
+    device: Option<Arc<DeviceInfo>>,
+    last_params: Vec<Parameter>,
+    //parameters which have their discovery config already published
+    announced: HashSet<String>,
+}
+
+pub struct MqttSink {
+    pub name: String,
+    pub settings: MqttSettings,
+    client: Option<AsyncClient>,
+    state: Arc<Mutex<MqttState>>,
+    poller: Option<JoinHandle<()>>,
+}
+
+/// Home Assistant `device_class` and `state_class` for a parameter unit
+fn ha_classes(name: &str, unit: &str) -> (Option<&'static str>, Option<&'static str>) {
+    match unit {
+        "W" | "kW" => (Some("power"), Some("measurement")),
+        "kWh" | "Wh" => (Some("energy"), Some("total_increasing")),
+        "V" => (Some("voltage"), Some("measurement")),
+        "A" => (Some("current"), Some("measurement")),
+        "°C" => (Some("temperature"), Some("measurement")),
+        "Hz" => (Some("frequency"), Some("measurement")),
+        "VA" => (Some("apparent_power"), Some("measurement")),
+        "VAr" | "Var" => (Some("reactive_power"), Some("measurement")),
+        "%" if name.ends_with("soc") => (Some("battery"), Some("measurement")),
+        "%" => (None, Some("measurement")),
+        _ => (None, None),
+    }
+}
+
+/// Only real physical units are passed as `unit_of_measurement`,
+/// not the internal markers like `epoch` or `status_enum`
+fn ha_unit(unit: &str) -> Option<&str> {
+    match unit {
+        "W" | "kW" | "kWh" | "Wh" | "V" | "A" | "°C" | "Hz" | "VA" | "%" | "min" | "MΩ" => Some(unit),
+        "VAr" | "Var" => Some("var"),
+        _ => None,
+    }
+}
+
+/// Topic-safe identifier of the inverter
+fn node_id(device: &DeviceInfo) -> String {
+    let id = match &device.serial_number {
+        Some(sn) => format!("{}_{}", device.name, sn),
+        None => device.name.clone(),
+    };
+    id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+impl MqttSettings {
+    fn state_topic(&self, param: &Parameter) -> String {
+        format!("{}/{}", self.topic_prefix, param.name)
+    }
+
+    fn alert_topic(&self, rule: &str) -> String {
+        format!("{}/alerts/{}", self.topic_prefix, rule)
+    }
+
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    fn ha_status_topic(&self) -> String {
+        format!("{}/status", self.discovery_prefix)
+    }
+
+    fn discovery_config(&self, device: &DeviceInfo, param: &Parameter) -> (String, String) {
+        let node = node_id(device);
+        let unit = param.unit.unwrap_or_default();
+        let (device_class, state_class) = ha_classes(&param.name, unit);
+
+        let mut config = json!({
+            "name": param.name,
+            "unique_id": format!("{}_{}", node, param.name),
+            "object_id": format!("{}_{}", device.name, param.name),
+            "state_topic": self.state_topic(param),
+            "availability_topic": self.availability_topic(),
+            "device": {
+                "identifiers": [node],
+                "manufacturer": "Huawei",
+                "name": device.model_name.clone().unwrap_or_else(|| device.name.clone()),
+                "model": device.model_name,
+                "sw_version": device.software_version,
+            },
+        });
+        if let Some(unit) = ha_unit(unit) {
+            config["unit_of_measurement"] = json!(unit);
+        }
+        if let Some(device_class) = device_class {
+            config["device_class"] = json!(device_class);
+        }
+        if let Some(state_class) = state_class {
+            config["state_class"] = json!(state_class);
+        }
+
+        let topic = format!("{}/sensor/{}/{}/config", self.discovery_prefix, node, param.name);
+        (topic, config.to_string())
+    }
+
+    fn publish(&self, client: &AsyncClient, topic: String, retain: bool, payload: String) {
+        if let Err(e) = client.try_publish(topic, self.qos, retain, payload) {
+            debug!("mqtt: publish error: {}", e);
+        }
+    }
+
+    fn publish_parameters(&self, client: &AsyncClient, state: &mut MqttState, params: &[Parameter]) {
+        for p in params.iter().filter(|p| p.save_to_influx) {
+            if self.discovery && !state.announced.contains(&p.name) {
+                if let Some(device) = &state.device {
+                    let (topic, config) = self.discovery_config(device, p);
+                    self.publish(client, topic, true, config);
+                    state.announced.insert(p.name.clone());
+                }
+            }
+            self.publish(client, self.state_topic(p), self.retain, p.get_text_value());
+        }
+    }
+}
+
+/// The event loop has to be polled all the time to keep the connection alive
+async fn poll_eventloop(
+    name: String,
+    settings: MqttSettings,
+    client: AsyncClient,
+    mut eventloop: EventLoop,
+    state: Arc<Mutex<MqttState>>,
+) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("<i>{}</>: connected to <u>{}:{}</>", name, settings.host, settings.port);
+                settings.publish(&client, settings.availability_topic(), true, "online".into());
+                if settings.discovery {
+                    if let Err(e) = client.try_subscribe(settings.ha_status_topic(), QoS::AtLeastOnce) {
+                        warn!("<i>{}</>: subscribe error: <b>{}</>", name, e);
+                    }
+                }
+                //the broker may have lost the retained configs
+                if let Ok(mut state) = state.lock() {
+                    state.announced.clear();
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) => {
+                //home assistant has been restarted: announce everything again
+                if p.topic == settings.ha_status_topic() && &p.payload[..] == b"online" {
+                    debug!("{}: home assistant is online, repeating discovery", name);
+                    if let Ok(mut state) = state.lock() {
+                        state.announced.clear();
+                        let params = std::mem::take(&mut state.last_params);
+                        settings.publish_parameters(&client, &mut state, &params);
+                        state.last_params = params;
+                    }
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(e) => {
+                error!("<i>{}</>: connection error: <b>{}</>", name, e);
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+        }
+    }
+}
+
+impl MqttSink {
+    pub fn new(name: String, settings: MqttSettings) -> Self {
+        Self {
+            name,
+            settings,
+            client: None,
+            state: Arc::new(Mutex::new(MqttState::default())),
+            poller: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        let s = &self.settings;
+        let mut options = MqttOptions::new(&s.client_id, &s.host, s.port);
+        options.set_keep_alive(Duration::from_secs(MQTT_KEEP_ALIVE_SECS));
+        options.set_last_will(LastWill::new(s.availability_topic(), "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = &s.username {
+            options.set_credentials(username, s.password.clone().unwrap_or_default());
+        }
+        let (client, eventloop) = AsyncClient::new(options, MQTT_CLIENT_CAPACITY);
+        self.poller = Some(tokio::spawn(poll_eventloop(
+            self.name.clone(),
+            self.settings.clone(),
+            client.clone(),
+            eventloop,
+            self.state.clone(),
+        )));
+        self.client = Some(client);
+        Ok(())
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        let client = match &self.client {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        if let Ok(mut state) = self.state.lock() {
+            if state.device.as_deref().map(|d| (&d.name, &d.serial_number))
+                != Some((&batch.device.name, &batch.device.serial_number))
+            {
+                state.announced.clear();
+            }
+            state.device = Some(batch.device.clone());
+            self.settings.publish_parameters(client, &mut state, &batch.parameters);
+            if !batch.metadata.initial_read {
+                state.last_params = batch.parameters.clone();
+            }
+        }
+        for event in batch.events.iter().filter(|e| e.actions.contains(&RuleAction::Mqtt)) {
+            let payload = event.to_json(&batch.device).to_string();
+            self.settings.publish(client, self.settings.alert_topic(&event.rule), self.settings.retain, payload);
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        //graceful disconnect, so the last will is not sent
+        if let Some(client) = &self.client {
+            self.settings.publish(client, self.settings.availability_topic(), true, "offline".into());
+            let _ = client.try_disconnect();
+        }
+        if let Some(mut poller) = self.poller.take() {
+            if tokio::time::timeout(Duration::from_secs(2), &mut poller).await.is_err() {
+                debug!("{}: event loop not finished in time", self.name);
+                poller.abort();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::{RuleEvent, RuleState};
+    use crate::sink::BatchMetadata;
+    use crate::sun2000::ParamKind;
+    use crate::testutil::{next, MqttBroker, MqttPacket};
+    use serde_json::Value;
+
+    fn settings(port: u16) -> MqttSettings {
+        MqttSettings {
+            host: "127.0.0.1".into(),
+            port,
+            client_id: "hard-test".into(),
+            username: Some("hard".into()),
+            password: Some("secret".into()),
+            topic_prefix: "sun2000".into(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            discovery: true,
+            discovery_prefix: "homeassistant".into(),
+        }
+    }
+
+    fn batch(initial_read: bool) -> ReadingBatch {
+        ReadingBatch {
+            device: Arc::new(DeviceInfo {
+                name: "sun2000".into(),
+                model_name: Some("SUN2000-10KTL-M1".into()),
+                serial_number: Some("HV2150012345".into()),
+                software_version: Some("V100R001C00SPC140".into()),
+                ..Default::default()
+            }),
+            timestamp: 1000,
+            parameters: vec![
+                Parameter::new("active_power", ParamKind::NumberI32(Some(4250)), 1000, None, Some("kW"), 1000, 32080, 2, false, true),
+                Parameter::new("power_factor", ParamKind::NumberI16(Some(999)), 1000, None, None, 1000, 32084, 1, false, false),
+            ],
+            metadata: BatchMetadata {
+                initial_read,
+                query_time_ms: 100,
+            },
+            events: vec![],
+        }
+    }
+
+    fn publish(topic: &str, payload: &str, qos: u8, retain: bool) -> MqttPacket {
+        MqttPacket::Publish {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos,
+            retain,
+        }
+    }
+
+    /// The discovery config published for the active_power
+    async fn discovery(broker: &mut MqttBroker) -> Value {
+        match next(&mut broker.rx).await {
+            MqttPacket::Publish { topic, payload, qos: 1, retain: true }
+                if topic == "homeassistant/sensor/sun2000_hv2150012345/active_power/config" =>
+            {
+                serde_json::from_str(&payload).unwrap()
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(ha_classes("daily_yield_energy", "kWh"), (Some("energy"), Some("total_increasing")));
+        assert_eq!(ha_classes("storage_state_of_capacity", "%"), (None, Some("measurement")));
+        assert_eq!(ha_classes("storage_soc", "%"), (Some("battery"), Some("measurement")));
+        assert_eq!(ha_classes("device_status", "status_enum"), (None, None));
+        assert_eq!(ha_unit("status_enum"), None);
+        assert_eq!(ha_unit("VAr"), Some("var"));
+    }
+
+    #[tokio::test]
+    async fn publishing() {
+        let mut broker = MqttBroker::start().await;
+        let mut sink = MqttSink::new("mqtt".into(), settings(broker.address.port()));
+        sink.start().await.unwrap();
+        assert_eq!(
+            next(&mut broker.rx).await,
+            MqttPacket::Connect {
+                client_id: "hard-test".into(),
+                username: Some("hard".into()),
+                password: Some("secret".into()),
+                will: Some(("sun2000/status".into(), "offline".into())),
+            }
+        );
+        assert_eq!(next(&mut broker.rx).await, publish("sun2000/status", "online", 1, true));
+        assert_eq!(next(&mut broker.rx).await, MqttPacket::Subscribe { topic: "homeassistant/status".into() });
+
+        //the parameters not saved to influxdb are skipped
+        sink.write(&batch(false)).await.unwrap();
+        let config = discovery(&mut broker).await;
+        assert_eq!(config["unique_id"], "sun2000_hv2150012345_active_power");
+        assert_eq!(config["state_topic"], "sun2000/active_power");
+        assert_eq!(config["availability_topic"], "sun2000/status");
+        assert_eq!(config["unit_of_measurement"], "kW");
+        assert_eq!(config["device_class"], "power");
+        assert_eq!(config["state_class"], "measurement");
+        assert_eq!(config["device"]["name"], "SUN2000-10KTL-M1");
+        assert_eq!(config["device"]["sw_version"], "V100R001C00SPC140");
+        assert_eq!(next(&mut broker.rx).await, publish("sun2000/active_power", "4.25", 1, false));
+
+        //announced only once
+        let mut batch = batch(false);
+        batch.events.push(RuleEvent {
+            rule: "high_power".into(),
+            state: RuleState::Firing,
+            param: "active_power".into(),
+            value: 4.25,
+            unit: "kW",
+            condition: "active_power > 4".into(),
+            message: "too much".into(),
+            time: 1000,
+            actions: vec![RuleAction::Mqtt],
+        });
+        sink.write(&batch).await.unwrap();
+        assert_eq!(next(&mut broker.rx).await, publish("sun2000/active_power", "4.25", 1, false));
+        match next(&mut broker.rx).await {
+            MqttPacket::Publish { topic, payload, .. } => {
+                assert_eq!(topic, "sun2000/alerts/high_power");
+                let alert: Value = serde_json::from_str(&payload).unwrap();
+                assert_eq!((alert["state"].as_str(), alert["message"].as_str()), (Some("firing"), Some("too much")));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        //home assistant restarted
+        broker.tx.send(("homeassistant/status".into(), "online".into())).unwrap();
+        discovery(&mut broker).await;
+        assert_eq!(next(&mut broker.rx).await, publish("sun2000/active_power", "4.25", 1, false));
+
+        sink.stop().await.unwrap();
+        assert_eq!(next(&mut broker.rx).await, publish("sun2000/status", "offline", 1, true));
+        assert_eq!(next(&mut broker.rx).await, MqttPacket::Disconnect);
+    }
+}
@@ -0,0 +1,489 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use humantime::format_duration;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde_json::json;
+use simplelog::*;
+
+use crate::config::REDACTED;
+use crate::rules::{self, RuleAction, RuleEvent};
+use crate::sink::Result;
+use crate::smtp::{self, SmtpSettings};
+use crate::sun2000::{DeviceInfo, Parameter};
+use crate::webhook::WEBHOOK_TIMEOUT_SECS;
+
+pub const NOTIFY_DEFAULT_TITLE: &str = "%inverter% (%model% %serial_number%): %event%";
+pub const NOTIFY_DEFAULT_MESSAGE: &str = "%details%";
+
+/// Parameters whose decoded bits are the `%alarms%`
+const ALARM_PARAMS: [&str; 3] = ["alarm_1", "alarm_2", "alarm_3"];
+
+/// Where the notifications are sent, one for every configured section
+#[derive(Clone, PartialEq)]
+pub enum Channel {
+    Email(SmtpSettings),
+    /// publishing to the topic of a ntfy server
+    Ntfy {
+        url: String,
+        topic: String,
+        token: Option<String>,
+        priority: u8,
+    },
+    /// sendMessage of the Telegram Bot API
+    Telegram {
+        api_url: String,
+        bot_token: String,
+        chat_id: String,
+    },
+    Gotify {
+        url: String,
+        token: String,
+        priority: u8,
+    },
+}
+
+/// The credentials are never printed
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Channel::Email(settings) => f.debug_tuple("Email").field(settings).finish(),
+            Channel::Ntfy { url, topic, token, priority } => f
+                .debug_struct("Ntfy")
+                .field("url", url)
+                .field("topic", topic)
+                .field("token", &token.as_ref().map(|_| REDACTED))
+                .field("priority", priority)
+                .finish(),
+            Channel::Telegram { api_url, chat_id, .. } => f
+                .debug_struct("Telegram")
+                .field("api_url", api_url)
+                .field("bot_token", &REDACTED)
+                .field("chat_id", chat_id)
+                .finish(),
+            Channel::Gotify { url, priority, .. } => f
+                .debug_struct("Gotify")
+                .field("url", url)
+                .field("token", &REDACTED)
+                .field("priority", priority)
+                .finish(),
+        }
+    }
+}
+
+impl Channel {
+    /// Rule action sending the rule events to this channel
+    pub fn action(&self) -> RuleAction {
+        match self {
+            Channel::Email(_) => RuleAction::Email,
+            Channel::Ntfy { .. } => RuleAction::Ntfy,
+            Channel::Telegram { .. } => RuleAction::Telegram,
+            Channel::Gotify { .. } => RuleAction::Gotify,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Channel::Email(_) => "email",
+            Channel::Ntfy { .. } => "ntfy",
+            Channel::Telegram { .. } => "telegram",
+            Channel::Gotify { .. } => "gotify",
+        }
+    }
+
+    async fn send(&self, client: &Client, title: &str, message: &str) -> Result<()> {
+        let request = match self {
+            Channel::Email(settings) => return smtp::send(settings, title, message).await,
+            Channel::Ntfy { url, topic, token, priority } => {
+                //publishing as json is the only way to have utf-8 in the title
+                let request = client.post(url.trim_end_matches('/')).body(
+                    json!({"topic": topic, "title": title, "message": message, "priority": priority}).to_string(),
+                );
+                match token {
+                    Some(token) => request.bearer_auth(token),
+                    None => request,
+                }
+            }
+            Channel::Telegram { api_url, bot_token, chat_id } => client
+                .post(format!("{}/bot{}/sendMessage", api_url.trim_end_matches('/'), bot_token))
+                .body(json!({"chat_id": chat_id, "text": format!("{}\n\n{}", title, message)}).to_string()),
+            Channel::Gotify { url, token, priority } => client
+                .post(format!("{}/message", url.trim_end_matches('/')))
+                .header("X-Gotify-Key", token)
+                .body(json!({"title": title, "message": message, "priority": priority}).to_string()),
+        };
+        //the url is not logged, the telegram bot token is a part of it
+        let response = request
+            .header(CONTENT_TYPE, "application/json")
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("HTTP {}: {}", status, body.trim()).into());
+        }
+        Ok(())
+    }
+}
+
+/// `[notifications]` of the config, with the configured channels
+#[derive(Clone, Debug, PartialEq)]
+pub struct NotifySettings {
+    pub channels: Vec<Channel>,
+    /// notify when the fault code changes
+    pub faults: bool,
+    /// notify when there are no data for this long, zero disables it
+    pub connection_lost: Duration,
+    pub title: String,
+    pub message: String,
+}
+
+impl Default for NotifySettings {
+    fn default() -> Self {
+        Self {
+            channels: vec![],
+            faults: true,
+            connection_lost: Duration::from_secs(3600),
+            title: NOTIFY_DEFAULT_TITLE.into(),
+            message: NOTIFY_DEFAULT_MESSAGE.into(),
+        }
+    }
+}
+
+/// Detects the faults and the lost connection, and sends the notifications of them
+/// and of the rule events
+pub struct Notifier {
+    name: String,
+    client: Client,
+    /// identity of the last connected inverter
+    device: Option<DeviceInfo>,
+    fault_code: Option<u16>,
+    /// decoded bits of the alarm parameters, by the parameter name
+    alarms: BTreeMap<String, Vec<&'static str>>,
+    /// the last successful poll (or the start)
+    last_seen: Instant,
+    lost_notified: bool,
+}
+
+impl Notifier {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            client: Client::builder()
+                .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+                .build()
+                .unwrap_or_default(),
+            device: None,
+            fault_code: None,
+            alarms: BTreeMap::new(),
+            last_seen: Instant::now(),
+            lost_notified: false,
+        }
+    }
+
+    /// Called with every poll result, notifies about the fault code changes
+    /// and about the restored connection
+    pub fn check(&mut self, settings: &NotifySettings, device: &DeviceInfo, parameters: &[Parameter]) {
+        self.device = Some(device.clone());
+        self.last_seen = Instant::now();
+        if self.lost_notified {
+            self.lost_notified = false;
+            self.notify(settings, None, "connection restored", "the inverter is responding again");
+        }
+
+        for p in parameters.iter().filter(|p| ALARM_PARAMS.contains(&p.name.as_str())) {
+            if let Some(names) = p.get_decoded_value() {
+                self.alarms.insert(p.name.clone(), names);
+            }
+        }
+        let fault_code = match parameters.iter().find(|p| p.name == "fault_code").and_then(|p| p.get_float_value()) {
+            Some(value) => value as u16,
+            None => return,
+        };
+        let old = self.fault_code.replace(fault_code);
+        if !settings.faults || old == Some(fault_code) {
+            return;
+        }
+        if fault_code != 0 {
+            warn!("<i>{}</>: ⚠️ inverter fault, code <b>{}</>, alarms: <b>{}</>", self.name, fault_code, self.alarm_names());
+            let details = format!("fault code {}, alarms: {}", fault_code, self.alarm_names());
+            self.notify(settings, None, "fault", &details);
+        } else if let Some(old) = old {
+            info!("<i>{}</>: fault code <b>{}</> cleared", self.name, old);
+            self.notify(settings, None, "fault cleared", &format!("fault code {} is cleared", old));
+        }
+    }
+
+    /// Called before every connection attempt, notifies once when the inverter is lost for too long.
+    /// The inverter is asleep at night, so it is not missing then.
+    pub fn check_connection(&mut self, settings: &NotifySettings, night: bool) {
+        if night {
+            self.last_seen = Instant::now();
+            return;
+        }
+        if self.lost_notified || settings.connection_lost.is_zero() || self.last_seen.elapsed() < settings.connection_lost {
+            return;
+        }
+        self.lost_notified = true;
+        warn!("<i>{}</>: no data from the inverter for {}", self.name, format_duration(settings.connection_lost));
+        let details = format!("no data from the inverter for {}", format_duration(settings.connection_lost));
+        self.notify(settings, None, "connection lost", &details);
+    }
+
+    /// Sends the rule events to the channels named in the rule actions
+    pub fn rule_events(&self, settings: &NotifySettings, events: &[RuleEvent]) {
+        for e in events {
+            let event = format!("rule {} {}", e.rule, e.state);
+            self.notify(settings, Some(&e.actions), &event, &e.message);
+        }
+    }
+
+    fn alarm_names(&self) -> String {
+        let names: Vec<&str> = self.alarms.values().flatten().copied().collect();
+        if names.is_empty() {
+            "none".into()
+        } else {
+            names.join(", ")
+        }
+    }
+
+    /// Sends the notification to all channels, or only to the ones of the given actions
+    fn notify(&self, settings: &NotifySettings, actions: Option<&[RuleAction]>, event: &str, details: &str) {
+        let channels: Vec<&Channel> = settings
+            .channels
+            .iter()
+            .filter(|c| actions.is_none_or(|actions| actions.contains(&c.action())))
+            .collect();
+        if channels.is_empty() {
+            return;
+        }
+        let device = self.device.clone().unwrap_or_else(|| DeviceInfo {
+            name: self.name.clone(),
+            ..Default::default()
+        });
+        let placeholders = [
+            ("%event%", event.to_string()),
+            ("%details%", details.to_string()),
+            ("%inverter%", device.name.clone()),
+            ("%model%", device.model_name.clone().unwrap_or_default()),
+            ("%serial_number%", device.serial_number.clone().unwrap_or_default()),
+            ("%alarms%", self.alarm_names()),
+            ("%time%", Local::now().format("%F %T").to_string()),
+        ];
+        let title = rules::render(&settings.title, &placeholders).trim().to_string();
+        let message = rules::render(&settings.message, &placeholders).trim().to_string();
+        debug!("<i>{}</>: sending notification {:?} to {} channels", self.name, title, channels.len());
+        for channel in channels {
+            let channel = channel.clone();
+            let client = self.client.clone();
+            let name = self.name.clone();
+            let (title, message) = (title.clone(), message.clone());
+            tokio::spawn(async move {
+                if let Err(e) = channel.send(&client, &title, &message).await {
+                    error!("<i>{}</>: {} notification error: <b>{}</>", name, channel.name(), e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::RuleState;
+    use crate::sun2000::ParamKind;
+    use crate::testutil::{http_server, next, HttpRequest};
+    use serde_json::Value;
+    use std::net::{SocketAddr, TcpListener};
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    const BOT_TOKEN: &str = "123456:ABC-secret";
+
+    fn device() -> DeviceInfo {
+        DeviceInfo {
+            name: "sun2000".into(),
+            model_name: Some("SUN2000-10KTL-M1".into()),
+            serial_number: Some("HV2150012345".into()),
+            ..Default::default()
+        }
+    }
+
+    fn ntfy(address: SocketAddr) -> Channel {
+        Channel::Ntfy {
+            url: format!("http://{}/", address),
+            topic: "solar".into(),
+            token: Some("tk_ntfy".into()),
+            priority: 4,
+        }
+    }
+
+    fn gotify(address: SocketAddr) -> Channel {
+        Channel::Gotify {
+            url: format!("http://{}", address),
+            token: "gotify-token".into(),
+            priority: 8,
+        }
+    }
+
+    fn telegram(api_url: String) -> Channel {
+        Channel::Telegram {
+            api_url,
+            bot_token: BOT_TOKEN.into(),
+            chat_id: "42".into(),
+        }
+    }
+
+    fn poll(fault_code: u16, alarm_1: u16) -> Vec<Parameter> {
+        vec![
+            Parameter::new("alarm_1", ParamKind::NumberU16(Some(alarm_1)), 0, None, Some("alarm_bitfield16"), 1, 32008, 1, false, true),
+            Parameter::new("fault_code", ParamKind::NumberU16(Some(fault_code)), 0, None, None, 1, 32090, 1, false, true),
+        ]
+    }
+
+    /// Title and message of the next notification
+    async fn notification(requests: &mut UnboundedReceiver<HttpRequest>) -> (String, String) {
+        let body: Value = serde_json::from_str(&next(requests).await.body).unwrap();
+        (body["title"].as_str().unwrap().into(), body["message"].as_str().unwrap().into())
+    }
+
+    fn title(event: &str) -> String {
+        format!("sun2000 (SUN2000-10KTL-M1 HV2150012345): {}", event)
+    }
+
+    #[test]
+    fn credentials_not_printed() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 80));
+        for channel in [ntfy(address), gotify(address), telegram("http://localhost".into())] {
+            let debug = format!("{:?}", channel);
+            assert!(!debug.contains("tk_ntfy") && !debug.contains("gotify-token") && !debug.contains(BOT_TOKEN), "{}", debug);
+        }
+    }
+
+    #[tokio::test]
+    async fn channels() {
+        let (address, mut requests) = http_server(200, "");
+        let client = Client::new();
+
+        ntfy(address).send(&client, "title ⚠️", "message").await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!((request.method.as_str(), request.uri.as_str()), ("POST", "/"));
+        assert_eq!(request.header("authorization"), Some("Bearer tk_ntfy"));
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body, json!({"topic": "solar", "title": "title ⚠️", "message": "message", "priority": 4}));
+
+        gotify(address).send(&client, "title", "message").await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!(request.uri, "/message");
+        assert_eq!(request.header("x-gotify-key"), Some("gotify-token"));
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body, json!({"title": "title", "message": "message", "priority": 8}));
+
+        telegram(format!("http://{}", address)).send(&client, "title", "message").await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!(request.uri, format!("/bot{}/sendMessage", BOT_TOKEN));
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body, json!({"chat_id": "42", "text": "title\n\nmessage"}));
+    }
+
+    #[tokio::test]
+    async fn telegram_errors() {
+        let client = Client::new();
+        let (address, _requests) = http_server(401, r#"{"ok":false,"error_code":401,"description":"Unauthorized"}"#);
+        let e = telegram(format!("http://{}", address)).send(&client, "title", "message").await.unwrap_err();
+        assert_eq!(e.to_string(), r#"HTTP 401 Unauthorized: {"ok":false,"error_code":401,"description":"Unauthorized"}"#);
+
+        //the token is a part of the url, which is not in the error
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let e = telegram(format!("http://{}", closed)).send(&client, "title", "message").await.unwrap_err();
+        assert!(!e.to_string().contains(BOT_TOKEN), "{}", e);
+        assert!(!format!("{:?}", e).contains(BOT_TOKEN), "{:?}", e);
+    }
+
+    #[tokio::test]
+    async fn notifier() {
+        let (ntfy_address, mut ntfy_requests) = http_server(200, "");
+        let (gotify_address, mut gotify_requests) = http_server(200, "");
+        let settings = NotifySettings {
+            channels: vec![ntfy(ntfy_address), gotify(gotify_address)],
+            connection_lost: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut notifier = Notifier::new("sun2000".into());
+
+        notifier.check(&settings, &device(), &poll(0, 0));
+        notifier.check(&settings, &device(), &poll(2064, 0b11));
+        notifier.check(&settings, &device(), &poll(2064, 0b11));
+        let fault = (title("fault"), "fault code 2064, alarms: High String Input Voltage, DC Arc Fault".to_string());
+        assert_eq!(notification(&mut ntfy_requests).await, fault);
+        assert_eq!(notification(&mut gotify_requests).await, fault);
+
+        notifier.check(&settings, &device(), &poll(0, 0));
+        let cleared = (title("fault cleared"), "fault code 2064 is cleared".to_string());
+        assert_eq!(notification(&mut ntfy_requests).await, cleared);
+        assert_eq!(notification(&mut gotify_requests).await, cleared);
+
+        //not missing at night
+        notifier.check_connection(&settings, false);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        notifier.check_connection(&settings, true);
+        notifier.check_connection(&settings, false);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        notifier.check_connection(&settings, false);
+        notifier.check_connection(&settings, false);
+        let lost = (title("connection lost"), "no data from the inverter for 50ms".to_string());
+        assert_eq!(notification(&mut ntfy_requests).await, lost);
+        assert_eq!(notification(&mut gotify_requests).await, lost);
+        notifier.check(&settings, &device(), &poll(0, 0));
+        let restored = (title("connection restored"), "the inverter is responding again".to_string());
+        assert_eq!(notification(&mut ntfy_requests).await, restored);
+        assert_eq!(notification(&mut gotify_requests).await, restored);
+
+        //only to the channels of the rule actions
+        let event = RuleEvent {
+            rule: "low_power".into(),
+            state: RuleState::Firing,
+            param: "active_power".into(),
+            value: 10.0,
+            unit: "W",
+            condition: "active_power < 100".into(),
+            message: "active_power = 10 W".into(),
+            time: 0,
+            actions: vec![RuleAction::Log, RuleAction::Gotify],
+        };
+        notifier.rule_events(&settings, &[event]);
+        assert_eq!(
+            notification(&mut gotify_requests).await,
+            (title("rule low_power firing"), "active_power = 10 W".to_string())
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(ntfy_requests.try_recv().is_err());
+        assert!(gotify_requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn faults_disabled() {
+        let (address, mut requests) = http_server(200, "");
+        let settings = NotifySettings {
+            channels: vec![ntfy(address)],
+            faults: false,
+            title: "%event% at %time%".into(),
+            message: "%alarms%".into(),
+            ..Default::default()
+        };
+        let mut notifier = Notifier::new("sun2000".into());
+        notifier.check(&settings, &device(), &poll(2064, 1));
+        //the templates are rendered for the other events
+        notifier.lost_notified = true;
+        notifier.check(&settings, &device(), &poll(2064, 1));
+        let (title, message) = notification(&mut requests).await;
+        assert!(title.starts_with("connection restored at 20"), "{}", title);
+        assert_eq!(message, "High String Input Voltage");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(requests.try_recv().is_err());
+    }
+}
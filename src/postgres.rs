@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::sink::{ReadingBatch, Result, Sink};
+use crate::sun2000::{DeviceInfo, ParamKind, Parameter};
+use async_trait::async_trait;
+use futures::pin_mut;
+use simplelog::*;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{Client, NoTls};
+
+pub const POSTGRES_MAX_PENDING_ROWS: usize = 100_000; //rows kept in memory while the database is unreachable
+
+/// Schema migrations, applied in order; the index + 1 is the schema version
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE devices (
+        id SERIAL PRIMARY KEY,
+        name TEXT NOT NULL,
+        serial_number TEXT NOT NULL DEFAULT '',
+        model_name TEXT,
+        product_number TEXT,
+        software_version TEXT,
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        UNIQUE (name, serial_number)
+    );
+    CREATE TABLE parameters (
+        id SERIAL PRIMARY KEY,
+        name TEXT NOT NULL,
+        reg_address INTEGER NOT NULL,
+        unit TEXT,
+        description TEXT,
+        gain INTEGER NOT NULL,
+        UNIQUE (name, reg_address)
+    );
+    CREATE TABLE readings (
+        time TIMESTAMPTZ NOT NULL,
+        device_id INTEGER NOT NULL REFERENCES devices (id),
+        parameter_id INTEGER NOT NULL REFERENCES parameters (id),
+        value DOUBLE PRECISION,
+        text_value TEXT
+    );
+    CREATE INDEX readings_device_parameter_time_idx ON readings (device_id, parameter_id, time DESC);",
+];
+
+/// Columns of the `readings` COPY and their types
+const COPY_COLUMNS: &str = "time, device_id, parameter_id, value, text_value";
+const COPY_TYPES: &[Type] = &[Type::TIMESTAMPTZ, Type::INT4, Type::INT4, Type::FLOAT8, Type::TEXT];
+
+struct Row {
+    time: SystemTime,
+    /// the inverter which was polled, its id is looked up when flushing
+    device: Arc<DeviceInfo>,
+    parameter: Parameter,
+}
+
+impl Row {
+    /// Values of the `COPY_COLUMNS`
+    fn copy_values(&self, device_id: i32, parameter_id: i32) -> [Box<dyn ToSql + Sync + Send>; 5] {
+        let text_value = match self.parameter.value {
+            ParamKind::Text(_) => Some(self.parameter.get_text_value()),
+            _ => None,
+        };
+        [
+            Box::new(self.time),
+            Box::new(device_id),
+            Box::new(parameter_id),
+            Box::new(self.parameter.get_float_value()),
+            Box::new(text_value),
+        ]
+    }
+}
+
+/// Migrations not applied yet to the schema of the `version`, with their new versions
+fn pending_migrations(version: i32) -> impl Iterator<Item = (i32, &'static str)> {
+    MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(version.max(0) as usize)
+        .map(|(i, migration)| (i as i32 + 1, *migration))
+}
+
+#[derive(Clone)]
+pub struct PostgresSettings {
+    pub host: String,
+    pub port: u16,
+    pub dbname: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub timescaledb: bool,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+}
+
+pub struct PostgresSink {
+    pub name: String,
+    pub settings: PostgresSettings,
+    client: Option<Client>,
+    /// ids of the devices by the name and the serial number
+    device_ids: HashMap<(String, String), i32>,
+    cache: HashMap<(String, u16), i32>,
+    pending: Vec<Row>,
+    flush_interval: Instant,
+    reconnect_interval: Option<Instant>,
+}
+
+impl PostgresSink {
+    pub fn new(name: String, settings: PostgresSettings) -> Self {
+        Self {
+            name,
+            settings,
+            client: None,
+            device_ids: HashMap::new(),
+            cache: HashMap::new(),
+            pending: vec![],
+            flush_interval: Instant::now(),
+            reconnect_interval: None,
+        }
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let s = &self.settings;
+        let mut config = tokio_postgres::Config::new();
+        config
+            .host(&s.host)
+            .port(s.port)
+            .dbname(&s.dbname)
+            .connect_timeout(Duration::from_secs(5));
+        if let Some(username) = &s.username {
+            config.user(username);
+        }
+        if let Some(password) = &s.password {
+            config.password(password);
+        }
+        let (client, connection) = config.connect(NoTls).await?;
+        let name = self.name.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("<i>{}</>: connection error: <b>{}</>", name, e);
+            }
+        });
+        Ok(client)
+    }
+
+    async fn migrate(&self, client: &mut Client) -> Result<()> {
+        client
+            .batch_execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")
+            .await?;
+        let version: i32 = client
+            .query_opt("SELECT max(version) FROM schema_version", &[])
+            .await?
+            .and_then(|row| row.get::<_, Option<i32>>(0))
+            .unwrap_or(0);
+
+        for (new_version, migration) in pending_migrations(version) {
+            info!("<i>{}</>: migrating schema to version <b>{}</>", self.name, new_version);
+            let tx = client.transaction().await?;
+            tx.batch_execute(migration).await?;
+            tx.execute("INSERT INTO schema_version (version) VALUES ($1)", &[&new_version])
+                .await?;
+            tx.commit().await?;
+        }
+
+        if self.settings.timescaledb {
+            client
+                .batch_execute(
+                    "CREATE EXTENSION IF NOT EXISTS timescaledb;
+                     SELECT create_hypertable('readings', 'time', if_not_exists => TRUE, migrate_data => TRUE);",
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn device_id(
+        &self,
+        client: &Client,
+        device_ids: &mut HashMap<(String, String), i32>,
+        device: &DeviceInfo,
+    ) -> Result<i32> {
+        let key = (device.name.clone(), device.serial_number.clone().unwrap_or_default());
+        if let Some(id) = device_ids.get(&key) {
+            return Ok(*id);
+        }
+        let row = client
+            .query_one(
+                "INSERT INTO devices (name, serial_number, model_name, product_number, software_version)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (name, serial_number) DO UPDATE SET
+                    model_name = EXCLUDED.model_name,
+                    product_number = EXCLUDED.product_number,
+                    software_version = EXCLUDED.software_version,
+                    updated_at = now()
+                 RETURNING id",
+                &[
+                    &device.name,
+                    &device.serial_number.clone().unwrap_or_default(),
+                    &device.model_name,
+                    &device.product_number,
+                    &device.software_version,
+                ],
+            )
+            .await?;
+        let id: i32 = row.get(0);
+        device_ids.insert(key, id);
+        Ok(id)
+    }
+
+    async fn parameter_id(
+        &self,
+        client: &Client,
+        cache: &mut HashMap<(String, u16), i32>,
+        p: &Parameter,
+    ) -> Result<i32> {
+        if let Some(id) = cache.get(&(p.name.clone(), p.reg_address)) {
+            return Ok(*id);
+        }
+        let row = client
+            .query_one(
+                "INSERT INTO parameters (name, reg_address, unit, description, gain)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (name, reg_address) DO UPDATE SET
+                    unit = EXCLUDED.unit,
+                    description = EXCLUDED.description,
+                    gain = EXCLUDED.gain
+                 RETURNING id",
+                &[&p.name, &(p.reg_address as i32), &p.unit, &p.desc, &(p.gain as i32)],
+            )
+            .await?;
+        let id: i32 = row.get(0);
+        cache.insert((p.name.clone(), p.reg_address), id);
+        Ok(id)
+    }
+
+    async fn flush(
+        &self,
+        client: &Client,
+        device_ids: &mut HashMap<(String, String), i32>,
+        cache: &mut HashMap<(String, u16), i32>,
+        rows: &[Row],
+    ) -> Result<()> {
+        let mut ids = Vec::with_capacity(rows.len());
+        for row in rows {
+            ids.push((
+                self.device_id(client, device_ids, &row.device).await?,
+                self.parameter_id(client, cache, &row.parameter).await?,
+            ));
+        }
+
+        let sink = client
+            .copy_in(&format!("COPY readings ({}) FROM STDIN BINARY", COPY_COLUMNS))
+            .await?;
+        let writer = BinaryCopyInWriter::new(sink, COPY_TYPES);
+        pin_mut!(writer);
+        for (row, (device_id, parameter_id)) in rows.iter().zip(ids) {
+            let values = row.copy_values(device_id, parameter_id);
+            let values: Vec<&(dyn ToSql + Sync)> = values.iter().map(|v| &**v as _).collect();
+            writer.as_mut().write(&values).await?;
+        }
+        writer.finish().await?;
+        Ok(())
+    }
+
+    /// (Re)connects and makes sure the schema is up to date
+    async fn reconnect(&mut self) {
+        let connected = self.client.as_ref().is_some_and(|c| !c.is_closed());
+        if connected || self.reconnect_interval.is_some_and(|i| i.elapsed() <= Duration::from_secs(10)) {
+            return;
+        }
+        self.reconnect_interval = Some(Instant::now());
+        self.cache.clear();
+        self.device_ids.clear();
+        info!(
+            "<i>{}</>: connecting to <u>{}:{}/{}</>...",
+            self.name, self.settings.host, self.settings.port, self.settings.dbname
+        );
+        self.client = match self.connect().await {
+            Ok(mut c) => match self.migrate(&mut c).await {
+                Ok(()) => {
+                    info!("<i>{}</>: connected successfully", self.name);
+                    Some(c)
+                }
+                Err(e) => {
+                    error!("<i>{}</>: schema migration error: <b>{}</>", self.name, e);
+                    None
+                }
+            },
+            Err(e) => {
+                error!("<i>{}</>: connection error: <b>{}</>", self.name, e);
+                None
+            }
+        };
+    }
+
+    async fn flush_pending(&mut self) -> Result<()> {
+        self.flush_interval = Instant::now();
+        if let Some(c) = &self.client {
+            if !self.pending.is_empty() {
+                let mut device_ids = std::mem::take(&mut self.device_ids);
+                let mut cache = std::mem::take(&mut self.cache);
+                let res = self.flush(c, &mut device_ids, &mut cache, &self.pending).await;
+                self.device_ids = device_ids;
+                self.cache = cache;
+                res?;
+                debug!("{}: inserted {} rows", self.name, self.pending.len());
+                self.pending.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for PostgresSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        for p in batch.parameters.iter().filter(|p| p.save_to_influx) {
+            self.pending.push(Row {
+                time: UNIX_EPOCH + Duration::from_millis(p.time as u64),
+                device: batch.device.clone(),
+                parameter: p.clone(),
+            });
+        }
+        if self.pending.len() > POSTGRES_MAX_PENDING_ROWS {
+            let excess = self.pending.len() - POSTGRES_MAX_PENDING_ROWS;
+            warn!("<i>{}</>: too many pending rows, dropping <b>{}</> oldest", self.name, excess);
+            self.pending.drain(0..excess);
+        }
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> Result<()> {
+        self.reconnect().await;
+        if self.pending.len() >= self.settings.batch_size
+            || self.flush_interval.elapsed() > self.settings.flush_interval
+        {
+            self.flush_pending().await?;
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        self.flush_pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::BatchMetadata;
+    use tokio_postgres::types::private::BytesMut;
+    use tokio_postgres::types::{FromSql, IsNull};
+
+    #[test]
+    fn migrations() {
+        let versions: Vec<i32> = pending_migrations(0).map(|(version, _)| version).collect();
+        assert_eq!(versions, (1..=MIGRATIONS.len() as i32).collect::<Vec<_>>());
+        assert_eq!(pending_migrations(0).next().unwrap().1, MIGRATIONS[0]);
+        assert_eq!(pending_migrations(MIGRATIONS.len() as i32).count(), 0);
+        //a database migrated by a newer version
+        assert_eq!(pending_migrations(MIGRATIONS.len() as i32 + 1).count(), 0);
+
+        //every referenced table is created before
+        let mut tables = vec![];
+        for (_, migration) in pending_migrations(0) {
+            for statement in migration.split(';') {
+                for reference in statement.split("REFERENCES ").skip(1) {
+                    let table = reference.split_whitespace().next().unwrap();
+                    assert!(tables.contains(&table), "{} referenced before created", table);
+                }
+                if let Some(table) = statement.split("CREATE TABLE ").nth(1) {
+                    tables.push(table.split_whitespace().next().unwrap());
+                }
+            }
+        }
+        assert_eq!(tables, vec!["devices", "parameters", "readings"]);
+    }
+
+    fn row(time: u64, parameter: Parameter) -> Row {
+        Row {
+            time: UNIX_EPOCH + Duration::from_millis(time),
+            device: Arc::new(DeviceInfo::default()),
+            parameter,
+        }
+    }
+
+    /// Binary encoding of the value in the COPY field, None for NULL
+    fn encode(value: &(dyn ToSql + Sync + Send), ty: &Type) -> Option<BytesMut> {
+        let mut buf = BytesMut::new();
+        match value.to_sql_checked(ty, &mut buf).unwrap() {
+            IsNull::Yes => None,
+            IsNull::No => Some(buf),
+        }
+    }
+
+    #[test]
+    fn copy_encoding() {
+        let power = Parameter::new("active_power", ParamKind::NumberI32(Some(-12345)), 0, None, Some("kW"), 1000, 32080, 2, false, true);
+        let values = row(1_700_000_000_123, power).copy_values(3, 42);
+        assert_eq!(COPY_COLUMNS.split(", ").count(), values.len());
+        assert_eq!(COPY_TYPES.len(), values.len());
+        let fields: Vec<_> = values.iter().zip(COPY_TYPES).map(|(v, ty)| encode(&**v, ty)).collect();
+
+        let time = SystemTime::from_sql(&Type::TIMESTAMPTZ, fields[0].as_ref().unwrap()).unwrap();
+        assert_eq!(time, UNIX_EPOCH + Duration::from_millis(1_700_000_000_123));
+        assert_eq!(&fields[1].as_ref().unwrap()[..], &3i32.to_be_bytes());
+        assert_eq!(&fields[2].as_ref().unwrap()[..], &42i32.to_be_bytes());
+        assert_eq!(&fields[3].as_ref().unwrap()[..], &(-12.345f64).to_be_bytes());
+        //NULL text_value
+        assert!(fields[4].is_none());
+
+        let model = Parameter::new("model_name", ParamKind::Text(Some("SUN2000-10KTL".into())), 0, None, None, 1, 30000, 15, true, true);
+        let values = row(0, model).copy_values(3, 1);
+        let fields: Vec<_> = values.iter().zip(COPY_TYPES).map(|(v, ty)| encode(&**v, ty)).collect();
+        assert!(fields[3].is_none());
+        assert_eq!(&fields[4].as_ref().unwrap()[..], b"SUN2000-10KTL");
+    }
+
+    #[tokio::test]
+    async fn pending_cap() {
+        let settings = PostgresSettings {
+            host: "127.0.0.1".into(),
+            port: 5432,
+            dbname: "hard".into(),
+            username: None,
+            password: None,
+            timescaledb: false,
+            batch_size: 100,
+            flush_interval: Duration::from_secs(10),
+        };
+        let mut sink = PostgresSink::new("postgres".into(), settings);
+        let batch = |time: u128| ReadingBatch {
+            device: Arc::new(DeviceInfo::default()),
+            timestamp: time,
+            parameters: (0..1000)
+                .map(|i| {
+                    let value = ParamKind::NumberU16(Some(i));
+                    Parameter::new("pv_voltage", value, time, None, Some("V"), 10, 32016 + i, 1, false, true)
+                })
+                .collect(),
+            metadata: BatchMetadata::default(),
+            events: vec![],
+        };
+        //the database is unreachable
+        for time in 0..100 {
+            sink.write(&batch(time)).await.unwrap();
+        }
+        assert_eq!(sink.pending.len(), POSTGRES_MAX_PENDING_ROWS);
+        assert_eq!(sink.pending[0].time, UNIX_EPOCH);
+
+        //the oldest rows are dropped
+        sink.write(&batch(100)).await.unwrap();
+        sink.write(&batch(101)).await.unwrap();
+        assert_eq!(sink.pending.len(), POSTGRES_MAX_PENDING_ROWS);
+        assert_eq!(sink.pending[0].time, UNIX_EPOCH + Duration::from_millis(2));
+        assert_eq!(sink.pending.last().unwrap().time, UNIX_EPOCH + Duration::from_millis(101));
+    }
+}
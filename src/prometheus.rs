@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::sink::{ReadingBatch, Result, Sink};
+use crate::sun2000::{DeviceInfo, Parameter, Sun2000Stats};
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use simplelog::*;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// Latest poll results, rendered on every scrape
+#[derive(Default)]
+struct MetricsState {
+    device: Arc<DeviceInfo>,
+    params: BTreeMap<String, Parameter>,
+}
+
+pub struct PrometheusSink {
+    pub name: String,
+    pub listen: SocketAddr,
+    pub stats: Arc<Sun2000Stats>,
+    state: Arc<Mutex<MetricsState>>,
+    server: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+}
+
+/// Base unit suffix of the metric name, as recommended by the Prometheus naming conventions
+fn unit_suffix(unit: &str) -> Option<&'static str> {
+    match unit {
+        "W" => Some("watts"),
+        "kW" => Some("kilowatts"),
+        "Wh" => Some("watt_hours"),
+        "kWh" => Some("kilowatt_hours"),
+        "V" => Some("volts"),
+        "A" | "I" => Some("amperes"),
+        "°C" => Some("celsius"),
+        "Hz" => Some("hertz"),
+        "%" => Some("percent"),
+        "VA" => Some("voltamperes"),
+        "VAr" | "Var" => Some("voltamperes_reactive"),
+        "kVarH" => Some("kilovoltampere_reactive_hours"),
+        "MΩ" => Some("megaohms"),
+        "min" => Some("minutes"),
+        "epoch" => Some("timestamp_seconds"),
+        _ => None,
+    }
+}
+
+/// Energy totals are only growing, so they are counters;
+/// the daily values are reset at midnight and stay gauges
+fn is_counter(param: &Parameter) -> bool {
+    matches!(param.unit, Some("kWh") | Some("kVarH"))
+        && !param.name.contains("daily")
+        && !param.name.contains("current_day")
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render(state: &MetricsState, stats: &Sun2000Stats) -> String {
+    let mut out = String::new();
+    let mut labels = format!("inverter=\"{}\"", escape_label(&state.device.name));
+    if let Some(sn) = &state.device.serial_number {
+        let _ = write!(labels, ",serial_number=\"{}\"", escape_label(sn));
+    }
+
+    //inverter identity
+    let _ = writeln!(out, "# HELP sun2000_info Inverter identity obtained after connecting");
+    let _ = writeln!(out, "# TYPE sun2000_info gauge");
+    let mut info_labels = labels.clone();
+    for (label, value) in [
+        ("model_name", &state.device.model_name),
+        ("product_number", &state.device.product_number),
+        ("software_version", &state.device.software_version),
+    ] {
+        if let Some(v) = value {
+            let _ = write!(info_labels, ",{}=\"{}\"", label, escape_label(v));
+        }
+    }
+    let _ = writeln!(out, "sun2000_info{{{}}} 1", info_labels);
+
+    //polled parameters
+    for p in state.params.values() {
+        let value = match p.get_float_value() {
+            Some(v) => v,
+            None => continue,
+        };
+        let unit = p.unit.unwrap_or_default();
+        let counter = is_counter(p);
+        let mut metric = format!("sun2000_{}", p.name);
+        if let Some(suffix) = unit_suffix(unit) {
+            metric = format!("{}_{}", metric, suffix);
+        }
+        if counter {
+            metric.push_str("_total");
+        }
+        let help = match (p.desc, unit.is_empty()) {
+            (Some(desc), false) => format!("{} [{}]", desc, unit),
+            (Some(desc), true) => desc.to_string(),
+            (None, false) => format!("{} [{}]", p.name, unit),
+            (None, true) => p.name.clone(),
+        };
+        let _ = writeln!(out, "# HELP {} {}", metric, help);
+        let _ = writeln!(out, "# TYPE {} {}", metric, if counter { "counter" } else { "gauge" });
+        let _ = writeln!(out, "{}{{{}}} {}", metric, labels, value);
+    }
+
+    //daemon statistics
+    let counters = [
+        ("hard_poll_ok_total", "Successful inverter polls", &stats.poll_ok),
+        ("hard_poll_errors_total", "Failed inverter polls", &stats.poll_errors),
+        ("hard_reconnects_total", "Reconnections to the inverter", &stats.reconnects),
+    ];
+    for (metric, help, value) in counters {
+        let _ = writeln!(out, "# HELP {} {}", metric, help);
+        let _ = writeln!(out, "# TYPE {} counter", metric);
+        let _ = writeln!(out, "{}{{{}}} {}", metric, labels, value.load(Ordering::Relaxed));
+    }
+    let _ = writeln!(out, "# HELP hard_inverter_query_time_milliseconds Duration of the last inverter poll");
+    let _ = writeln!(out, "# TYPE hard_inverter_query_time_milliseconds gauge");
+    let _ = writeln!(
+        out,
+        "hard_inverter_query_time_milliseconds{{{}}} {}",
+        labels,
+        stats.query_time_ms.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(out, "# HELP hard_register_errors_total Failed register reads");
+    let _ = writeln!(out, "# TYPE hard_register_errors_total counter");
+    if let Ok(errors) = stats.register_errors.lock() {
+        let sorted: BTreeMap<_, _> = errors.iter().collect();
+        for (register, count) in sorted {
+            let _ = writeln!(
+                out,
+                "hard_register_errors_total{{{},register=\"{}\"}} {}",
+                labels,
+                escape_label(register),
+                count
+            );
+        }
+    }
+
+    out
+}
+
+async fn handle(
+    req: Request<Body>,
+    state: Arc<Mutex<MetricsState>>,
+    stats: Arc<Sun2000Stats>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        let mut not_found = Response::new(Body::from("not found\n"));
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(not_found);
+    }
+
+    let body = match state.lock() {
+        Ok(state) => render(&state, &stats),
+        Err(_) => String::new(),
+    };
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .unwrap_or_default())
+}
+
+impl PrometheusSink {
+    pub fn new(name: String, listen: SocketAddr, stats: Arc<Sun2000Stats>) -> Self {
+        Self {
+            name,
+            listen,
+            stats,
+            state: Arc::new(Mutex::new(MetricsState::default())),
+            server: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for PrometheusSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        let service_state = self.state.clone();
+        let service_stats = self.stats.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let state = service_state.clone();
+            let stats = service_stats.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone(), stats.clone())))
+            }
+        });
+
+        let server = Server::try_bind(&self.listen)?.serve(make_service);
+        info!("<i>{}</>: listening on <u>http://{}/metrics</>", self.name, self.listen);
+        let (tx_shutdown, rx_shutdown) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async move {
+            let _ = rx_shutdown.await;
+        });
+        let server_name = self.name.clone();
+        let server_future = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("<i>{}</>: server error: <b>{}</>", server_name, e);
+            }
+        });
+        self.server = Some((tx_shutdown, server_future));
+        Ok(())
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        if let Ok(mut state) = self.state.lock() {
+            state.device = batch.device.clone();
+            for p in &batch.parameters {
+                state.params.insert(p.name.clone(), p.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        if let Some((tx_shutdown, mut server_future)) = self.server.take() {
+            let _ = tx_shutdown.send(());
+            //the graceful shutdown is waiting for the open connections
+            if timeout(Duration::from_secs(2), &mut server_future).await.is_err() {
+                debug!("{}: server not finished in time", self.name);
+                server_future.abort();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::BatchMetadata;
+    use crate::sun2000::ParamKind;
+    use std::net::TcpListener;
+
+    #[tokio::test]
+    async fn metrics() {
+        let listen = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stats = Arc::new(Sun2000Stats::default());
+        stats.poll_ok.store(42, Ordering::Relaxed);
+        stats.register_errors.lock().unwrap().insert("alarm_1".into(), 3);
+        let mut sink = PrometheusSink::new("prometheus".into(), listen, stats);
+        sink.start().await.unwrap();
+
+        let device = DeviceInfo {
+            name: "sun2000".into(),
+            serial_number: Some("HV2012345678".into()),
+            ..Default::default()
+        };
+        let batch = ReadingBatch {
+            device: Arc::new(device),
+            timestamp: 0,
+            parameters: vec![
+                Parameter::new("active_power", ParamKind::NumberI32(Some(2500)), 0, None, Some("W"), 1, 32080, 2, false, true),
+                Parameter::new("internal_temperature", ParamKind::NumberI16(Some(456)), 0, None, Some("°C"), 10, 32087, 1, false, true),
+                Parameter::new("accumulated_yield_energy", ParamKind::NumberU32(Some(123456)), 0, None, Some("kWh"), 100, 32106, 2, false, true),
+                Parameter::new("daily_yield_energy", ParamKind::NumberU32(Some(1234)), 0, None, Some("kWh"), 100, 32114, 2, false, true),
+                Parameter::new("model_name", ParamKind::Text(Some("SUN2000".into())), 0, None, None, 1, 30000, 15, true, false),
+            ],
+            metadata: BatchMetadata::default(),
+            events: vec![],
+        };
+        sink.write(&batch).await.unwrap();
+
+        let response = reqwest::get(format!("http://{}/metrics", listen)).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/plain; version=0.0.4");
+        let body = response.text().await.unwrap();
+        let labels = r#"{inverter="sun2000",serial_number="HV2012345678"}"#;
+        for line in [
+            "# TYPE sun2000_active_power_watts gauge".to_string(),
+            format!("sun2000_active_power_watts{} 2500", labels),
+            "# TYPE sun2000_internal_temperature_celsius gauge".into(),
+            format!("sun2000_internal_temperature_celsius{} 45.6", labels),
+            "# TYPE sun2000_accumulated_yield_energy_kilowatt_hours_total counter".into(),
+            format!("sun2000_accumulated_yield_energy_kilowatt_hours_total{} 1234.56", labels),
+            //reset at midnight
+            "# TYPE sun2000_daily_yield_energy_kilowatt_hours gauge".into(),
+            format!("sun2000_daily_yield_energy_kilowatt_hours{} 12.34", labels),
+            "# TYPE hard_poll_ok_total counter".into(),
+            format!("hard_poll_ok_total{} 42", labels),
+            r#"hard_register_errors_total{inverter="sun2000",serial_number="HV2012345678",register="alarm_1"} 3"#.into(),
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} missing in:\n{}", line, body);
+        }
+        assert!(!body.contains("model_name"));
+
+        let response = reqwest::get(format!("http://{}/", listen)).await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        sink.stop().await.unwrap();
+        assert!(reqwest::get(format!("http://{}/metrics", listen)).await.is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::sink::{ReadingBatch, Result, Sink};
+use crate::sun2000::Parameter;
+use async_trait::async_trait;
+use chrono::{DateTime, Local, LocalResult, TimeZone};
+use reqwest::{Client, StatusCode};
+use simplelog::*;
+
+pub const PVOUTPUT_BATCH_SIZE: usize = 30; //max statuses in a single addbatchstatus request
+pub const PVOUTPUT_MAX_PENDING: usize = 14 * 24 * 12; //statuses older than 14 days are rejected by the API anyway
+pub const PVOUTPUT_RETRY_MIN_SECS: u64 = 30;
+pub const PVOUTPUT_RETRY_MAX_SECS: u64 = 900;
+pub const PVOUTPUT_BACKLOG_DELAY_SECS: u64 = 60; //delay between requests when sending the backlog (60 requests/hour limit)
+
+#[derive(Clone)]
+pub struct PvoutputSettings {
+    pub base_url: String,
+    pub api_key: String,
+    pub system_id: String,
+    pub interval: Duration,
+}
+
+/// Running averages of a single status interval
+#[derive(Default)]
+struct Accumulator {
+    bucket: u128,
+    last_time: u128,
+    energy_generation: Option<f64>,
+    power_generation: (f64, u32),
+    power_consumption: (f64, u32),
+    temperature: (f64, u32),
+    voltage: (f64, u32),
+}
+
+/// Single addstatus entry
+struct Status {
+    time: DateTime<Local>,
+    energy_generation: Option<f64>,
+    power_generation: Option<f64>,
+    power_consumption: Option<f64>,
+    temperature: Option<f64>,
+    voltage: Option<f64>,
+}
+
+fn average(sum_count: (f64, u32)) -> Option<f64> {
+    match sum_count {
+        (_, 0) => None,
+        (sum, count) => Some(sum / count as f64),
+    }
+}
+
+fn add(sum_count: &mut (f64, u32), value: Option<f64>) {
+    if let Some(v) = value {
+        sum_count.0 += v;
+        sum_count.1 += 1;
+    }
+}
+
+fn fmt_value(value: Option<f64>, precision: usize) -> String {
+    match value {
+        Some(v) => format!("{:.*}", precision, v),
+        None => String::new(),
+    }
+}
+
+impl Accumulator {
+    fn status(&self) -> Option<Status> {
+        let time = match Local.timestamp_millis_opt(self.last_time as i64) {
+            LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt,
+            LocalResult::None => return None,
+        };
+        Some(Status {
+            time,
+            energy_generation: self.energy_generation,
+            power_generation: average(self.power_generation),
+            power_consumption: average(self.power_consumption),
+            temperature: average(self.temperature),
+            voltage: average(self.voltage),
+        })
+    }
+}
+
+impl Status {
+    /// Values in the addbatchstatus order: d,t,v1,v2,v3,v4,v5,v6
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("d", self.time.format("%Y%m%d").to_string()),
+            ("t", self.time.format("%H:%M").to_string()),
+            ("v1", fmt_value(self.energy_generation, 0)),
+            ("v2", fmt_value(self.power_generation, 0)),
+            ("v3", String::new()),
+            ("v4", fmt_value(self.power_consumption, 0)),
+            ("v5", fmt_value(self.temperature, 1)),
+            ("v6", fmt_value(self.voltage, 1)),
+        ]
+    }
+}
+
+pub struct PvoutputSink {
+    pub name: String,
+    pub settings: PvoutputSettings,
+    client: Client,
+    current: Option<Accumulator>,
+    pending: VecDeque<Status>,
+    next_attempt: Option<Instant>,
+    retry_delay: u64,
+}
+
+impl PvoutputSink {
+    pub fn new(name: String, settings: PvoutputSettings) -> Self {
+        Self {
+            name,
+            settings,
+            client: Client::new(),
+            current: None,
+            pending: VecDeque::new(),
+            next_attempt: None,
+            retry_delay: PVOUTPUT_RETRY_MIN_SECS,
+        }
+    }
+
+    fn accumulate(&mut self, batch: &ReadingBatch) {
+        let value = |name: &str| -> Option<f64> {
+            batch
+                .parameters
+                .iter()
+                .find(|p: &&Parameter| p.name == name)
+                .and_then(|p| p.get_float_value())
+        };
+        let interval = self.settings.interval.as_millis().max(1);
+        let bucket = batch.timestamp - batch.timestamp % interval;
+
+        //interval completed
+        if self.current.as_ref().is_some_and(|acc| acc.bucket != bucket) {
+            if let Some(status) = self.current.take().and_then(|acc| acc.status()) {
+                self.pending.push_back(status);
+                if self.pending.len() > PVOUTPUT_MAX_PENDING {
+                    self.pending.pop_front();
+                }
+            }
+        }
+        let acc = self.current.get_or_insert_with(|| Accumulator {
+            bucket,
+            ..Default::default()
+        });
+
+        acc.last_time = batch.timestamp;
+        if let Some(energy) = value("daily_yield_energy") {
+            acc.energy_generation = Some(energy * 1000.0);
+        }
+        let generation = value("active_power");
+        add(&mut acc.power_generation, generation);
+        add(&mut acc.temperature, value("internal_temperature"));
+        add(&mut acc.voltage, value("phase_A_voltage"));
+        //the meter is reporting the power exported to the grid as positive
+        if value("power_meter_status") == Some(1.0) {
+            if let (Some(generation), Some(grid)) = (generation, value("power_meter_active_power")) {
+                add(&mut acc.power_consumption, Some(generation - grid));
+            }
+        }
+    }
+
+    async fn upload(&mut self) -> Result<()> {
+        let count = self.pending.len().min(PVOUTPUT_BATCH_SIZE);
+        let request = if count == 1 {
+            let url = format!("{}/service/r2/addstatus.jsp", self.settings.base_url);
+            let form: Vec<(&str, String)> =
+                self.pending[0].fields().into_iter().filter(|(_, v)| !v.is_empty()).collect();
+            self.client.post(url).form(&form)
+        } else {
+            let url = format!("{}/service/r2/addbatchstatus.jsp", self.settings.base_url);
+            let data: Vec<String> = self
+                .pending
+                .iter()
+                .take(count)
+                .map(|s| s.fields().into_iter().map(|(_, v)| v).collect::<Vec<_>>().join(","))
+                .collect();
+            self.client.post(url).form(&[("data", data.join(";"))])
+        };
+
+        let response = request
+            .header("X-Pvoutput-Apikey", &self.settings.api_key)
+            .header("X-Pvoutput-SystemId", &self.settings.system_id)
+            .header("X-Rate-Limit", "1")
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await?;
+
+        let header = |name: &str| -> Option<u64> {
+            response.headers().get(name).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok())
+        };
+        let remaining = header("X-Rate-Limit-Remaining");
+        let reset = header("X-Rate-Limit-Reset");
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+
+        //wait for the limit reset instead of burning the remaining requests on retries
+        let rate_limited = status == StatusCode::FORBIDDEN && body.contains("Exceeded");
+        if rate_limited || remaining == Some(0) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let wait = reset.map(|r| r.saturating_sub(now)).unwrap_or(PVOUTPUT_RETRY_MAX_SECS);
+            warn!("<i>{}</>: rate limit reached, waiting <b>{}</> secs", self.name, wait);
+            self.next_attempt = Some(Instant::now() + Duration::from_secs(wait));
+        }
+        if !status.is_success() {
+            return Err(format!("HTTP {}: {}", status, body.trim()).into());
+        }
+
+        debug!("{}: uploaded {} statuses", self.name, count);
+        self.pending.drain(..count);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for PvoutputSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    //the averages are computed from every sample
+    fn deadband(&self) -> bool {
+        false
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        if !batch.metadata.initial_read {
+            self.accumulate(batch);
+        }
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> Result<()> {
+        if self.pending.is_empty() || self.next_attempt.is_some_and(|t| Instant::now() < t) {
+            return Ok(());
+        }
+        self.next_attempt = None;
+        match self.upload().await {
+            Ok(()) => {
+                self.retry_delay = PVOUTPUT_RETRY_MIN_SECS;
+                if !self.pending.is_empty() && self.next_attempt.is_none() {
+                    self.next_attempt = Some(Instant::now() + Duration::from_secs(PVOUTPUT_BACKLOG_DELAY_SECS));
+                }
+                Ok(())
+            }
+            Err(e) => {
+                //exponential backoff, unless we are already waiting for the rate limit reset
+                if self.next_attempt.is_none() {
+                    self.next_attempt = Some(Instant::now() + Duration::from_secs(self.retry_delay));
+                    self.retry_delay = (self.retry_delay * 2).min(PVOUTPUT_RETRY_MAX_SECS);
+                }
+                Err(format!("upload error ({} pending): {}", self.pending.len(), e).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::BatchMetadata;
+    use crate::sun2000::{DeviceInfo, ParamKind};
+    use crate::testutil::{http_server, http_server_with_headers, next};
+    use std::sync::Arc;
+
+    const START: u128 = 1_700_000_100_000; //start of a 5 minute interval
+
+    fn sink(address: std::net::SocketAddr) -> PvoutputSink {
+        let settings = PvoutputSettings {
+            base_url: format!("http://{}", address),
+            api_key: "key".into(),
+            system_id: "12345".into(),
+            interval: Duration::from_secs(300),
+        };
+        PvoutputSink::new("pvoutput".into(), settings)
+    }
+
+    fn batch(time: u128, initial_read: bool, values: &[(&'static str, i32, u16)]) -> ReadingBatch {
+        ReadingBatch {
+            device: Arc::new(DeviceInfo::default()),
+            timestamp: time,
+            parameters: values
+                .iter()
+                .map(|(name, value, gain)| {
+                    Parameter::new(name, ParamKind::NumberI32(Some(*value)), time, None, None, *gain, 32080, 2, false, true)
+                })
+                .collect(),
+            metadata: BatchMetadata {
+                initial_read,
+                query_time_ms: 100,
+            },
+            events: vec![],
+        }
+    }
+
+    fn power(time: u128, power: i32) -> ReadingBatch {
+        batch(time, false, &[("active_power", power, 1)])
+    }
+
+    fn date_time(time: u128) -> (String, String) {
+        let time = Local.timestamp_millis_opt(time as i64).unwrap();
+        (time.format("%Y%m%d").to_string(), time.format("%H:%M").to_string())
+    }
+
+    /// Form body with the separators of the batch data decoded
+    fn decoded(body: &str) -> String {
+        body.replace("%2C", ",").replace("%3B", ";").replace("%3A", ":")
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let (address, mut requests) = http_server(200, "OK 200: Added Status");
+        let mut sink = sink(address);
+        sink.write(&batch(START, true, &[("active_power", 9000, 1)])).await.unwrap();
+        for (time, power, temperature, voltage, energy, meter) in
+            [(60_000, 4000, 450, 2300, 1234, 1000), (120_000, 5000, 460, 2310, 1240, -500)]
+        {
+            let values = [
+                ("active_power", power, 1),
+                ("internal_temperature", temperature, 10),
+                ("phase_A_voltage", voltage, 10),
+                ("daily_yield_energy", energy, 100),
+                ("power_meter_status", 1, 1),
+                ("power_meter_active_power", meter, 1),
+            ];
+            sink.write(&batch(START + time, false, &values)).await.unwrap();
+        }
+        //the interval is not complete yet
+        sink.tick().await.unwrap();
+        assert!(requests.try_recv().is_err());
+
+        sink.write(&power(START + 300_000, 1000)).await.unwrap();
+        sink.tick().await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.uri, "/service/r2/addstatus.jsp");
+        assert_eq!(request.header("X-Pvoutput-Apikey"), Some("key"));
+        assert_eq!(request.header("X-Pvoutput-SystemId"), Some("12345"));
+        assert_eq!(request.header("X-Rate-Limit"), Some("1"));
+        let (d, t) = date_time(START + 120_000);
+        assert_eq!(
+            decoded(&request.body),
+            format!("d={}&t={}&v1=12400&v2=4500&v4=4250&v5=45.5&v6=230.5", d, t)
+        );
+        assert!(sink.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn batch_status() {
+        let (address, mut requests) = http_server(200, "1;2;3");
+        let mut sink = sink(address);
+        for interval in 0..4 {
+            sink.write(&power(START + interval * 300_000, 1000 + interval as i32)).await.unwrap();
+        }
+        sink.tick().await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!(request.uri, "/service/r2/addbatchstatus.jsp");
+        let data: Vec<String> = (0..3)
+            .map(|interval| {
+                let (d, t) = date_time(START + interval * 300_000);
+                format!("{},{},,{},,,,", d, t, 1000 + interval)
+            })
+            .collect();
+        assert_eq!(decoded(&request.body), format!("data={}", data.join(";")));
+        assert!(sink.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn retry() {
+        let (address, mut requests) = http_server(400, "Bad request 400: Invalid system id");
+        let mut sink = sink(address);
+        sink.write(&power(START, 1000)).await.unwrap();
+        sink.write(&power(START + 300_000, 1000)).await.unwrap();
+        let e = sink.tick().await.unwrap_err().to_string();
+        assert!(e.contains("HTTP 400 Bad Request: Bad request 400: Invalid system id"), "{}", e);
+        next(&mut requests).await;
+        assert_eq!(sink.pending.len(), 1);
+        assert_eq!(sink.retry_delay, 2 * PVOUTPUT_RETRY_MIN_SECS);
+        //waiting for the retry
+        sink.tick().await.unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let headers = &[("X-Rate-Limit-Remaining", "0")];
+        let (address, mut requests) =
+            http_server_with_headers(403, headers, "Forbidden 403: Exceeded 60 requests per hour");
+        let mut sink = sink(address);
+        sink.write(&power(START, 1000)).await.unwrap();
+        sink.write(&power(START + 300_000, 1000)).await.unwrap();
+        assert!(sink.tick().await.is_err());
+        next(&mut requests).await;
+        //waiting for the limit reset, not for the retry
+        let wait = sink.next_attempt.unwrap() - Instant::now();
+        assert!(wait > Duration::from_secs(PVOUTPUT_RETRY_MAX_SECS - 10), "{:?}", wait);
+        assert_eq!(sink.retry_delay, PVOUTPUT_RETRY_MIN_SECS);
+        assert_eq!(sink.pending.len(), 1);
+    }
+}
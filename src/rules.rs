@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use humantime::format_duration;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde_json::{json, Value};
+use simplelog::*;
+
+use crate::command;
+use crate::sun2000::{DeviceInfo, Parameter};
+use crate::webhook::WEBHOOK_TIMEOUT_SECS;
+
+pub const RULE_DEFAULT_MESSAGE: &str = "%param% = %value% %unit% (%condition%)";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Operator {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Operator::Greater => value > threshold,
+            Operator::GreaterOrEqual => value >= threshold,
+            Operator::Less => value < threshold,
+            Operator::LessOrEqual => value <= threshold,
+            Operator::Equal => value == threshold,
+            Operator::NotEqual => value != threshold,
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+        })
+    }
+}
+
+/// `<parameter> <operator> <number> [for <duration>]`, the parameter may contain `*` wildcards
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub pattern: String,
+    pub operator: Operator,
+    pub threshold: f64,
+    /// how long the condition has to hold before the rule fires
+    pub duration: Duration,
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected <parameter> <operator> <number> [for <duration>], got {:?}", s);
+        let (expression, duration) = match s.split_once(" for ") {
+            Some((expression, duration)) => (
+                expression,
+                humantime::parse_duration(duration.trim()).map_err(|e| format!("invalid duration {:?}: {}", duration.trim(), e))?,
+            ),
+            None => (s, Duration::ZERO),
+        };
+        let position = expression.find(['<', '>', '=', '!']).ok_or_else(invalid)?;
+        let pattern = expression[..position].trim();
+        let rest = &expression[position..];
+        let (operator, threshold) = [
+            (">=", Operator::GreaterOrEqual),
+            ("<=", Operator::LessOrEqual),
+            ("==", Operator::Equal),
+            ("!=", Operator::NotEqual),
+            (">", Operator::Greater),
+            ("<", Operator::Less),
+        ]
+        .iter()
+        .find_map(|(token, operator)| rest.strip_prefix(token).map(|threshold| (*operator, threshold)))
+        .ok_or_else(invalid)?;
+        if pattern.is_empty() || !pattern.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '*') {
+            return Err(invalid());
+        }
+        Ok(Condition {
+            pattern: pattern.to_string(),
+            operator,
+            threshold: threshold.trim().parse().map_err(|_| invalid())?,
+            duration,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.pattern, self.operator, self.threshold)?;
+        if !self.duration.is_zero() {
+            write!(f, " for {}", format_duration(self.duration))?;
+        }
+        Ok(())
+    }
+}
+
+/// `*` is matching any (also empty) part of the name
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => match name.strip_prefix(prefix) {
+            None => false,
+            Some(name) => (0..=name.len()).any(|i| name.is_char_boundary(i) && matches(rest, &name[i..])),
+        },
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuleAction {
+    Log,
+    Command,
+    Webhook,
+    /// published by the mqtt sink
+    Mqtt,
+    /// written by the influxdb sink
+    Influxdb,
+    /// sent by the notifier to the channel
+    Email,
+    Ntfy,
+    Telegram,
+    Gotify,
+}
+
+impl FromStr for RuleAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "log" => Ok(RuleAction::Log),
+            "command" => Ok(RuleAction::Command),
+            "webhook" => Ok(RuleAction::Webhook),
+            "mqtt" => Ok(RuleAction::Mqtt),
+            "influxdb" => Ok(RuleAction::Influxdb),
+            "email" => Ok(RuleAction::Email),
+            "ntfy" => Ok(RuleAction::Ntfy),
+            "telegram" => Ok(RuleAction::Telegram),
+            "gotify" => Ok(RuleAction::Gotify),
+            other => Err(format!("unknown rule action: {:?}", other)),
+        }
+    }
+}
+
+/// `[rule.<name>]` of the config
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+    /// the firing rule is resolved only when the value is back by this amount
+    pub hysteresis: f64,
+    pub actions: Vec<RuleAction>,
+    pub command: Option<String>,
+    pub command_timeout: Duration,
+    pub webhook: Option<String>,
+    pub message: Option<String>,
+}
+
+impl Rule {
+    /// Whether the firing rule keeps firing, the threshold is moved back by the hysteresis
+    fn still_holds(&self, value: f64) -> bool {
+        let c = &self.condition;
+        let threshold = match c.operator {
+            Operator::Greater | Operator::GreaterOrEqual => c.threshold - self.hysteresis,
+            Operator::Less | Operator::LessOrEqual => c.threshold + self.hysteresis,
+            Operator::Equal | Operator::NotEqual => c.threshold,
+        };
+        c.operator.holds(value, threshold)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuleState {
+    Firing,
+    Resolved,
+}
+
+impl fmt::Display for RuleState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RuleState::Firing => "firing",
+            RuleState::Resolved => "resolved",
+        })
+    }
+}
+
+/// Rule which started firing or was resolved, passed to the sinks along with the poll results
+#[derive(Clone, Debug)]
+pub struct RuleEvent {
+    pub rule: String,
+    pub state: RuleState,
+    pub param: String,
+    pub value: f64,
+    pub unit: &'static str,
+    pub condition: String,
+    pub message: String,
+    /// milliseconds since the epoch
+    pub time: u128,
+    pub actions: Vec<RuleAction>,
+}
+
+impl RuleEvent {
+    pub fn to_json(&self, device: &DeviceInfo) -> Value {
+        json!({
+            "rule": self.rule,
+            "state": self.state.to_string(),
+            "param": self.param,
+            "value": self.value,
+            "unit": self.unit,
+            "condition": self.condition,
+            "message": self.message,
+            "time": self.time as u64,
+            "inverter": device.name,
+            "serial_number": device.serial_number,
+        })
+    }
+
+    fn placeholders(&self, device: &DeviceInfo) -> Vec<(&'static str, String)> {
+        vec![
+            ("%rule%", self.rule.clone()),
+            ("%state%", self.state.to_string()),
+            ("%param%", self.param.clone()),
+            ("%value%", self.value.to_string()),
+            ("%unit%", self.unit.to_string()),
+            ("%condition%", self.condition.clone()),
+            ("%inverter%", device.name.clone()),
+            ("%serial_number%", device.serial_number.clone().unwrap_or_default()),
+        ]
+    }
+}
+
+/// Replaces the `%placeholders%` in the template
+pub fn render(template: &str, placeholders: &[(&str, String)]) -> String {
+    placeholders
+        .iter()
+        .fold(template.to_string(), |out, (placeholder, value)| out.replace(placeholder, value))
+}
+
+enum AlertState {
+    /// the condition holds since, but not for long enough yet
+    Pending(Instant),
+    Firing,
+}
+
+/// States of the rules for every matching parameter
+pub struct RuleEngine {
+    name: String,
+    states: HashMap<(String, String), AlertState>,
+    client: Client,
+}
+
+impl RuleEngine {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            states: HashMap::new(),
+            client: Client::builder()
+                .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Updates the states with the polled values, runs the immediate actions of the state
+    /// changes and returns their events for the sinks
+    pub fn evaluate(&mut self, rules: &[Rule], device: &DeviceInfo, parameters: &[Parameter]) -> Vec<RuleEvent> {
+        //forget the rules removed by a config reload
+        self.states.retain(|(rule, _), _| rules.iter().any(|r| &r.name == rule));
+        let mut events = vec![];
+        for rule in rules {
+            for p in parameters.iter().filter(|p| matches(&rule.condition.pattern, &p.name)) {
+                let value = match p.get_float_value() {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let key = (rule.name.clone(), p.name.clone());
+                let state = match self.states.get(&key) {
+                    Some(AlertState::Firing) if rule.still_holds(value) => continue,
+                    Some(AlertState::Firing) => {
+                        self.states.remove(&key);
+                        RuleState::Resolved
+                    }
+                    _ if !rule.condition.operator.holds(value, rule.condition.threshold) => {
+                        self.states.remove(&key);
+                        continue;
+                    }
+                    Some(AlertState::Pending(since)) if since.elapsed() < rule.condition.duration => continue,
+                    None if !rule.condition.duration.is_zero() => {
+                        self.states.insert(key, AlertState::Pending(Instant::now()));
+                        continue;
+                    }
+                    _ => {
+                        self.states.insert(key, AlertState::Firing);
+                        RuleState::Firing
+                    }
+                };
+                let mut event = RuleEvent {
+                    rule: rule.name.clone(),
+                    state,
+                    param: p.name.clone(),
+                    value,
+                    unit: p.unit.unwrap_or_default(),
+                    condition: rule.condition.to_string(),
+                    message: String::new(),
+                    time: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .expect("Time went backwards")
+                        .as_millis(),
+                    actions: rule.actions.clone(),
+                };
+                event.message = render(
+                    rule.message.as_deref().unwrap_or(RULE_DEFAULT_MESSAGE),
+                    &event.placeholders(device),
+                )
+                .trim()
+                .to_string();
+                self.run_actions(rule, device, &event);
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    fn run_actions(&self, rule: &Rule, device: &DeviceInfo, event: &RuleEvent) {
+        for action in &rule.actions {
+            match action {
+                RuleAction::Log => match event.state {
+                    RuleState::Firing => warn!("<i>{}</>: 🚨 rule <b>{}</> is firing: {}", self.name, rule.name, event.message),
+                    RuleState::Resolved => info!("<i>{}</>: ✅ rule <b>{}</> is resolved: {}", self.name, rule.name, event.message),
+                },
+                RuleAction::Command => {
+                    if let Some(command) = &rule.command {
+                        let placeholders = event.placeholders(device);
+                        let placeholders: Vec<(&str, &str)> = placeholders.iter().map(|(p, v)| (*p, v.as_str())).collect();
+                        let args = command::expand(command, &placeholders);
+                        tokio::spawn(command::run(self.name.clone(), "rule command", args, rule.command_timeout));
+                    }
+                }
+                RuleAction::Webhook => {
+                    if let Some(url) = &rule.webhook {
+                        let request = self
+                            .client
+                            .post(url)
+                            .header(CONTENT_TYPE, "application/json")
+                            .body(event.to_json(device).to_string());
+                        let name = self.name.clone();
+                        let rule = rule.name.clone();
+                        tokio::spawn(async move {
+                            match request.send().await {
+                                Ok(response) if response.status().is_success() => {}
+                                Ok(response) => error!("<i>{}</>: rule <b>{}</> webhook error: HTTP <b>{}</>", name, rule, response.status()),
+                                Err(e) => error!("<i>{}</>: rule <b>{}</> webhook error: <b>{}</>", name, rule, e),
+                            }
+                        });
+                    }
+                }
+                //these are done by the sinks and the notifier
+                RuleAction::Mqtt
+                | RuleAction::Influxdb
+                | RuleAction::Email
+                | RuleAction::Ntfy
+                | RuleAction::Telegram
+                | RuleAction::Gotify => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sun2000::ParamKind;
+
+    fn param(name: &'static str, value: i32) -> Parameter {
+        Parameter::new(name, ParamKind::NumberI32(Some(value)), 0, None, Some("W"), 1, 32080, 2, false, true)
+    }
+
+    fn rule(condition: &str, hysteresis: f64) -> Rule {
+        Rule {
+            name: "test".into(),
+            condition: condition.parse().unwrap(),
+            hysteresis,
+            actions: vec![],
+            command: None,
+            command_timeout: Duration::from_secs(10),
+            webhook: None,
+            message: None,
+        }
+    }
+
+    fn states(engine: &mut RuleEngine, rules: &[Rule], name: &'static str, value: i32) -> Vec<RuleState> {
+        engine
+            .evaluate(rules, &DeviceInfo::default(), &[param(name, value)])
+            .iter()
+            .map(|e| e.state)
+            .collect()
+    }
+
+    #[test]
+    fn condition_from_str() {
+        let c: Condition = "active_power >= 5000 for 10m".parse().unwrap();
+        assert_eq!(c.pattern, "active_power");
+        assert_eq!(c.operator, Operator::GreaterOrEqual);
+        assert_eq!(c.threshold, 5000.0);
+        assert_eq!(c.duration, Duration::from_secs(600));
+
+        let c: Condition = "pv_*_voltage<-1.5".parse().unwrap();
+        assert_eq!(c.pattern, "pv_*_voltage");
+        assert_eq!(c.operator, Operator::Less);
+        assert_eq!(c.threshold, -1.5);
+        assert_eq!(c.duration, Duration::ZERO);
+
+        assert_eq!("a != 0".parse::<Condition>().unwrap().operator, Operator::NotEqual);
+        assert_eq!("a == 0".parse::<Condition>().unwrap().operator, Operator::Equal);
+        assert_eq!("a > 0".parse::<Condition>().unwrap().operator, Operator::Greater);
+        assert_eq!("a <= 0".parse::<Condition>().unwrap().operator, Operator::LessOrEqual);
+    }
+
+    #[test]
+    fn condition_from_str_invalid() {
+        for s in ["", "active_power", "> 5", "active_power > ", "active_power > x", "active-power > 5", "a = 5", "a > 5 for 3 parsecs"] {
+            assert!(s.parse::<Condition>().is_err(), "{:?} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn condition_display() {
+        for s in ["active_power >= 5000 for 10m", "pv_*_voltage < -1.5", "fault_code != 0"] {
+            assert_eq!(s.parse::<Condition>().unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("fault_code", "fault_code"));
+        assert!(!matches("fault_code", "fault_code_2"));
+        assert!(matches("pv_*_voltage", "pv_01_voltage"));
+        assert!(!matches("pv_*_voltage", "pv_01_current"));
+        assert!(matches("alarm_*", "alarm_"));
+        assert!(matches("*_temperature", "internal_temperature"));
+        assert!(matches("*", "anything"));
+        assert!(matches("a*b*c", "a_b_b_c"));
+        assert!(!matches("a*b*c", "a_c_b"));
+    }
+
+    #[test]
+    fn firing_and_resolved() {
+        let rules = [rule("input_power > 1000", 0.0)];
+        let mut engine = RuleEngine::new("test".into());
+        assert_eq!(states(&mut engine, &rules, "input_power", 500), vec![]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 1200), vec![RuleState::Firing]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 1300), vec![]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 1000), vec![RuleState::Resolved]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 900), vec![]);
+        //other parameters are ignored
+        assert_eq!(states(&mut engine, &rules, "active_power", 5000), vec![]);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let rules = [rule("input_power > 1000", 100.0)];
+        let mut engine = RuleEngine::new("test".into());
+        assert_eq!(states(&mut engine, &rules, "input_power", 1200), vec![RuleState::Firing]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 950), vec![]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 901), vec![]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 900), vec![RuleState::Resolved]);
+
+        let rules = [rule("input_power < 100", 50.0)];
+        let mut engine = RuleEngine::new("test".into());
+        assert_eq!(states(&mut engine, &rules, "input_power", 0), vec![RuleState::Firing]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 149), vec![]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 150), vec![RuleState::Resolved]);
+    }
+
+    #[test]
+    fn duration() {
+        let rules = [rule("input_power > 1000 for 50ms", 0.0)];
+        let mut engine = RuleEngine::new("test".into());
+        assert_eq!(states(&mut engine, &rules, "input_power", 1200), vec![]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 1200), vec![]);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(states(&mut engine, &rules, "input_power", 1200), vec![RuleState::Firing]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 500), vec![RuleState::Resolved]);
+
+        //the pending condition starts again when it stops holding
+        assert_eq!(states(&mut engine, &rules, "input_power", 1200), vec![]);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(states(&mut engine, &rules, "input_power", 500), vec![]);
+        assert_eq!(states(&mut engine, &rules, "input_power", 1200), vec![]);
+    }
+
+    #[test]
+    fn event_message() {
+        let mut rule = rule("pv_*_voltage < 10", 0.0);
+        rule.message = Some("%rule%: %param% is %value% %unit% on %inverter%".into());
+        let device = DeviceInfo {
+            name: "sun2000".into(),
+            ..Default::default()
+        };
+        let events = RuleEngine::new("test".into()).evaluate(&[rule], &device, &[param("pv_02_voltage", 5)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].param, "pv_02_voltage");
+        assert_eq!(events[0].condition, "pv_*_voltage < 10");
+        assert_eq!(events[0].message, "test: pv_02_voltage is 5 W on sun2000");
+    }
+}
@@ -0,0 +1,412 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::rules::RuleEvent;
+use crate::sun2000::{DeviceInfo, Parameter};
+use async_channel::{Receiver, Sender, TrySendError};
+use async_trait::async_trait;
+use simplelog::*;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{timeout, Instant};
+use tokio_compat_02::FutureExt;
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const SINK_CHANNEL_SIZE: usize = 100; //max batches queued for a single sink
+pub const SINK_TICK_MS: u64 = 100; //max delay between Sink::tick() calls
+pub const SINK_START_RETRY_MAX_SECS: u64 = 60; //max delay between Sink::start() retries
+
+/// Additional information about the poll which produced the batch
+#[derive(Clone, Debug, Default)]
+pub struct BatchMetadata {
+    /// parameters obtained right after connecting (device identity, limits, etc)
+    pub initial_read: bool,
+    /// duration of the whole modbus query
+    pub query_time_ms: u64,
+}
+
+/// Result of a single poll, passed to all sinks
+#[derive(Clone)]
+pub struct ReadingBatch {
+    pub device: Arc<DeviceInfo>,
+    /// milliseconds since the epoch, when the poll was finished
+    pub timestamp: u128,
+    pub parameters: Vec<Parameter>,
+    pub metadata: BatchMetadata,
+    /// rules which changed their state with this poll
+    pub events: Vec<RuleEvent>,
+}
+
+/// Destination for the polled parameters.
+/// Every sink is running in its own task, so a slow sink is not affecting the others
+/// nor the modbus poll loop.
+#[async_trait]
+pub trait Sink: Send {
+    fn name(&self) -> &str;
+
+    /// Whether the sink is getting only the values passing the deadband filter;
+    /// the sinks aggregating the samples need all of them
+    fn deadband(&self) -> bool {
+        true
+    }
+
+    /// Called once in the sink task before receiving any batch
+    async fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()>;
+
+    /// Called periodically (also when there is nothing to write) for housekeeping,
+    /// like reconnecting or flushing buffered data
+    async fn tick(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once when the daemon is terminating
+    async fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct SinkQueue {
+    name: String,
+    deadband: bool,
+    tx: Sender<Arc<ReadingBatch>>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Fans out the poll results to all configured sinks.
+/// Sinks can be added and removed while the poller is running (config reload).
+#[derive(Default)]
+pub struct SinkDispatcher {
+    queues: Mutex<Vec<SinkQueue>>,
+}
+
+impl SinkDispatcher {
+    fn queues(&self) -> std::sync::MutexGuard<'_, Vec<SinkQueue>> {
+        self.queues.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Starts the sink task and registers its queue
+    pub fn spawn(
+        &self,
+        mut sink: Box<dyn Sink>,
+        worker_cancel_flag: Arc<AtomicBool>,
+    ) -> JoinHandle<Result<()>> {
+        let (tx, rx) = async_channel::bounded(SINK_CHANNEL_SIZE);
+        self.queues().push(SinkQueue {
+            name: sink.name().to_string(),
+            deadband: sink.deadband(),
+            tx,
+            dropped: Arc::new(AtomicU64::new(0)),
+        });
+        task::spawn(async move { run_sink(sink.as_mut(), rx, worker_cancel_flag).compat().await })
+    }
+
+    /// Unregisters the sink queue, the sink task is stopping after writing
+    /// the already queued batches
+    pub fn remove(&self, name: &str) {
+        self.queues().retain(|q| {
+            if q.name == name {
+                q.tx.close();
+            }
+            q.name != name
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues().is_empty()
+    }
+
+    /// Batches waiting in the most loaded sink queue
+    pub fn backlog(&self) -> usize {
+        self.queues().iter().map(|q| q.tx.len()).max().unwrap_or_default()
+    }
+
+    /// Passes the batch to all sinks, never waiting for them:
+    /// when a sink queue is full the batch is dropped for that sink.
+    /// The sinks using the deadband are getting the `filtered` parameters, when there are some.
+    pub fn dispatch(&self, thread_name: &str, batch: ReadingBatch, filtered: Option<Vec<Parameter>>) {
+        let filtered = filtered.map(|parameters| {
+            Arc::new(ReadingBatch {
+                parameters,
+                ..batch.clone()
+            })
+        });
+        let batch = Arc::new(batch);
+        for queue in self.queues().iter() {
+            let batch = match &filtered {
+                Some(filtered) if queue.deadband => filtered,
+                _ => &batch,
+            };
+            match queue.tx.try_send(batch.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    debug!("{}: {} queue full, dropping batch", thread_name, queue.name);
+                    queue.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    debug!("{}: {} queue closed, dropping batch", thread_name, queue.name);
+                    queue.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    pub fn log_stats(&self, thread_name: &str) {
+        for queue in self.queues().iter() {
+            info!(
+                "<i>{}</>: 📊 {} sink statistics: queued: <b>{}</>, dropped: <b>{}</>",
+                thread_name,
+                queue.name,
+                queue.tx.len(),
+                queue.dropped.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+async fn run_sink(
+    sink: &mut dyn Sink,
+    rx: Receiver<Arc<ReadingBatch>>,
+    worker_cancel_flag: Arc<AtomicBool>,
+) -> Result<()> {
+    info!("<i>{}</>: Starting task", sink.name());
+    //the queue stays registered, so keep trying (eg. the port may be freed or the database started)
+    let mut retry = Duration::from_secs(1);
+    while let Err(e) = sink.start().await {
+        error!("<i>{}</>: start error: <b>{}</>, retrying in {:?}", sink.name(), e, retry);
+        let failed = Instant::now();
+        while failed.elapsed() < retry {
+            if worker_cancel_flag.load(Ordering::SeqCst) || rx.is_closed() {
+                info!("<i>{}</>: task stopped", sink.name());
+                return Err(e);
+            }
+            tokio::time::sleep(Duration::from_millis(SINK_TICK_MS)).await;
+        }
+        retry = (retry * 2).min(Duration::from_secs(SINK_START_RETRY_MAX_SECS));
+    }
+
+    loop {
+        if worker_cancel_flag.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match timeout(Duration::from_millis(SINK_TICK_MS), rx.recv()).await {
+            Ok(Ok(batch)) => {
+                if let Err(e) = sink.write(&batch).await {
+                    error!("<i>{}</>: write error: <b>{}</>", sink.name(), e);
+                }
+            }
+            Ok(Err(_)) => break,
+            Err(_) => {}
+        }
+
+        if let Err(e) = sink.tick().await {
+            error!("<i>{}</>: error: <b>{}</>", sink.name(), e);
+        }
+    }
+
+    if let Err(e) = sink.stop().await {
+        error!("<i>{}</>: stop error: <b>{}</>", sink.name(), e);
+    }
+    info!("<i>{}</>: task stopped", sink.name());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sun2000::ParamKind;
+    use crate::testutil::next;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+    use tokio::sync::Notify;
+
+    /// Sink reporting the start attempts and the written parameter names
+    struct TestSink {
+        name: String,
+        deadband: bool,
+        /// failing start attempts left
+        start_errors: usize,
+        /// start is waiting for it, holding the batches in the queue
+        gate: Option<Arc<Notify>>,
+        starts: UnboundedSender<Instant>,
+        writes: UnboundedSender<Vec<String>>,
+    }
+
+    struct Reports {
+        starts: UnboundedReceiver<Instant>,
+        writes: UnboundedReceiver<Vec<String>>,
+    }
+
+    fn test_sink(name: &str, deadband: bool) -> (TestSink, Reports) {
+        let (tx_starts, starts) = unbounded_channel();
+        let (tx_writes, writes) = unbounded_channel();
+        let sink = TestSink {
+            name: name.into(),
+            deadband,
+            start_errors: 0,
+            gate: None,
+            starts: tx_starts,
+            writes: tx_writes,
+        };
+        (sink, Reports { starts, writes })
+    }
+
+    #[async_trait]
+    impl Sink for TestSink {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn deadband(&self) -> bool {
+            self.deadband
+        }
+
+        async fn start(&mut self) -> Result<()> {
+            let _ = self.starts.send(Instant::now());
+            if let Some(gate) = &self.gate {
+                gate.notified().await;
+            }
+            if self.start_errors > 0 {
+                self.start_errors -= 1;
+                return Err("port in use".into());
+            }
+            Ok(())
+        }
+
+        async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+            let _ = self.writes.send(batch.parameters.iter().map(|p| p.name.clone()).collect());
+            Ok(())
+        }
+    }
+
+    fn parameter(name: &'static str) -> Parameter {
+        Parameter::new(name, ParamKind::NumberI32(Some(1)), 0, None, Some("W"), 1, 32080, 2, false, true)
+    }
+
+    fn batch() -> ReadingBatch {
+        ReadingBatch {
+            device: Arc::new(DeviceInfo::default()),
+            timestamp: 0,
+            parameters: vec![parameter("active_power"), parameter("input_power")],
+            metadata: BatchMetadata::default(),
+            events: vec![],
+        }
+    }
+
+    fn dropped(dispatcher: &SinkDispatcher, name: &str) -> u64 {
+        let queues = dispatcher.queues();
+        queues.iter().find(|q| q.name == name).unwrap().dropped.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn full_queue() {
+        let dispatcher = SinkDispatcher::default();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (mut slow, mut slow_reports) = test_sink("slow", true);
+        let gate = Arc::new(Notify::new());
+        slow.gate = Some(gate.clone());
+        let (fast, mut fast_reports) = test_sink("fast", true);
+        dispatcher.spawn(Box::new(slow), cancel.clone());
+        dispatcher.spawn(Box::new(fast), cancel.clone());
+
+        for _ in 0..SINK_CHANNEL_SIZE {
+            dispatcher.dispatch("test", batch(), None);
+        }
+        for _ in 0..SINK_CHANNEL_SIZE {
+            next(&mut fast_reports.writes).await;
+        }
+        next(&mut slow_reports.starts).await;
+        assert_eq!(dispatcher.backlog(), SINK_CHANNEL_SIZE);
+
+        //only the stuck sink is losing the batches
+        for _ in 0..5 {
+            dispatcher.dispatch("test", batch(), None);
+        }
+        for _ in 0..5 {
+            next(&mut fast_reports.writes).await;
+        }
+        assert_eq!(dropped(&dispatcher, "slow"), 5);
+        assert_eq!(dropped(&dispatcher, "fast"), 0);
+
+        //the queued batches are written when the sink is back
+        gate.notify_one();
+        for _ in 0..SINK_CHANNEL_SIZE {
+            next(&mut slow_reports.writes).await;
+        }
+        assert!(slow_reports.writes.try_recv().is_err());
+        cancel.store(true, Ordering::SeqCst);
+    }
+
+    #[tokio::test]
+    async fn deadband_routing() {
+        let dispatcher = SinkDispatcher::default();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (filtered, mut filtered_reports) = test_sink("filtered", true);
+        let (unfiltered, mut unfiltered_reports) = test_sink("unfiltered", false);
+        dispatcher.spawn(Box::new(filtered), cancel.clone());
+        dispatcher.spawn(Box::new(unfiltered), cancel.clone());
+
+        dispatcher.dispatch("test", batch(), Some(vec![parameter("input_power")]));
+        assert_eq!(next(&mut filtered_reports.writes).await, vec!["input_power"]);
+        assert_eq!(next(&mut unfiltered_reports.writes).await, vec!["active_power", "input_power"]);
+
+        //nothing filtered (eg. the deadband is not configured)
+        dispatcher.dispatch("test", batch(), None);
+        assert_eq!(next(&mut filtered_reports.writes).await, vec!["active_power", "input_power"]);
+        assert_eq!(next(&mut unfiltered_reports.writes).await, vec!["active_power", "input_power"]);
+
+        //the removed sink is stopping, the rest is still getting the batches
+        dispatcher.remove("filtered");
+        dispatcher.dispatch("test", batch(), None);
+        next(&mut unfiltered_reports.writes).await;
+        assert!(filtered_reports.writes.recv().await.is_none());
+        cancel.store(true, Ordering::SeqCst);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_retry() {
+        let dispatcher = SinkDispatcher::default();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (mut sink, mut reports) = test_sink("retrying", true);
+        sink.start_errors = 7;
+        dispatcher.spawn(Box::new(sink), cancel.clone());
+
+        //the delay is doubled up to the max
+        let mut previous = next(&mut reports.starts).await;
+        for secs in [1, 2, 4, 8, 16, 32, SINK_START_RETRY_MAX_SECS] {
+            //the paused clock is advancing past the `next` timeout
+            let started = timeout(Duration::from_secs(2 * SINK_START_RETRY_MAX_SECS), reports.starts.recv());
+            let started = started.await.unwrap().unwrap();
+            let delay = started - previous;
+            assert!(delay >= Duration::from_secs(secs), "{:?} before retry, expected {} secs", delay, secs);
+            assert!(delay < Duration::from_secs(secs) + Duration::from_millis(2 * SINK_TICK_MS));
+            previous = started;
+        }
+
+        //the batches queued meanwhile are not lost
+        dispatcher.dispatch("test", batch(), None);
+        next(&mut reports.writes).await;
+        cancel.store(true, Ordering::SeqCst);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_retry_cancelled() {
+        let dispatcher = SinkDispatcher::default();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (mut sink, mut reports) = test_sink("retrying", true);
+        sink.start_errors = usize::MAX;
+        let task = dispatcher.spawn(Box::new(sink), cancel);
+
+        next(&mut reports.starts).await;
+        //removed on reload while still failing
+        dispatcher.remove("retrying");
+        assert_eq!(task.await.unwrap().unwrap_err().to_string(), "port in use");
+        assert!(reports.starts.try_recv().is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Local;
+use simplelog::*;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+use crate::config::REDACTED;
+use crate::sink::Result;
+
+pub const SMTP_TIMEOUT_SECS: u64 = 30; //max duration of sending a single mail
+
+/// Encryption of the SMTP connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmtpSecurity {
+    /// plain text, for local relays
+    None,
+    /// plain connection upgraded with STARTTLS (usually port 587)
+    Starttls,
+    /// TLS from the start (usually port 465)
+    Tls,
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(SmtpSecurity::None),
+            "starttls" => Ok(SmtpSecurity::Starttls),
+            "tls" => Ok(SmtpSecurity::Tls),
+            other => Err(format!("unknown smtp security: {:?}", other)),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct SmtpSettings {
+    /// `host[:port]`, the port defaults to the usual one of the security
+    pub host: String,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// The password is never printed
+impl fmt::Debug for SmtpSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SmtpSettings")
+            .field("host", &self.host)
+            .field("security", &self.security)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| REDACTED))
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .finish()
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl Connection {
+    /// Reads the (possibly multi-line) reply, fails when its code is not the expected one
+    async fn reply(&mut self, expected: &[u16]) -> Result<String> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err("connection closed by the server".into());
+            }
+            reply.push_str(&line);
+            //"250-..." is followed by more lines, "250 ..." is the last one
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+        let code: u16 = reply.get(..3).and_then(|c| c.parse().ok()).unwrap_or_default();
+        if !expected.contains(&code) {
+            return Err(format!("unexpected reply: {}", reply.trim()).into());
+        }
+        Ok(reply)
+    }
+
+    async fn command(&mut self, command: &str, expected: &[u16]) -> Result<String> {
+        self.stream.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await?;
+        self.reply(expected).await
+    }
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn tls(stream: Box<dyn Stream>, host: &str) -> Result<Box<dyn Stream>> {
+    let domain = ServerName::try_from(host).map_err(|e| format!("invalid tls server name {:?}: {}", host, e))?;
+    Ok(Box::new(tls_connector().connect(domain, stream).await?))
+}
+
+/// Subject header, encoded when it is not plain ascii
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+fn message(settings: &SmtpSettings, subject: &str, body: &str) -> String {
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        settings.from,
+        settings.to.join(", "),
+        encode_header(subject),
+        Local::now().to_rfc2822(),
+    );
+    for line in body.lines() {
+        //dot-stuffing, a single dot would end the data
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push('.');
+    message
+}
+
+async fn session(settings: &SmtpSettings, subject: &str, body: &str) -> Result<()> {
+    let (host, address) = match settings.host.rsplit_once(':') {
+        Some((host, _)) => (host.to_string(), settings.host.clone()),
+        None => {
+            let port = match settings.security {
+                SmtpSecurity::None => 25,
+                SmtpSecurity::Starttls => 587,
+                SmtpSecurity::Tls => 465,
+            };
+            (settings.host.clone(), format!("{}:{}", settings.host, port))
+        }
+    };
+    let mut stream: Box<dyn Stream> = Box::new(TcpStream::connect(&address).await?);
+    if settings.security == SmtpSecurity::Tls {
+        stream = tls(stream, &host).await?;
+    }
+    let mut connection = Connection { stream: BufReader::new(stream) };
+    connection.reply(&[220]).await?;
+    connection.command("EHLO hard", &[250]).await?;
+    if settings.security == SmtpSecurity::Starttls {
+        connection.command("STARTTLS", &[220]).await?;
+        let stream = tls(connection.stream.into_inner(), &host).await?;
+        connection = Connection { stream: BufReader::new(stream) };
+        connection.command("EHLO hard", &[250]).await?;
+    }
+    if let Some(username) = &settings.username {
+        let credentials = format!("\0{}\0{}", username, settings.password.as_deref().unwrap_or_default());
+        connection.command(&format!("AUTH PLAIN {}", STANDARD.encode(credentials)), &[235]).await?;
+    }
+    connection.command(&format!("MAIL FROM:<{}>", settings.from), &[250]).await?;
+    for to in &settings.to {
+        connection.command(&format!("RCPT TO:<{}>", to), &[250, 251]).await?;
+    }
+    connection.command("DATA", &[354]).await?;
+    connection.command(&message(settings, subject, body), &[250]).await?;
+    //the mail is accepted, the QUIT reply is not important
+    let _ = connection.command("QUIT", &[221]).await;
+    Ok(())
+}
+
+/// Sends a plain text mail to all the recipients
+pub async fn send(settings: &SmtpSettings, subject: &str, body: &str) -> Result<()> {
+    debug!("smtp: sending {:?} to {:?} using <u>{}</>", subject, settings.to, settings.host);
+    match timeout(Duration::from_secs(SMTP_TIMEOUT_SECS), session(settings, subject, body)).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timeout after {} secs", SMTP_TIMEOUT_SECS).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{next, smtp_server};
+
+    fn settings(host: String) -> SmtpSettings {
+        SmtpSettings {
+            host,
+            security: SmtpSecurity::None,
+            username: Some("hard".into()),
+            password: Some("secret".into()),
+            from: "hard@example.com".into(),
+            to: vec!["admin@example.com".into(), "ops@example.com".into()],
+        }
+    }
+
+    #[test]
+    fn security_from_str() {
+        assert_eq!("starttls".parse(), Ok(SmtpSecurity::Starttls));
+        assert_eq!(" tls ".parse(), Ok(SmtpSecurity::Tls));
+        assert!("ssl".parse::<SmtpSecurity>().is_err());
+    }
+
+    #[test]
+    fn password_not_printed() {
+        let debug = format!("{:?}", settings("localhost".into()));
+        assert!(!debug.contains("secret"), "{}", debug);
+        assert!(debug.contains(REDACTED), "{}", debug);
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(encode_header("inverter fault"), "inverter fault");
+        assert_eq!(encode_header("⚠️ fault"), "=?UTF-8?B?4pqg77iPIGZhdWx0?=");
+    }
+
+    #[tokio::test]
+    async fn sending() {
+        let (address, mut mails) = smtp_server("secret").await;
+        send(&settings(address.to_string()), "fault ✅", "fault code 2064\n.hidden\nalarms: none").await.unwrap();
+        let mail = next(&mut mails).await;
+        assert_eq!(
+            mail.commands,
+            vec![
+                "EHLO hard",
+                "AUTH PLAIN AGhhcmQAc2VjcmV0",
+                "MAIL FROM:<hard@example.com>",
+                "RCPT TO:<admin@example.com>",
+                "RCPT TO:<ops@example.com>",
+                "DATA",
+                "QUIT",
+            ]
+        );
+        let (headers, body) = mail.data.split_once("\n\n").unwrap();
+        let headers: Vec<&str> = headers.lines().collect();
+        assert_eq!(headers[..3], ["From: hard@example.com", "To: admin@example.com, ops@example.com", "Subject: =?UTF-8?B?ZmF1bHQg4pyF?="]);
+        assert!(headers.contains(&"Content-Type: text/plain; charset=utf-8"));
+        //the dots starting a line are doubled
+        assert_eq!(body, "fault code 2064\n..hidden\nalarms: none\n.\n");
+    }
+
+    #[tokio::test]
+    async fn auth_failure() {
+        let (address, mut mails) = smtp_server("other").await;
+        let e = send(&settings(address.to_string()), "fault", "").await.unwrap_err().to_string();
+        assert_eq!(e, "unexpected reply: 535 5.7.8 authentication failed");
+        assert_eq!(next(&mut mails).await.commands.len(), 2);
+    }
+}
@@ -0,0 +1,97 @@
+use chrono::{DateTime, Duration, Utc};
+
+/// Sun elevation at sunrise and sunset, including the atmospheric refraction and the solar disc
+pub const HORIZON_DEG: f64 = -0.833;
+
+/// Sun elevation above the horizon in degrees (low precision NOAA formulas, ~0.1°)
+pub fn elevation(lat: f64, lon: f64, time: DateTime<Utc>) -> f64 {
+    //days since J2000.0
+    let n = time.timestamp_millis() as f64 / 86_400_000.0 + 2_440_587.5 - 2_451_545.0;
+    let mean_longitude = (280.460 + 0.985_647_4 * n).rem_euclid(360.0);
+    let mean_anomaly = (357.528 + 0.985_600_3 * n).rem_euclid(360.0).to_radians();
+    let ecliptic_longitude =
+        (mean_longitude + 1.915 * mean_anomaly.sin() + 0.020 * (2.0 * mean_anomaly).sin()).to_radians();
+    let obliquity = (23.439 - 0.000_000_4 * n).to_radians();
+
+    let right_ascension = (obliquity.cos() * ecliptic_longitude.sin()).atan2(ecliptic_longitude.cos());
+    let declination = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+    let sidereal_time = (18.697_374_558 + 24.065_709_824_419_08 * n).rem_euclid(24.0) * 15.0;
+    let hour_angle = (sidereal_time + lon).to_radians() - right_ascension;
+
+    let lat = lat.to_radians();
+    (lat.sin() * declination.sin() + lat.cos() * declination.cos() * hour_angle.cos())
+        .asin()
+        .to_degrees()
+}
+
+/// Whether the sun is above the horizon
+pub fn is_up(lat: f64, lon: f64, time: DateTime<Utc>) -> bool {
+    elevation(lat, lon, time) > HORIZON_DEG
+}
+
+/// Next sunrise (or sunset when `rising` is false) within two days, to the minute;
+/// None during the polar day or night
+pub fn next_crossing(lat: f64, lon: f64, from: DateTime<Utc>, rising: bool) -> Option<DateTime<Utc>> {
+    let mut up = is_up(lat, lon, from);
+    (1..=2 * 24 * 60).map(|minute| from + Duration::minutes(minute)).find(|&time| {
+        let was_up = up;
+        up = is_up(lat, lon, time);
+        up != was_up && up == rising
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn assert_near(time: Option<DateTime<Utc>>, expected: DateTime<Utc>) {
+        let time = time.expect("no crossing");
+        assert!((time - expected).num_minutes().abs() <= 2, "{} instead of {}", time, expected);
+    }
+
+    //reference values of the NOAA solar calculator
+    #[test]
+    fn elevation_reference() {
+        let cases = [
+            //Greenwich, solar noon at the solstices and the equinox
+            (51.4769, -0.0005, utc(2024, 6, 20, 12, 2), 61.96),
+            (51.4769, -0.0005, utc(2024, 12, 21, 11, 58), 15.08),
+            (51.4769, -0.0005, utc(2024, 3, 20, 12, 7), 38.53),
+            //Sydney, solar noon and midnight
+            (-33.87, 151.21, utc(2024, 1, 15, 1, 56), 77.33),
+            (-33.87, 151.21, utc(2024, 1, 15, 13, 56), -34.94),
+        ];
+        for (lat, lon, time, expected) in cases {
+            let elevation = elevation(lat, lon, time);
+            assert!((elevation - expected).abs() < 0.2, "{} at {}: {} instead of {}", lat, time, elevation, expected);
+        }
+    }
+
+    #[test]
+    fn sunrise_sunset() {
+        //London, the longest day: 04:43 and 21:21 BST
+        let from = utc(2024, 6, 21, 0, 0);
+        assert_near(next_crossing(51.5074, -0.1278, from, true), utc(2024, 6, 21, 3, 43));
+        assert_near(next_crossing(51.5074, -0.1278, from, false), utc(2024, 6, 21, 20, 21));
+        //Sydney, the longest day: 05:41 and 20:05 AEDT
+        let from = utc(2024, 12, 20, 12, 0);
+        assert_near(next_crossing(-33.87, 151.21, from, true), utc(2024, 12, 20, 18, 41));
+        assert_near(next_crossing(-33.87, 151.21, from, false), utc(2024, 12, 21, 9, 5));
+        assert!(!is_up(-33.87, 151.21, from));
+        assert!(is_up(-33.87, 151.21, utc(2024, 12, 21, 2, 0)));
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        //Tromsø
+        assert!(is_up(69.65, 18.96, utc(2024, 6, 21, 22, 0)));
+        assert_eq!(next_crossing(69.65, 18.96, utc(2024, 6, 21, 0, 0), false), None);
+        assert!(!is_up(69.65, 18.96, utc(2024, 12, 21, 11, 0)));
+        assert_eq!(next_crossing(69.65, 18.96, utc(2024, 12, 21, 0, 0), true), None);
+    }
+}
@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::sink::{ReadingBatch, Result, Sink};
+use crate::sun2000::{ParamKind, Parameter};
+use async_trait::async_trait;
+use chrono::{Local, LocalResult, TimeZone};
+use rusqlite::{params, Connection, OptionalExtension};
+use simplelog::*;
+
+pub const SQLITE_BUCKET_5MIN_MS: u128 = 5 * 60 * 1000;
+pub const SQLITE_PRUNE_INTERVAL_SECS: f32 = 3600.0; //secs between removing expired data
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS parameters (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        reg_address INTEGER NOT NULL,
+        unit TEXT,
+        UNIQUE (name, reg_address)
+    );
+    CREATE TABLE IF NOT EXISTS readings (
+        time INTEGER NOT NULL,
+        parameter_id INTEGER NOT NULL REFERENCES parameters (id),
+        value REAL,
+        text_value TEXT
+    );
+    CREATE INDEX IF NOT EXISTS readings_parameter_time_idx ON readings (parameter_id, time);
+    CREATE TABLE IF NOT EXISTS readings_5min (
+        bucket INTEGER NOT NULL,
+        parameter_id INTEGER NOT NULL REFERENCES parameters (id),
+        min REAL NOT NULL,
+        max REAL NOT NULL,
+        avg REAL NOT NULL,
+        count INTEGER NOT NULL,
+        first REAL NOT NULL,
+        last REAL NOT NULL,
+        delta REAL,
+        PRIMARY KEY (parameter_id, bucket)
+    );
+    CREATE TABLE IF NOT EXISTS readings_daily (
+        bucket INTEGER NOT NULL,
+        parameter_id INTEGER NOT NULL REFERENCES parameters (id),
+        min REAL NOT NULL,
+        max REAL NOT NULL,
+        avg REAL NOT NULL,
+        count INTEGER NOT NULL,
+        first REAL NOT NULL,
+        last REAL NOT NULL,
+        delta REAL,
+        PRIMARY KEY (parameter_id, bucket)
+    );
+";
+
+/// Min/max/avg accumulator of a single parameter in a single time bucket
+#[derive(Clone)]
+struct Aggregate {
+    bucket: u128,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+    first: f64,
+    last: f64,
+    energy: bool,
+}
+
+impl Aggregate {
+    fn new(bucket: u128, value: f64, energy: bool) -> Self {
+        Self {
+            bucket,
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+            first: value,
+            last: value,
+            energy,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+        self.last = value;
+    }
+}
+
+/// Start of the local day containing the `time` (in ms)
+fn day_bucket(time: u128) -> u128 {
+    let secs = (time / 1000) as i64;
+    match Local.timestamp_opt(secs, 0) {
+        LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => {
+            match dt.date_naive().and_hms_opt(0, 0, 0).map(|m| Local.from_local_datetime(&m)) {
+                Some(LocalResult::Single(midnight)) | Some(LocalResult::Ambiguous(midnight, _)) => {
+                    midnight.timestamp_millis() as u128
+                }
+                _ => time - time % (24 * 3600 * 1000),
+            }
+        }
+        LocalResult::None => time - time % (24 * 3600 * 1000),
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+pub struct SqliteSink {
+    pub name: String,
+    pub path: String,
+    pub raw_retention_days: u32,
+    pub five_min_retention_days: u32,
+    pub daily_retention_days: u32,
+    conn: Option<Connection>,
+    cache: HashMap<(String, u16), i64>,
+    //running aggregates of the current buckets, keyed by parameter id
+    five_min: HashMap<i64, Aggregate>,
+    daily: HashMap<i64, Aggregate>,
+    prune_interval: Option<Instant>,
+}
+
+impl SqliteSink {
+    pub fn new(
+        name: String,
+        path: String,
+        raw_retention_days: u32,
+        five_min_retention_days: u32,
+        daily_retention_days: u32,
+    ) -> Self {
+        Self {
+            name,
+            path,
+            raw_retention_days,
+            five_min_retention_days,
+            daily_retention_days,
+            conn: None,
+            cache: HashMap::new(),
+            five_min: HashMap::new(),
+            daily: HashMap::new(),
+            prune_interval: None,
+        }
+    }
+
+    fn parameter_id(
+        conn: &Connection,
+        cache: &mut HashMap<(String, u16), i64>,
+        p: &Parameter,
+    ) -> rusqlite::Result<i64> {
+        if let Some(id) = cache.get(&(p.name.clone(), p.reg_address)) {
+            return Ok(*id);
+        }
+        conn.execute(
+            "INSERT OR IGNORE INTO parameters (name, reg_address, unit) VALUES (?1, ?2, ?3)",
+            params![p.name, p.reg_address, p.unit],
+        )?;
+        let id = conn.query_row(
+            "SELECT id FROM parameters WHERE name = ?1 AND reg_address = ?2",
+            params![p.name, p.reg_address],
+            |row| row.get(0),
+        )?;
+        cache.insert((p.name.clone(), p.reg_address), id);
+        Ok(id)
+    }
+
+    /// Merges the aggregate with a row possibly stored before a restart
+    fn store_aggregate(conn: &Connection, table: &str, parameter_id: i64, agg: &Aggregate) -> rusqlite::Result<()> {
+        let delta = if agg.energy { Some(agg.last - agg.first) } else { None };
+        conn.execute(
+            &format!(
+                "INSERT INTO {table} (bucket, parameter_id, min, max, avg, count, first, last, delta)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT (parameter_id, bucket) DO UPDATE SET
+                    min = min({table}.min, excluded.min),
+                    max = max({table}.max, excluded.max),
+                    avg = ({table}.avg * {table}.count + excluded.avg * excluded.count) / ({table}.count + excluded.count),
+                    count = {table}.count + excluded.count,
+                    last = excluded.last,
+                    delta = CASE WHEN excluded.delta IS NULL THEN NULL ELSE excluded.last - {table}.first END",
+                table = table
+            ),
+            params![
+                agg.bucket as i64,
+                parameter_id,
+                agg.min,
+                agg.max,
+                agg.sum / agg.count as f64,
+                agg.count as i64,
+                agg.first,
+                agg.last,
+                delta
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn prune(&self, conn: &Connection) -> rusqlite::Result<usize> {
+        let now = now_ms() as i64;
+        let day_ms: i64 = 24 * 3600 * 1000;
+        let mut removed = 0;
+        for (table, days) in [
+            ("readings", self.raw_retention_days),
+            ("readings_5min", self.five_min_retention_days),
+            ("readings_daily", self.daily_retention_days),
+        ] {
+            //zero means keeping the data forever
+            if days == 0 {
+                continue;
+            }
+            let column = if table == "readings" { "time" } else { "bucket" };
+            removed += conn.execute(
+                &format!("DELETE FROM {} WHERE {} < ?1", table, column),
+                params![now - days as i64 * day_ms],
+            )?;
+        }
+        Ok(removed)
+    }
+
+    fn open(&self) -> rusqlite::Result<Connection> {
+        let conn = Connection::open(&self.path)?;
+        //WAL is much gentler for SD cards than the rollback journal
+        let _: Option<String> = conn
+            .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+            .optional()?;
+        conn.execute_batch("PRAGMA synchronous = NORMAL;")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(conn)
+    }
+
+    fn store(&mut self, params: &[Parameter]) -> rusqlite::Result<()> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        let tx = conn.transaction()?;
+        //the running aggregates are updated only when the transaction is committed
+        let mut five_min = HashMap::new();
+        let mut daily = HashMap::new();
+        for p in params.iter().filter(|p| p.save_to_influx) {
+            let parameter_id = SqliteSink::parameter_id(&tx, &mut self.cache, p)?;
+            let text_value = match p.value {
+                ParamKind::Text(_) => Some(p.get_text_value()),
+                _ => None,
+            };
+            let value = p.get_float_value();
+            tx.execute(
+                "INSERT INTO readings (time, parameter_id, value, text_value) VALUES (?1, ?2, ?3, ?4)",
+                params![p.time as i64, parameter_id, value, text_value],
+            )?;
+
+            let value = match value {
+                Some(v) if p.unit != Some("epoch") => v,
+                _ => continue,
+            };
+            let energy = matches!(p.unit, Some("kWh") | Some("Wh"));
+            for (updated, aggregates, bucket, table) in [
+                (&mut five_min, &self.five_min, p.time - p.time % SQLITE_BUCKET_5MIN_MS, "readings_5min"),
+                (&mut daily, &self.daily, day_bucket(p.time), "readings_daily"),
+            ] {
+                let current = updated.get(&parameter_id).or_else(|| aggregates.get(&parameter_id));
+                let agg = match current {
+                    Some(agg) if agg.bucket == bucket => {
+                        let mut agg = agg.clone();
+                        agg.add(value);
+                        agg
+                    }
+                    Some(agg) => {
+                        //bucket completed
+                        SqliteSink::store_aggregate(&tx, table, parameter_id, agg)?;
+                        Aggregate::new(bucket, value, energy)
+                    }
+                    None => Aggregate::new(bucket, value, energy),
+                };
+                updated.insert(parameter_id, agg);
+            }
+        }
+        tx.commit()?;
+        self.five_min.extend(five_min);
+        self.daily.extend(daily);
+        Ok(())
+    }
+
+    fn store_pending_aggregates(
+        conn: &mut Connection,
+        five_min: &HashMap<i64, Aggregate>,
+        daily: &HashMap<i64, Aggregate>,
+    ) -> rusqlite::Result<()> {
+        let tx = conn.transaction()?;
+        for (parameter_id, agg) in five_min {
+            SqliteSink::store_aggregate(&tx, "readings_5min", *parameter_id, agg)?;
+        }
+        for (parameter_id, agg) in daily {
+            SqliteSink::store_aggregate(&tx, "readings_daily", *parameter_id, agg)?;
+        }
+        tx.commit()
+    }
+}
+
+#[async_trait]
+impl Sink for SqliteSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    //the rollups are computed from every sample
+    fn deadband(&self) -> bool {
+        false
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        match self.open() {
+            Ok(conn) => {
+                info!("<i>{}</>: using database <u>{}</>", self.name, self.path);
+                self.conn = Some(conn);
+                Ok(())
+            }
+            Err(e) => {
+                error!("<i>{}</>: cannot open database <u>{}</>: <b>{}</>", self.name, self.path, e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        tokio::task::block_in_place(|| self.store(&batch.parameters))?;
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> Result<()> {
+        if self
+            .prune_interval
+            .is_none_or(|i| i.elapsed() > Duration::from_secs_f32(SQLITE_PRUNE_INTERVAL_SECS))
+        {
+            self.prune_interval = Some(Instant::now());
+            if let Some(conn) = &self.conn {
+                let removed = tokio::task::block_in_place(|| self.prune(conn))?;
+                debug!("{}: removed {} expired rows", self.name, removed);
+            }
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        //keep the partial buckets, they are merged after restart
+        if let Some(conn) = &mut self.conn {
+            SqliteSink::store_pending_aggregates(conn, &self.five_min, &self.daily)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //aligned to the 5-minute buckets
+    const T0: u128 = 1_700_000_100_000;
+    const MINUTE: u128 = 60 * 1000;
+    const DAY: u128 = 24 * 60 * MINUTE;
+
+    fn power(time: u128, value: i32) -> Parameter {
+        Parameter::new("active_power", ParamKind::NumberI32(Some(value)), time, None, Some("W"), 1, 32080, 2, false, true)
+    }
+
+    fn energy(time: u128, value: u32) -> Parameter {
+        let value = ParamKind::NumberU32(Some(value));
+        Parameter::new("accumulated_yield_energy", value, time, None, Some("kWh"), 100, 32106, 2, false, true)
+    }
+
+    fn sqlite_sink(path: &std::path::Path) -> SqliteSink {
+        let mut sink = SqliteSink::new("sqlite".into(), path.to_string_lossy().into(), 5, 30, 0);
+        sink.conn = Some(sink.open().unwrap());
+        sink
+    }
+
+    /// (bucket, min, max, avg, count, first, last, delta) rows of the table
+    #[allow(clippy::type_complexity)]
+    fn rows(sink: &SqliteSink, table: &str) -> Vec<(i64, f64, f64, f64, i64, f64, f64, Option<f64>)> {
+        let conn = sink.conn.as_ref().unwrap();
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT bucket, min, max, avg, count, first, last, delta FROM {} ORDER BY parameter_id, bucket",
+                table
+            ))
+            .unwrap();
+        let rows = statement
+            .query_map([], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?, r.get(6)?, r.get(7)?))
+            })
+            .unwrap();
+        rows.map(|r| r.unwrap()).collect()
+    }
+
+    fn count(sink: &SqliteSink, table: &str) -> i64 {
+        let conn = sink.conn.as_ref().unwrap();
+        conn.query_row(&format!("SELECT count(*) FROM {}", table), [], |r| r.get(0)).unwrap()
+    }
+
+    #[test]
+    fn five_min_rollup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = sqlite_sink(&dir.path().join("hard.db"));
+        sink.store(&[power(T0, 100), energy(T0, 1000)]).unwrap();
+        sink.store(&[power(T0 + MINUTE, 300), energy(T0 + MINUTE, 1100)]).unwrap();
+        sink.store(&[power(T0 + 2 * MINUTE, 200), energy(T0 + 2 * MINUTE, 1250)]).unwrap();
+        //the buckets are stored when completed
+        assert!(rows(&sink, "readings_5min").is_empty());
+        assert_eq!(count(&sink, "readings"), 6);
+
+        sink.store(&[power(T0 + 5 * MINUTE, 50), energy(T0 + 5 * MINUTE, 1300)]).unwrap();
+        let bucket = T0 as i64;
+        assert_eq!(
+            rows(&sink, "readings_5min"),
+            vec![
+                (bucket, 100.0, 300.0, 200.0, 3, 100.0, 200.0, None),
+                (bucket, 10.0, 12.5, 11.0 + 1.0 / 6.0, 3, 10.0, 12.5, Some(2.5)),
+            ]
+        );
+        assert_eq!(sink.five_min[&1].bucket, T0 + SQLITE_BUCKET_5MIN_MS);
+        assert_eq!(sink.five_min[&1].count, 1);
+    }
+
+    #[test]
+    fn daily_rollup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = sqlite_sink(&dir.path().join("hard.db"));
+        let day = day_bucket(T0);
+        sink.store(&[energy(day + MINUTE, 1000)]).unwrap();
+        sink.store(&[energy(day + 12 * 60 * MINUTE, 2500)]).unwrap();
+        sink.store(&[energy(day + DAY - MINUTE, 3000)]).unwrap();
+        assert!(rows(&sink, "readings_daily").is_empty());
+
+        sink.store(&[energy(day_bucket(day + DAY + 60 * MINUTE) + MINUTE, 3010)]).unwrap();
+        assert_eq!(
+            rows(&sink, "readings_daily"),
+            vec![(day as i64, 10.0, 30.0, 65.0 / 3.0, 3, 10.0, 30.0, Some(20.0))]
+        );
+        //every sample was in its own 5-minute bucket
+        assert_eq!(rows(&sink, "readings_5min").len(), 3);
+    }
+
+    #[test]
+    fn restart_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hard.db");
+        let mut sink = sqlite_sink(&path);
+        sink.store(&[energy(T0, 1000)]).unwrap();
+        sink.store(&[energy(T0 + MINUTE, 1200)]).unwrap();
+        SqliteSink::store_pending_aggregates(sink.conn.as_mut().unwrap(), &sink.five_min, &sink.daily).unwrap();
+        assert_eq!(rows(&sink, "readings_5min"), vec![(T0 as i64, 10.0, 12.0, 11.0, 2, 10.0, 12.0, Some(2.0))]);
+        drop(sink);
+
+        //the partial bucket of the previous run is merged with the new samples
+        let mut sink = sqlite_sink(&path);
+        sink.store(&[energy(T0 + 3 * MINUTE, 1500)]).unwrap();
+        sink.store(&[energy(T0 + 5 * MINUTE, 1600)]).unwrap();
+        assert_eq!(
+            rows(&sink, "readings_5min"),
+            vec![(T0 as i64, 10.0, 15.0, 37.0 / 3.0, 3, 10.0, 15.0, Some(5.0))]
+        );
+    }
+
+    #[test]
+    fn failed_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = sqlite_sink(&dir.path().join("hard.db"));
+        sink.store(&[power(T0, 100), energy(T0, 1000)]).unwrap();
+        //the energy insert fails after the power bucket is completed
+        let conn = sink.conn.as_ref().unwrap();
+        conn.execute_batch(
+            "CREATE TRIGGER fail BEFORE INSERT ON readings WHEN NEW.parameter_id = 2
+             BEGIN SELECT RAISE(ABORT, 'disk full'); END",
+        )
+        .unwrap();
+        assert!(sink.store(&[power(T0 + 5 * MINUTE, 300), energy(T0 + 5 * MINUTE, 1100)]).is_err());
+        assert!(rows(&sink, "readings_5min").is_empty());
+        //the running aggregate is not advanced by the rolled back samples
+        assert_eq!(sink.five_min[&1].bucket, T0);
+        assert_eq!(sink.five_min[&1].count, 1);
+        assert_eq!(sink.daily[&1].count, 1);
+
+        sink.conn.as_ref().unwrap().execute_batch("DROP TRIGGER fail").unwrap();
+        sink.store(&[power(T0 + 5 * MINUTE, 300), energy(T0 + 5 * MINUTE, 1100)]).unwrap();
+        assert_eq!(rows(&sink, "readings_5min").len(), 2);
+    }
+
+    #[test]
+    fn retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = sqlite_sink(&dir.path().join("hard.db"));
+        let now = now_ms();
+        //an hour after midnight, both samples are in the same day
+        let old = day_bucket(now - 10 * DAY) + 60 * MINUTE;
+        let ancient = day_bucket(now - 40 * DAY) + 60 * MINUTE;
+        for time in [ancient, ancient + 5 * MINUTE, old, old + 5 * MINUTE, now] {
+            sink.store(&[power(time, 100)]).unwrap();
+        }
+        assert_eq!(count(&sink, "readings"), 5);
+        assert_eq!(count(&sink, "readings_5min"), 4);
+        assert_eq!(count(&sink, "readings_daily"), 2);
+
+        //raw kept for 5 days, 5-minute for 30 days, daily forever
+        let removed = sink.prune(sink.conn.as_ref().unwrap()).unwrap();
+        assert_eq!(removed, 4 + 2);
+        assert_eq!(count(&sink, "readings"), 1);
+        assert_eq!(count(&sink, "readings_5min"), 2);
+        assert_eq!(count(&sink, "readings_daily"), 2);
+    }
+}
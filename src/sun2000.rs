@@ -1,20 +1,30 @@
-use async_channel::Sender;
-use chrono::{Local, LocalResult, NaiveDateTime, TimeZone};
-use influxdb::WriteQuery;
-use influxdb::{Client, InfluxDbWriteable, Timestamp, Type};
+use chrono::{DateTime, Local, LocalResult, NaiveDateTime, TimeZone, Utc};
+use influxdb::Type;
+use crate::deadband::{Deadband, DeadbandSettings};
+use crate::history::History;
+use crate::rules::{Rule, RuleEngine, RuleEvent};
+use crate::mode_change::{ModeChange, ModeChangeScript};
+use crate::notify::{Notifier, NotifySettings};
+use crate::solar;
+use crate::sink::{BatchMetadata, ReadingBatch, SinkDispatcher};
+use crate::systemd;
 use io::ErrorKind;
 use simplelog::*;
 use std::fmt;
+use std::str::FromStr;
 use std::io::{self, Error};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
 use tokio::time::timeout;
 use tokio_modbus::client::Context;
 use tokio_modbus::prelude::*;
 
 pub const SUN2000_STATS_DUMP_INTERVAL_SECS: f32 = 3600.0; //secs between showing stats
+pub const SUN2000_NIGHT_WATCHDOG_SECS: u64 = 5; //secs between the watchdog pings while the inverter is asleep
 pub const SUN2000_ATTEMPTS_PER_PARAM: u8 = 3; //max read attempts per single parameter
 
 // Just a generic Result type to ease error handling for us. Errors in multithreaded
@@ -42,11 +52,25 @@ impl fmt::Display for ParamKind {
     }
 }
 
+/// How often the parameter is read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PollTier {
+    /// only once, right after connecting
+    OnConnect,
+    /// every `poll_interval`
+    Fast,
+    Normal,
+    Slow,
+}
+
+pub const PERIODIC_TIERS: [PollTier; 3] = [PollTier::Fast, PollTier::Normal, PollTier::Slow];
+
 pub struct ParameterBlock {
     reg_address: u16,
     len: u16,
-    parameters: Vec<Parameter>,
-    only_initial_read: bool
+    pub parameters: Vec<Parameter>,
+    /// tier of the parameters which are not `initial_read`
+    tier: PollTier,
 }
 
 impl ParameterBlock {
@@ -55,7 +79,6 @@ impl ParameterBlock {
     ) -> Self {
         //parameters pre-condition that is memory contigue
         let mut end_reg_address = parameters[0].reg_address;
-        let mut only_initial_read = true;
         parameters.iter().for_each(|p| {
             
             if p.reg_address != end_reg_address {
@@ -63,34 +86,44 @@ impl ParameterBlock {
             } else {
                 end_reg_address = p.reg_address + p.len;
             }
-            only_initial_read = only_initial_read && p.initial_read;
         });
 
         Self {
             reg_address: parameters[0].reg_address,
             len: end_reg_address - parameters[0].reg_address,
             parameters,
-            only_initial_read
+            tier: PollTier::Fast,
         }
     }
-   
+
+    /// Battery (370xx, 377xx, 38xxx) and power meter (371xx) registers, the optimizers excluded
+    fn is_storage_or_meter(&self) -> bool {
+        (37000..39000).contains(&self.reg_address) && self.reg_address != 37200
+    }
+
+    /// Default tier of the periodic parameters (fast when not set)
+    pub fn with_tier(mut self, tier: PollTier) -> Self {
+        self.tier = tier;
+        self
+    }
 }
 
 #[derive(Clone)]
 pub struct Parameter {
-    name: String,
-    value: ParamKind,
-    time: u128,
-    desc: Option<&'static str>,
-    unit: Option<&'static str>,
-    gain: u16,
-    reg_address: u16,
-    len: u16,
-    initial_read: bool,
-    save_to_influx: bool,
+    pub name: String,
+    pub value: ParamKind,
+    pub time: u128,
+    pub desc: Option<&'static str>,
+    pub unit: Option<&'static str>,
+    pub gain: u16,
+    pub reg_address: u16,
+    pub len: u16,
+    pub initial_read: bool,
+    pub save_to_influx: bool,
 }
 
 impl Parameter {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: &'static str,
         value: ParamKind,
@@ -117,6 +150,7 @@ impl Parameter {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_string(
         name: String,
         value: ParamKind,
@@ -190,6 +224,60 @@ impl Parameter {
         }
     }
 
+    pub fn get_float_value(&self) -> Option<f64> {
+        let value = match &self.value {
+            ParamKind::Text(_) => None,
+            ParamKind::NumberU16(v) => v.map(|v| v as f64),
+            ParamKind::NumberI16(v) => v.map(|v| v as f64),
+            ParamKind::NumberU32(v) => v.map(|v| v as f64),
+            ParamKind::NumberI32(v) => v.map(|v| v as f64),
+        };
+        value.map(|v| v / self.gain as f64)
+    }
+
+    /// Human readable meaning of the alarm/state bitfields and status codes
+    pub fn get_decoded_value(&self) -> Option<Vec<&'static str>> {
+        let value = match self.value {
+            ParamKind::NumberU16(Some(v)) => v,
+            ParamKind::NumberI16(Some(v)) if self.unit == Some("storage_status_enum") => v as u16,
+            _ => return None,
+        };
+        match (self.unit.unwrap_or_default(), self.name.as_str()) {
+            ("alarm_bitfield16", "alarm_1") => Some(get_bit_names(&ALARM_1_BITS, value)),
+            ("alarm_bitfield16", "alarm_2") => Some(get_bit_names(&ALARM_2_BITS, value)),
+            ("alarm_bitfield16", "alarm_3") => Some(get_bit_names(&ALARM_3_BITS, value)),
+            ("state_bitfield16", "state_1") => Some(get_bit_names(&STATE_1_BITS, value)),
+            ("status_enum", _) => Some(vec![get_device_status_name(value)]),
+            ("storage_status_enum", _) => Some(vec![get_storage_status_name(value)]),
+            _ => None,
+        }
+    }
+
+    /// False for the registers which were read but have no meaningful value (eg. zero epoch)
+    pub fn has_value(&self) -> bool {
+        match &self.value {
+            ParamKind::Text(v) => v.is_some(),
+            ParamKind::NumberU16(v) => v.is_some(),
+            ParamKind::NumberI16(v) => v.is_some(),
+            ParamKind::NumberU32(v) => v.is_some(),
+            ParamKind::NumberI32(v) => v.is_some(),
+        }
+    }
+
+    /// Same value as shown in the logs, but numbers are json numbers
+    pub fn get_json_value(&self) -> serde_json::Value {
+        let text = self.get_text_value();
+        match self.value {
+            ParamKind::Text(_) => serde_json::Value::String(text),
+            _ if self.unit == Some("epoch") => serde_json::Value::String(text),
+            _ => match (text.parse::<i64>(), text.parse::<f64>()) {
+                (Ok(v), _) => v.into(),
+                (_, Ok(v)) => v.into(),
+                _ => serde_json::Value::String(text),
+            },
+        }
+    }
+
     pub fn get_influx_value(&self) -> influxdb::Type {
         match &self.value {
             ParamKind::Text(v) => {
@@ -246,21 +334,374 @@ fn get_attribute_name(id: &str) -> &'static str {
     "Unknown attribute"
 }
 
-pub struct Sun2000 {
+const ALARM_1_BITS: [&str; 16] = [
+    "High String Input Voltage",
+    "DC Arc Fault",
+    "String Reverse Connection",
+    "String Current Backfeed",
+    "Abnormal String Power",
+    "AFCI Self-Check Fail",
+    "Phase Wire Short-Circuited to PE",
+    "Grid Loss",
+    "Grid Undervoltage",
+    "Grid Overvoltage",
+    "Grid Voltage Imbalance",
+    "Grid Overfrequency",
+    "Grid Underfrequency",
+    "Unstable Grid Frequency",
+    "Output Overcurrent",
+    "Output DC Component Overhigh",
+];
+
+const ALARM_2_BITS: [&str; 16] = [
+    "Abnormal Residual Current",
+    "Abnormal Grounding",
+    "Low Insulation Resistance",
+    "Overtemperature",
+    "Device Fault",
+    "Upgrade Failed or Version Mismatch",
+    "License Expired",
+    "Faulty Monitoring Unit",
+    "Faulty Power Collector",
+    "Battery Abnormal",
+    "Active Islanding",
+    "Passive Islanding",
+    "Transient AC Overvoltage",
+    "Peripheral Port Short Circuit",
+    "Churn Output Overload",
+    "Abnormal PV Module Configuration",
+];
+
+const ALARM_3_BITS: [&str; 9] = [
+    "Optimizer Fault",
+    "Built-in PID Operation Abnormal",
+    "High Input String Voltage to Ground",
+    "External Fan Abnormal",
+    "Battery Reverse Connection",
+    "On-grid/Off-grid Controller Abnormal",
+    "PV String Loss",
+    "Internal Fan Abnormal",
+    "DC Protection Unit Abnormal",
+];
+
+const STATE_1_BITS: [&str; 10] = [
+    "Standby",
+    "Grid-connected",
+    "Grid-connected normally",
+    "Grid connection with derating due to power rationing",
+    "Grid connection with derating due to internal causes",
+    "Normal stop",
+    "Stop due to faults",
+    "Stop due to power rationing",
+    "Shutdown",
+    "Spot check",
+];
+
+fn get_bit_names(bits: &[&'static str], value: u16) -> Vec<&'static str> {
+    bits.iter()
+        .enumerate()
+        .filter(|(i, _)| value & (1 << i) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn get_storage_status_name(status: u16) -> &'static str {
+    match status {
+        0 => "Offline",
+        1 => "Standby",
+        2 => "Running",
+        3 => "Fault",
+        4 => "Sleep mode",
+        _ => "Unknown",
+    }
+}
+
+fn get_device_status_name(status: u16) -> &'static str {
+    match status {
+        0x0000 => "Standby: initializing",
+        0x0001 => "Standby: detecting insulation resistance",
+        0x0002 => "Standby: detecting irradiation",
+        0x0003 => "Standby: grid detecting",
+        0x0100 => "Starting",
+        0x0200 => "On-grid",
+        0x0201 => "Grid connection: power limited",
+        0x0202 => "Grid connection: self-derating",
+        0x0203 => "Off-grid running",
+        0x0300 => "Shutdown: fault",
+        0x0301 => "Shutdown: command",
+        0x0302 => "Shutdown: OVGR",
+        0x0303 => "Shutdown: communication disconnected",
+        0x0304 => "Shutdown: power limited",
+        0x0305 => "Shutdown: manual startup required",
+        0x0306 => "Shutdown: DC switches disconnected",
+        0x0307 => "Shutdown: rapid cutoff",
+        0x0308 => "Shutdown: input underpower",
+        0x0401 => "Grid scheduling: cos(phi)-P curve",
+        0x0402 => "Grid scheduling: Q-U curve",
+        0x0403 => "Grid scheduling: PF-U curve",
+        0x0404 => "Grid scheduling: dry contact",
+        0x0405 => "Grid scheduling: Q-P curve",
+        0x0500 => "Spot-check ready",
+        0x0501 => "Spot-checking",
+        0x0600 => "Inspecting",
+        0x0700 => "AFCI self check",
+        0x0800 => "I-V scanning",
+        0x0900 => "DC input detection",
+        0x0A00 => "Running: off-grid charging",
+        0xA000 => "Standby: no irradiation",
+        _ => "Unknown status",
+    }
+}
+
+/// Inverter identity, obtained during the initial read after connecting
+#[derive(Clone, Debug, Default)]
+pub struct DeviceInfo {
     pub name: String,
-    pub host_port: String,
-    pub poll_ok: u64,
-    pub poll_errors: u64,
-    pub influxdb_url: Option<String>,
-    pub influxdb_token: Option<String>,
-    pub mode_change_script: Option<String>,
-    pub dongle_connection: bool,
-    pub partial: bool,
-    pub bulk_insert: bool,
-    pub tx_influxdb: Option<Sender<Vec<WriteQuery>>>,
+    pub model_name: Option<String>,
+    pub serial_number: Option<String>,
+    pub product_number: Option<String>,
+    pub software_version: Option<String>,
+}
+
+/// Poll counters, shared with the tasks exporting the daemon state
+#[derive(Default)]
+pub struct Sun2000Stats {
+    pub poll_ok: AtomicU64,
+    pub poll_errors: AtomicU64,
+    pub reconnects: AtomicU64,
+    pub query_time_ms: AtomicU64,
+    pub register_errors: Mutex<HashMap<String, u64>>,
+    pub connected: AtomicBool,
+    /// milliseconds since the epoch of the last successful poll
+    pub last_poll_ms: AtomicU64,
+    /// time (ms since the epoch) and message of the last error which caused a reconnect
+    pub last_error: Mutex<Option<(u128, String)>>,
+    /// batches waiting in the most loaded sink queue after the last dispatch
+    pub sink_backlog: AtomicU64,
+    /// the night mode is active, missed polls are expected
+    pub night: AtomicBool,
+}
+
+/// Limits for reporting the daemon as unhealthy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HealthLimits {
+    pub poll_interval: Duration,
+    /// polls which may be missed before the daemon is unhealthy
+    pub max_missed_polls: u32,
+    pub max_sink_backlog: u64,
+}
+
+impl Sun2000Stats {
+    fn register_error(&self, register: &str) {
+        if let Ok(mut errors) = self.register_errors.lock() {
+            *errors.entry(register.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    /// Ok, or the reason why the daemon is unhealthy
+    pub fn check_health(&self, limits: &HealthLimits) -> std::result::Result<(), String> {
+        let last_poll = self.last_poll_ms.load(Ordering::Relaxed);
+        //the inverter may be asleep at night, only the sinks are checked then
+        let night = self.night.load(Ordering::Relaxed);
+        if !night && last_poll == 0 {
+            return Err("no successful poll yet".into());
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        //a single poll is taking the interval plus the query time
+        let poll_ms = limits.poll_interval.as_millis() as u64 + self.query_time_ms.load(Ordering::Relaxed);
+        let max_age = poll_ms.max(1000) * limits.max_missed_polls as u64;
+        if !night && now.saturating_sub(last_poll) > max_age {
+            return Err(format!("no successful poll for {} secs", now.saturating_sub(last_poll) / 1000));
+        }
+        let backlog = self.sink_backlog.load(Ordering::Relaxed);
+        if backlog > limits.max_sink_backlog {
+            return Err(format!("sink backlog of {} batches", backlog));
+        }
+        Ok(())
+    }
+
+    fn connection_error(&self, error: String) {
+        self.connected.store(false, Ordering::Relaxed);
+        systemd::notify(&format!("STATUS=Reconnecting after error: {}", error));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+        if let Ok(mut last_error) = self.last_error.lock() {
+            *last_error = Some((now, error));
+        }
+    }
+}
+
+/// What is polled while the inverter is expected to be asleep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NightMode {
+    Off,
+    /// every tier, but at most once in the night poll interval
+    Slow,
+    /// only the battery and power meter blocks, at their usual intervals
+    Storage,
+}
+
+impl FromStr for NightMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "off" => Ok(NightMode::Off),
+            "slow" => Ok(NightMode::Slow),
+            "storage" => Ok(NightMode::Storage),
+            other => Err(format!("unknown night mode: {:?}", other)),
+        }
+    }
+}
+
+/// Night mode, driven by the sun position at the configured location
+#[derive(Clone, Debug, PartialEq)]
+pub struct NightSettings {
+    pub lat: f64,
+    pub lon: f64,
+    pub mode: NightMode,
     pub poll_interval_sec: f32,
+    /// the night ends this long before the sunrise and starts this long after the sunset
+    pub margin: Duration,
+}
+
+impl NightSettings {
+    pub fn is_night(&self, time: DateTime<Utc>) -> bool {
+        let margin = chrono::Duration::from_std(self.margin).unwrap_or_else(|_| chrono::Duration::zero());
+        !solar::is_up(self.lat, self.lon, time - margin) && !solar::is_up(self.lat, self.lon, time + margin)
+    }
+
+    /// Start of the next day mode, including the margin
+    pub fn dawn(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let margin = chrono::Duration::from_std(self.margin).unwrap_or_else(|_| chrono::Duration::zero());
+        solar::next_crossing(self.lat, self.lon, time, true).map(|sunrise| sunrise - margin)
+    }
+}
+
+/// Poll settings which can be changed on config reload, without reconnecting
+#[derive(Clone, Debug, PartialEq)]
+pub struct PollSettings {
+    /// interval of the fast tier
+    pub poll_interval_sec: f32,
+    pub poll_interval_normal_sec: f32,
+    pub poll_interval_slow_sec: f32,
+    pub partial: bool,
     pub reconnect_params_threshold: usize,
     pub reconnect_params_wait: f32,
+    /// parameters skipped in the periodic polls
+    pub disabled_params: Vec<String>,
+    /// tiers overriding the defaults of the parameter table
+    pub param_tiers: BTreeMap<String, PollTier>,
+    /// filtering of the periodic values before passing them to the sinks
+    pub deadband: DeadbandSettings,
+    /// None when the night mode is off
+    pub night: Option<NightSettings>,
+    /// evaluated on every poll result
+    pub rules: Vec<Rule>,
+    /// channels of the fault, connection and rule notifications
+    pub notify: NotifySettings,
+    /// set by the poller while the night mode is active
+    pub at_night: bool,
+}
+
+impl PollSettings {
+    pub fn interval(&self, tier: PollTier) -> Duration {
+        let interval = match tier {
+            PollTier::OnConnect => return Duration::MAX,
+            PollTier::Fast => Duration::from_secs_f32(self.poll_interval_sec),
+            PollTier::Normal => Duration::from_secs_f32(self.poll_interval_normal_sec),
+            PollTier::Slow => Duration::from_secs_f32(self.poll_interval_slow_sec),
+        };
+        match self.active_night_mode() {
+            Some(NightMode::Slow) => interval.max(self.night_poll_interval()),
+            _ => interval,
+        }
+    }
+
+    /// Names of the fields differing from `other`, for logging a reload without the values
+    /// (the notification channels and the rule commands may hold credentials)
+    pub fn changed_fields(&self, other: &PollSettings) -> Vec<&'static str> {
+        [
+            ("poll_interval", self.poll_interval_sec != other.poll_interval_sec),
+            ("poll_interval_normal", self.poll_interval_normal_sec != other.poll_interval_normal_sec),
+            ("poll_interval_slow", self.poll_interval_slow_sec != other.poll_interval_slow_sec),
+            ("partial", self.partial != other.partial),
+            ("reconnect_params_threshold", self.reconnect_params_threshold != other.reconnect_params_threshold),
+            ("reconnect_params_wait", self.reconnect_params_wait != other.reconnect_params_wait),
+            ("disabled_params", self.disabled_params != other.disabled_params),
+            ("param_tiers", self.param_tiers != other.param_tiers),
+            ("deadband", self.deadband != other.deadband),
+            ("night", self.night != other.night),
+            ("rules", self.rules != other.rules),
+            ("notify", self.notify != other.notify),
+        ]
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+        .collect()
+    }
+
+    fn active_night_mode(&self) -> Option<NightMode> {
+        self.night.as_ref().filter(|_| self.at_night).map(|n| n.mode)
+    }
+
+    /// How long to wait before reconnecting while the inverter is asleep
+    pub fn night_poll_interval(&self) -> Duration {
+        self.night
+            .as_ref()
+            .map(|n| Duration::from_secs_f32(n.poll_interval_sec))
+            .unwrap_or_default()
+    }
+
+    /// Periodic tiers to be read in this pass, from the time elapsed since their
+    /// last polls (none yet after connecting)
+    pub fn due_tiers(&self, elapsed: &[Option<Duration>; 3]) -> Vec<PollTier> {
+        let due = |slack: Duration| -> Vec<PollTier> {
+            PERIODIC_TIERS
+                .iter()
+                .zip(elapsed)
+                .filter(|(&tier, elapsed)| elapsed.is_none_or(|e| e + slack > self.interval(tier)))
+                .map(|(&tier, _)| tier)
+                .collect()
+        };
+        if due(Duration::ZERO).is_empty() {
+            return vec![];
+        }
+        //group the tiers which would be due closer to this pass than to the next fast one
+        due(self.interval(PollTier::Fast) / 2)
+    }
+
+    /// Whether the parameter of the block is read in a poll of the tier
+    pub fn is_polled(&self, p: &Parameter, block: &ParameterBlock, tier: PollTier) -> bool {
+        let tier_override = self.param_tiers.get(&p.name).copied();
+        if !p.save_to_influx {
+            return false;
+        }
+        if tier == PollTier::OnConnect {
+            return p.initial_read || tier_override == Some(PollTier::OnConnect);
+        }
+        if self.active_night_mode() == Some(NightMode::Storage) && !block.is_storage_or_meter() {
+            return false;
+        }
+        let default = if p.initial_read { PollTier::OnConnect } else { block.tier };
+        tier_override.unwrap_or(default) == tier && !self.disabled_params.contains(&p.name)
+    }
+}
+
+pub struct Sun2000 {
+    pub name: String,
+    pub host_port: String,
+    pub stats: Arc<Sun2000Stats>,
+    pub mode_change_script: Option<ModeChangeScript>,
+    pub dongle_connection: bool,
+    pub sinks: Arc<SinkDispatcher>,
+    pub history: Arc<History>,
+    pub settings: watch::Receiver<PollSettings>,
 }
 
 impl Sun2000 {
@@ -330,13 +771,13 @@ impl Sun2000 {
             ]),
             ParameterBlock::new(vec![
                 Parameter::new("accumulated_yield_energy", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 32106, 2, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             // ParameterBlock::new(vec![
             //         Parameter::new("unknown_time_1", ParamKind::NumberU32(None), 0, None, Some("epoch"), 1, 32110, 2, false, false),
             // ]),
             ParameterBlock::new(vec![
                 Parameter::new("daily_yield_energy", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 32114, 2, false,true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![
                 Parameter::new("storage1_status", ParamKind::NumberI16(None), 0, None, Some("storage_status_enum"), 1, 37000, 1, false, true),
                 Parameter::new("storage1_charge_discharge_power", ParamKind::NumberI32(None), 0, None, Some("W"), 1, 37001, 2, false, true),
@@ -347,21 +788,21 @@ impl Sun2000 {
                 Parameter::new("storage_working_mode", ParamKind::NumberU16(None), 0, None, Some("storage_working_mode_enum"), 1, 37006, 1, false, true),
                 Parameter::new("storage1_rated_charge_power", ParamKind::NumberU32(None), 0, None, Some("W"), 1, 37007, 2, false, true),
                 Parameter::new("storage1_rated_discharge_power", ParamKind::NumberU32(None), 0, None, Some("W"), 1, 37009, 2, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![    
                 Parameter::new("storage1_fault_id", ParamKind::NumberU16(None), 0, None, None, 1, 37014, 1, false, true),
                 Parameter::new("storage1_current_day_charge_capacity", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 37015, 2, false, true),
                 Parameter::new("storage1_current_day_discharge_capacity", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 37017, 2, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![        
                 Parameter::new("storage1_bus_current", ParamKind::NumberI16(None), 0, None, Some("A"), 10, 37021, 1, false, true),
                 Parameter::new("storage1_internal_temperature", ParamKind::NumberI16(None), 0, None, Some("°C"), 10, 37022, 1, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![            
                 Parameter::new("storage1_remaining_charge_discharge_time", ParamKind::NumberU16(None), 0, None, Some("min"), 1, 37025, 1, false, true),                
                 // Parameter::new("storage1_dcdc_version", ParamKind::Text(None), 0, None, None, 1, 37026, 10, true, false),
                 // Parameter::new("storage1_bms_version", ParamKind::Text(None), 0, None, None, 1, 37036, 10, true, false),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![            
                 Parameter::new("storage1_maximum_charge_power", ParamKind::NumberU32(None), 0, None, Some("W"), 1, 37046, 2, true, true),
                 Parameter::new("storage1_maximum_discharge_power", ParamKind::NumberU32(None), 0, None, Some("W"), 1, 37048, 2, true, true),
@@ -372,7 +813,7 @@ impl Sun2000 {
             ParameterBlock::new(vec![                
                 Parameter::new("storage1_total_charge", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 37066, 2, false, true),
                 Parameter::new("storage1_total_discharge", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 37068, 2, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![    
                 Parameter::new("power_meter_status", ParamKind::NumberU16(None), 0, None, None, 1, 37100, 1, false, true),
                 Parameter::new("grid_A_voltage", ParamKind::NumberI32(None), 0, None, Some("V"), 10, 37101, 2, false, true),
@@ -413,7 +854,7 @@ impl Sun2000 {
             ParameterBlock::new(vec![                
                 Parameter::new("storage_current_day_charge_capacity", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 37784, 2, false, true),
                 Parameter::new("storage_current_day_discharge_capacity", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 37786,  2, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![        
                 Parameter::new("storage1_sw_version", ParamKind::Text(None), 0, None, None, 1, 37814, 15, true, true),
             ]),
@@ -424,7 +865,7 @@ impl Sun2000 {
             ParameterBlock::new(vec![                                
                 Parameter::new("storage1_battery1_working_status", ParamKind::NumberU16(None), 0, None, Some("storage_status_enum"), 1, 38228, 1, false, true),
                 Parameter::new("storage1_battery1_soc", ParamKind::NumberU16(None), 0, None, Some("%"), 10, 38229, 1, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![                                                    
                 Parameter::new("storage1_battery1_charge_discharge_power", ParamKind::NumberI32(None), 0, None, Some("kW"), 1, 38233, 2, false, true),
                 Parameter::new("storage1_battery1_voltage", ParamKind::NumberU16(None), 0, None, Some("V"), 10, 38235, 1, false, true),
@@ -433,7 +874,7 @@ impl Sun2000 {
             ParameterBlock::new(vec![                                    
                 Parameter::new("storage1_battery1_total_charge", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 38238, 2, false, true),
                 Parameter::new("storage1_battery1_total_discharge", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 38240,  2, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![                                                        
                 Parameter::new("storage1_battery2_sn", ParamKind::Text(None), 0, None, None, 1, 38242, 10, true, true),
                 Parameter::new("storage1_battery2_sw_version", ParamKind::Text(None), 0, None, None, 1, 38252, 15, true, true),
@@ -441,7 +882,7 @@ impl Sun2000 {
             ParameterBlock::new(vec![                                    
                 Parameter::new("storage1_battery2_working_status", ParamKind::NumberU16(None), 0, None, Some("storage_status_enum"), 1, 38270, 1, false, true),
                 Parameter::new("storage1_battery2_soc", ParamKind::NumberU16(None), 0, None, Some("%"), 10, 38271, 1, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![                                    
                 Parameter::new("storage1_battery2_charge_discharge_power", ParamKind::NumberI32(None), 0, None, Some("kW"), 1, 38275, 2, false, true),
                 Parameter::new("storage1_battery2_voltage", ParamKind::NumberU16(None), 0, None, Some("V"), 10, 38277, 1, false, true),
@@ -450,7 +891,7 @@ impl Sun2000 {
             ParameterBlock::new(vec![                                    
                 Parameter::new("storage1_battery2_total_charge", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 38280, 2, false, true),
                 Parameter::new("storage1_battery2_total_discharge", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 38282,  2, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![                                        
                 Parameter::new("storage1_battery3_sn", ParamKind::Text(None), 0, None, None, 1, 38284, 10, true, true),
                 Parameter::new("storage1_battery3_sw_version", ParamKind::Text(None), 0, None, None, 1, 38294, 15, true, true),
@@ -458,7 +899,7 @@ impl Sun2000 {
             ParameterBlock::new(vec![                                    
                 Parameter::new("storage1_battery3_working_status", ParamKind::NumberU16(None), 0, None, Some("storage_status_enum"), 1, 38312, 1, false, true),
                 Parameter::new("storage1_battery3_soc", ParamKind::NumberU16(None), 0, None, Some("%"), 10, 38313, 1, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![                                    
                 Parameter::new("storage1_battery3_charge_discharge_power", ParamKind::NumberI32(None), 0, None, Some("kW"), 1, 38317, 2, false, true),
                 Parameter::new("storage1_battery3_voltage", ParamKind::NumberU16(None), 0, None, Some("V"), 10, 38319, 1, false, true),
@@ -467,7 +908,7 @@ impl Sun2000 {
             ParameterBlock::new(vec![                                    
                 Parameter::new("storage1_battery3_total_charge", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 38322, 2, false, true),
                 Parameter::new("storage1_battery3_total_discharge", ParamKind::NumberU32(None), 0, None, Some("kWh"), 100, 38324,  2, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![            
                 Parameter::new("storage1_battery1_max_temperature", ParamKind::NumberI16(None), 0, None, Some("°C"), 10, 38452, 1, false, true),
                 Parameter::new("storage1_battery1_min_temperature", ParamKind::NumberI16(None), 0, None, Some("°C"), 10, 38453, 1, false, true),
@@ -475,10 +916,10 @@ impl Sun2000 {
                 Parameter::new("storage1_battery2_min_temperature", ParamKind::NumberI16(None), 0, None, Some("°C"), 10, 38455, 1, false, true),
                 Parameter::new("storage1_battery3_max_temperature", ParamKind::NumberI16(None), 0, None, Some("°C"), 10, 38456, 1, false, true),
                 Parameter::new("storage1_battery3_min_temperature", ParamKind::NumberI16(None), 0, None, Some("°C"), 10, 38457, 1, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![            
                 Parameter::new("system_time", ParamKind::NumberU32(None), 0, None, Some("epoch"), 1, 40000, 2, false, true),
-            ]),
+            ]).with_tier(PollTier::Slow),
             ParameterBlock::new(vec![            
                 Parameter::new("grid_code", ParamKind::NumberU16(None), 0, None, Some("grid_enum"), 1, 42000, 1, true, true),
             ]),
@@ -487,7 +928,7 @@ impl Sun2000 {
             ]),
             ParameterBlock::new(vec![            
                 Parameter::new("storage_working_mode", ParamKind::NumberI16(None), 0, None, Some("storage_working_mode_enum"), 1, 47004, 1, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             // ParameterBlock::new(vec![                                    
             //     Parameter::new("storage_time_of_use_price", ParamKind::NumberI16(None), 0, None, Some("storage_tou_price_enum"), 1, 47027, 1, false, true),
             // ]),
@@ -505,10 +946,10 @@ impl Sun2000 {
                 Parameter::new("storage_forced_charging_and_discharging_period", ParamKind::NumberU16(None), 0, None, Some("min"), 1, 47083, 1, false, true),
                 Parameter::new("storage_forced_charging_and_discharging_power", ParamKind::NumberI32(None), 0, None, Some("min"), 1, 47084, 2, false, true),
                 Parameter::new("storage_working_mode", ParamKind::NumberU16(None), 0, None, Some("working_mode"), 1, 47086, 1, false, true),
-            ]),
+            ]).with_tier(PollTier::Normal),
             ParameterBlock::new(vec![            
                 Parameter::new("active_power_control_mode", ParamKind::NumberU16(None), 0, None, Some("active_power_control_mode_enum"), 1, 47415, 1, false, true),
-            ]),
+            ]).with_tier(PollTier::Slow),
             ParameterBlock::new(vec![            
                 Parameter::new("storage1_battery1_no", ParamKind::NumberU16(None), 0, None, None, 1, 47750, 1, true, true),
                 Parameter::new("storage1_battery2_no", ParamKind::NumberU16(None), 0, None, None, 1, 47751, 1, true, true),
@@ -517,141 +958,46 @@ impl Sun2000 {
         ]
     }
 
-    async fn save_to_influxdb(
-        client: influxdb::Client,
-        thread_name: &String,
-        param: Parameter,
-        tx_influxdb: &Option<Sender<Vec<WriteQuery>>>,
-    ) -> Result<()> {
-        let mut query = Timestamp::Milliseconds(param.time).into_query(&param.name);
-        query = query.add_field("value", param.get_influx_value());
-
-        match tx_influxdb {
-            Some(tx) => {
-                tx.send(vec![query]).await.unwrap();
-            },
-            None => {
-                match client.query(&query).await {
-                    Ok(msg) => {
-                        if !msg.is_empty() {
-                            error!("{}: influxdb write success: {:?}", thread_name, msg);
-                        } else {
-                            debug!("{}: influxdb write success: {:?}", thread_name, msg);
-                        }
-                    }
-                    Err(e) => {
-                        error!("<i>{}</>: influxdb write error: <b>{:?}</>", thread_name, e);
-                    }
-                }        
-            }
-        }
-
+    /// Connects to the inverter, `slave` overrides the Slave ID implied by the connection type
+    pub async fn connect(&self, slave: Option<u8>) -> io::Result<Context> {
+        let socket_addr = self
+            .host_port
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid address {:?}: {}", self.host_port, e)))?;
 
-        Ok(())
-    }
+        let slave = match slave {
+            Some(id) => Slave(id),
+            //USB dongle connection: Slave ID has to be 0x01
+            None if self.dongle_connection => Slave(0x01),
+            //internal wifi: Slave ID has to be 0x00, otherwise the inverter is not responding
+            None => Slave(0x00),
+        };
 
-    async fn save_multiple_to_influxdb(
-        client: influxdb::Client,
-        thread_name: &String,
-        param: &[&Parameter],
-        tx_influxdb: &Option<Sender<Vec<WriteQuery>>>,
-    ) -> Result<()> {
-        let query = param.iter().map(|p| 
-            Timestamp::Milliseconds(p.time).into_query(&p.name).add_field("value", p.get_influx_value())
-        ).collect::<Vec<WriteQuery>>();
-
-        match tx_influxdb {
-            Some(tx) => {
-                tx.send(query).await.unwrap();
-            },
-            None => {
-                match client.query(&query).await {
-                    Ok(msg) => {
-                        if !msg.is_empty() {
-                            error!("{}: influxdb write success: {:?}", thread_name, msg);
-                        } else {
-                            debug!("{}: influxdb write success: {:?}", thread_name, msg);
-                        }
-                    }
-                    Err(e) => {
-                        error!("<i>{}</>: influxdb write error: <b>{:?}</>", thread_name, e);
-                    }
-                }        
-            }
+        match timeout(Duration::from_secs(5), tcp::connect_slave(socket_addr, slave)).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::new(ErrorKind::TimedOut, "connect timeout")),
         }
-
-
-        Ok(())
     }
 
-    async fn save_ms_to_influxdb(
-        client: influxdb::Client,
-        thread_name: &String,
-        ms: u64,
-        param_count: usize,
-        tx_influxdb: &Option<Sender<Vec<WriteQuery>>>,
-    ) -> Result<()> {
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-
-        let mut query = Timestamp::Milliseconds(since_the_epoch).into_query("inverter_query_time");
-        query = query.add_field("value", Type::SignedInteger(ms as i64));
-        query = query.add_field("param_count", param_count as u8);
-
-        match tx_influxdb {
-            Some(tx) => {
-                tx.send(vec![query]).await.unwrap();
-            },
-            None => {
-                match client.query(&query).await {
-                    Ok(msg) => {
-                        if !msg.is_empty() {
-                            error!("{}: influxdb write success: {:?}", thread_name, msg);
-                        } else {
-                            debug!("{}: influxdb write success: {:?}", thread_name, msg);
-                        }
-                    }
-                    Err(e) => {
-                        error!("<i>{}</>: influxdb write error: <b>{:?}</>", thread_name, e);
-                    }
-                }        
-            }
-        }
-
-        Ok(())
-    }
-
-    async fn read_params(
+    /// Reads the parameters of the given tiers, the due blocks are read in a single pass
+    pub async fn read_params(
         &mut self,
         mut ctx: Context,
         parameters: &[ParameterBlock],
-        initial_read: bool,
+        tiers: &[PollTier],
     ) -> io::Result<(Context, Vec<Parameter>)> {
-        // connect to influxdb
-        let client = match &self.influxdb_url {
-            Some(url) => match &self.influxdb_token {
-                Some(token) => Some(Client::new(url, "sun2000").with_token(token)),
-                None => Some(Client::new(url, "sun2000")),
-            },
-            None => None,
-        };
-
         let mut params: Vec<Parameter> = vec![];
         let mut disconnected = false;
         let now = Instant::now();
+        let mut settings = self.settings.borrow().clone();
+        settings.at_night = self.stats.night.load(Ordering::Relaxed);
+        let polled = |p: &Parameter, pb: &ParameterBlock| tiers.iter().any(|&t| settings.is_polled(p, pb, t));
 
-        for pb in parameters.iter().filter(|pb| initial_read || !pb.only_initial_read) {
+        for pb in parameters.iter().filter(|pb| pb.parameters.iter().any(|p| polled(p, pb))) {
             // let pb_start = Instant::now();
 
-            if self.partial {
-                for p in pb.parameters.iter().filter(|s| {
-                    (initial_read && s.initial_read)
-                        || (!initial_read
-                            && (s.save_to_influx))
-                }) {
+            if settings.partial {
+                for p in pb.parameters.iter().filter(|s| polled(s, pb)) {
                     if disconnected {
                         break;
                     }
@@ -679,12 +1025,13 @@ impl Sun2000 {
                                     "<i>{}</i>: read timeout (attempt #{} of {}), register: <green><i>{}</>, error: <b>{}</>",
                                     self.name, attempts, SUN2000_ATTEMPTS_PER_PARAM, p.name, e
                                 );
+                                self.stats.register_error(&p.name);
                                 if attempts == SUN2000_ATTEMPTS_PER_PARAM {
-                                    error!("{}", msg);
+                                    self.log_read_error(&msg, true);
                                     disconnected = true;
                                     break;
                                 } else {
-                                    warn!("{}", msg);
+                                    self.log_read_error(&msg, false);
                                     continue;
                                 };
                             }
@@ -749,16 +1096,7 @@ impl Sun2000 {
                                     p.initial_read,
                                     p.save_to_influx,
                                 );
-                                params.push(param.clone());
-        
-                                //write data to influxdb if configured
-                                if !self.bulk_insert {
-                                    if let Some(c) = client.clone() {
-                                        if !initial_read && p.save_to_influx {
-                                            let _ = Sun2000::save_to_influxdb(c, &self.name, param, &self.tx_influxdb).await;
-                                        }
-                                    }
-                                }
+                                params.push(param);
         
                                 break; //read next parameter
                             }
@@ -767,19 +1105,20 @@ impl Sun2000 {
                                     "<i>{}</i>: read error (attempt #{} of {}), register: <green><i>{}</>, error: <b>{}</>, read time: <b>{:?}</>",
                                     self.name, attempts, SUN2000_ATTEMPTS_PER_PARAM, p.name, e, read_time
                                 );
+                                self.stats.register_error(&p.name);
                                 match e.kind() {
                                     ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
-                                        error!("{}", msg);
+                                        self.log_read_error(&msg, true);
                                         disconnected = true;
                                         break;
                                     }
                                     _ => {
                                         if attempts == SUN2000_ATTEMPTS_PER_PARAM {
-                                            error!("{}", msg);
+                                            self.log_read_error(&msg, true);
                                             disconnected = true;
                                             break;
                                         } else {
-                                            warn!("{}", msg);
+                                            self.log_read_error(&msg, false);
                                             continue;
                                         };
                                     }
@@ -815,12 +1154,13 @@ impl Sun2000 {
                                 "<i>{}</i>: read timeout (attempt #{} of {}), register: <green><i>{}</>, error: <b>{}</>",
                                 self.name, attempts, SUN2000_ATTEMPTS_PER_PARAM, pb.reg_address, e
                             );
+                            self.stats.register_error(&pb.reg_address.to_string());
                             if attempts == SUN2000_ATTEMPTS_PER_PARAM {
-                                error!("{}", msg);
+                                self.log_read_error(&msg, true);
                                 disconnected = true;
                                 break;
                             } else {
-                                warn!("{}", msg);
+                                self.log_read_error(&msg, false);
                                 continue;
                             };
                         }
@@ -835,12 +1175,10 @@ impl Sun2000 {
                                 );
                             }
 
-                            for p in pb.parameters.iter().filter(|s| {
-                                !initial_read || s.initial_read
-                            }) {
+                            for p in pb.parameters.iter() {
                                 let data = &remaining_data[0..(p.len as usize)];
                                 remaining_data = &remaining_data[(p.len as usize)..];
-                                if !p.save_to_influx {
+                                if !polled(p, pb) {
                                     continue;
                                 } 
 
@@ -895,16 +1233,7 @@ impl Sun2000 {
                                     p.initial_read,
                                     p.save_to_influx,
                                 );
-                                params.push(param.clone());
-        
-                                //write data to influxdb if configured
-                                if !self.bulk_insert {
-                                    if let Some(c) = client.clone() {
-                                        if !initial_read && p.save_to_influx {
-                                            let _ = Sun2000::save_to_influxdb(c, &self.name, param, &self.tx_influxdb).await;
-                                        }
-                                    }
-                                }
+                                params.push(param);
                             }
                         }
                         Err(e) => {
@@ -912,19 +1241,20 @@ impl Sun2000 {
                                 "<i>{}</i>: read error (attempt #{} of {}), register: <green><i>{}</>, error: <b>{}</>, read time: <b>{:?}</>",
                                 self.name, attempts, SUN2000_ATTEMPTS_PER_PARAM, pb.reg_address, e, read_time
                             );
+                            self.stats.register_error(&pb.reg_address.to_string());
                             match e.kind() {
                                 ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
-                                    error!("{}", msg);
+                                    self.log_read_error(&msg, true);
                                     disconnected = true;
                                     break;
                                 }
                                 _ => {
                                     if attempts == SUN2000_ATTEMPTS_PER_PARAM {
-                                        error!("{}", msg);
+                                        self.log_read_error(&msg, true);
                                         disconnected = true;
                                         break;
                                     } else {
-                                        warn!("{}", msg);
+                                        self.log_read_error(&msg, false);
                                         continue;
                                     };
                                 }
@@ -948,6 +1278,7 @@ impl Sun2000 {
 
         let elapsed = now.elapsed();
         let ms = (elapsed.as_secs() * 1_000) + elapsed.subsec_millis() as u64;
+        self.stats.query_time_ms.store(ms, Ordering::Relaxed);
         debug!(
             "{}: read {} parameters [⏱️ {} ms]",
             self.name,
@@ -955,15 +1286,6 @@ impl Sun2000 {
             ms
         );
 
-        if self.bulk_insert {
-            if let Some(c) = client.clone() {
-                let _ = Sun2000::save_multiple_to_influxdb(c, &self.name, 
-                    &params.iter().filter(|p| !initial_read && p.save_to_influx).collect::<Vec<_>>()[..], 
-                    &self.tx_influxdb
-                ).await;
-            }
-        }
-        
         // let elapsed2 = now.elapsed();
         // let ms2 = (elapsed2.as_secs() * 1_000) + elapsed2.subsec_millis() as u64;
         // info!(
@@ -972,11 +1294,6 @@ impl Sun2000 {
         //     ms2,
         // );
 
-        //save query time
-        if let Some(c) = client {
-            let _ = Sun2000::save_ms_to_influxdb(c, &self.name, ms, params.len(), &self.tx_influxdb).await;
-        }
-
         if disconnected {
             Err(Error::from(ErrorKind::ConnectionAborted))
         } else {
@@ -984,7 +1301,102 @@ impl Sun2000 {
         }
     }
 
-    pub fn attribute_parser(&self, mut a: Vec<u8>) -> Result<()> {
+    /// Read errors are expected while the inverter is asleep, they are logged at debug level then
+    fn log_read_error(&self, msg: &str, last_attempt: bool) {
+        if self.stats.night.load(Ordering::Relaxed) {
+            debug!("{}", msg);
+        } else if last_attempt {
+            error!("{}", msg);
+        } else {
+            warn!("{}", msg);
+        }
+    }
+
+    /// Switches the night mode on and off with the sun, returns whether it is active
+    fn update_night(&self, settings: &PollSettings) -> bool {
+        let now = Utc::now();
+        let night = settings.night.as_ref().is_some_and(|n| n.is_night(now));
+        if self.stats.night.swap(night, Ordering::Relaxed) != night {
+            if night {
+                let dawn = settings.night.as_ref().and_then(|n| n.dawn(now));
+                info!(
+                    "<i>{}</>: 🌙 night mode, until <b>{}</>",
+                    self.name,
+                    dawn.map(|t| t.with_timezone(&Local).format("%F %H:%M").to_string()).unwrap_or_else(|| "the polar night ends".into())
+                );
+            } else {
+                info!("<i>{}</>: ☀️ day mode", self.name);
+            }
+        }
+        night
+    }
+
+    /// Pings the systemd watchdog while the night mode is active: the polls are rare
+    /// or failing then, but the daemon is fine
+    fn night_watchdog(&self) {
+        if self.stats.night.load(Ordering::Relaxed) {
+            systemd::notify("WATCHDOG=1\nSTATUS=Night mode");
+        }
+    }
+
+    /// Waits before reconnecting: a while, or at night up to the night poll interval,
+    /// but no longer than the night lasts
+    async fn reconnect_wait(&self, settings: &PollSettings, cancel_flag: &AtomicBool) {
+        if !self.stats.night.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            return;
+        }
+        let start = Instant::now();
+        while start.elapsed() < settings.night_poll_interval()
+            && !cancel_flag.load(Ordering::SeqCst)
+            && self.update_night(settings)
+        {
+            self.night_watchdog();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    fn dispatch(
+        &self,
+        device: &Arc<DeviceInfo>,
+        parameters: Vec<Parameter>,
+        initial_read: bool,
+        deadband: &mut Deadband,
+        events: Vec<RuleEvent>,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+        let filtered = if initial_read {
+            None
+        } else {
+            //the history and the aggregating sinks are getting every sample,
+            //the others only the ones passing the deadband
+            self.history.record(timestamp, &parameters);
+            let settings = &self.settings.borrow().deadband;
+            if settings.is_off() {
+                None
+            } else {
+                Some(deadband.filter(settings, parameters.clone()))
+            }
+        };
+        let batch = ReadingBatch {
+            device: device.clone(),
+            timestamp,
+            parameters,
+            metadata: BatchMetadata {
+                initial_read,
+                query_time_ms: self.stats.query_time_ms.load(Ordering::Relaxed),
+            },
+            events,
+        };
+        self.sinks.dispatch(&self.name, batch, filtered);
+        self.stats.sink_backlog.store(self.sinks.backlog() as u64, Ordering::Relaxed);
+    }
+
+    pub fn attribute_parser(&self, mut a: Vec<u8>) -> Result<Vec<(String, String)>> {
+        let mut attributes = vec![];
         //search for 'Description about the first device' (0x88)
         if let Some(index) = a.iter().position(|&x| x == 0x88) {
             //strip beginning bytes up to descriptor start
@@ -1021,70 +1433,71 @@ impl Sun2000 {
                     get_attribute_name(id.unwrap()),
                     val.unwrap()
                 );
+                attributes.push((id.unwrap().to_string(), val.unwrap().to_string()));
             }
         }
-        Ok(())
+        Ok(attributes)
     }
 
     #[rustfmt::skip]
     pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
         info!("<i>{}</>: Starting task", self.name);
-        let mut poll_interval = Instant::now();
         let mut stats_interval = Instant::now();
         let mut terminated = false;
+        //the last written values, modes and rule states are kept over the reconnects
+        let mut deadband = Deadband::default();
+        let mut rules = RuleEngine::new(self.name.clone());
+        let mut notifier = Notifier::new(self.name.clone());
+        let mut mode_change = self.mode_change_script.clone().map(|script| ModeChange::new(self.name.clone(), script));
 
         'mainloop: loop  {
             if terminated || worker_cancel_flag.load(Ordering::SeqCst) {
                 break;
             }
 
-            let socket_addr = self.host_port.parse().unwrap();
-
-            let slave = if self.dongle_connection {
-                //USB dongle connection: Slave ID has to be 0x01
-                Slave(0x01)
+            let settings = self.settings.borrow().clone();
+            let night = self.update_night(&settings);
+            notifier.check_connection(&settings.notify, night);
+            if night {
+                debug!("<i>{}</>: connecting to <u>{}</>...", self.name, self.host_port);
             } else {
-                //internal wifi: Slave ID has to be 0x00, otherwise the inverter is not responding
-                Slave(0x00)
-            };
-
-            info!("<i>{}</>: connecting to <u>{}</>...", self.name, self.host_port);
-            let retval = tcp::connect_slave(socket_addr, slave);
-            let conn= match timeout(Duration::from_secs(5), retval).await {
-                Ok(res) => res,
-                Err(e) => {
-                    error!("<i>{}</>: connect timeout: <b>{}</>", self.name, e);
-                    tokio::time::sleep(Duration::from_secs(2)).await;
-                    continue;
-                }
-            };
-
-            match conn {
+                info!("<i>{}</>: connecting to <u>{}</>...", self.name, self.host_port);
+            }
+            match self.connect(None).await {
                 Ok(mut ctx) => {
                     info!("<i>{}</>: connected successfully", self.name);
+                    self.stats.connected.store(true, Ordering::Relaxed);
+                    systemd::notify(&format!("STATUS=Connected to {}", self.host_port));
                     //initial parameters table
                     let parameters = Sun2000::param_table();
                     tokio::time::sleep(Duration::from_secs(2)).await;
 
                     //obtaining all parameters from inverter
-                    match self.read_params(ctx, &parameters, true).await {
+                    match self.read_params(ctx, &parameters, &[PollTier::OnConnect]).await {
                         Ok((new_ctx, params)) => {
                             ctx = new_ctx;
+                            let mut device_info = DeviceInfo {
+                                name: self.name.clone(),
+                                ..Default::default()
+                            };
                             for p in &params {
                                 match &p.value {
                                     ParamKind::Text(_) => match p.name.as_ref() {
                                         "model_name" => {
                                             info!("<i>{}</>: model name: <b><cyan>{}</>", self.name, &p.get_text_value());
+                                            device_info.model_name = Some(p.get_text_value());
                                         }
                                         "serial_number" => {
                                             info!("<i>{}</>: serial number: <b><cyan>{}</>", self.name, &p.get_text_value());
+                                            device_info.serial_number = Some(p.get_text_value());
                                         }
                                         "product_number" => {
                                             info!("<i>{}</>: product number: <b><cyan>{}</>", self.name, &p.get_text_value());
+                                            device_info.product_number = Some(p.get_text_value());
                                         }
                                         _ => {}
                                     },
-                                    ParamKind::NumberU32(_) => if p.name == "rated_power" {
+                                    ParamKind::NumberU32(_) if p.name == "rated_power" => {
                                         info!(
                                             "<i>{}</>: rated power: <b><cyan>{} {}</>",
                                             self.name,
@@ -1104,7 +1517,13 @@ impl Sun2000 {
                                     Ok(rsp) => match rsp {
                                         Response::Custom(f, rsp) => {
                                             debug!("<i>{}</>: Result for function {} is '{:?}'", self.name, f, rsp);
-                                            let _ = self.attribute_parser(rsp);
+                                            if let Ok(attributes) = self.attribute_parser(rsp) {
+                                                //attribute 2 is the device software version
+                                                device_info.software_version = attributes
+                                                    .into_iter()
+                                                    .find(|(id, _)| id == "2")
+                                                    .map(|(_, val)| val);
+                                            }
                                         }
                                         _ => {
                                             error!("<i>{}</>: unexpected Reading Device Identifiers (0x2B) result", self.name);
@@ -1119,7 +1538,16 @@ impl Sun2000 {
                                 }
                             }
         
+                            let device_info = Arc::new(device_info);
+                            let events = rules.evaluate(&settings.rules, &device_info, &params);
+                            notifier.check(&settings.notify, &device_info, &params);
+                            notifier.rule_events(&settings.notify, &events);
+                            self.dispatch(&device_info, params, true, &mut deadband, events);
+
                             let mut daily_yield_energy: Option<u32> = None;
+                            //last poll of every periodic tier, all are due after connecting
+                            let mut last_polls: [Option<Instant>; 3] = [None; 3];
+                            let mut night_watchdog = Instant::now();
                             loop {
                                 if worker_cancel_flag.load(Ordering::SeqCst) {
                                     debug!("<i>{}</>: Got terminate signal from main", self.name);
@@ -1133,24 +1561,40 @@ impl Sun2000 {
                                     stats_interval = Instant::now();
                                     info!(
                                         "<i>{}</>: 📊 inverter query statistics: ok: <b>{}</>, errors: <b>{}</>, daily energy yield: <b>{:.1} kWh</>",
-                                        self.name, self.poll_ok, self.poll_errors,
+                                        self.name, self.stats.poll_ok.load(Ordering::Relaxed), self.stats.poll_errors.load(Ordering::Relaxed),
                                         daily_yield_energy.unwrap_or_default() as f64 / 100.0,
                                     );
+                                    self.sinks.log_stats(&self.name);
         
                                     if terminated {
                                         break;
                                     }
                                 }
         
-                                if poll_interval.elapsed()
-                                    > Duration::from_secs_f32(self.poll_interval_sec)
-                                {
-                                    poll_interval = Instant::now();
+                                //the settings may be changed by a config reload
+                                let mut settings = self.settings.borrow_and_update().clone();
+                                settings.at_night = self.update_night(&settings);
+                                if night_watchdog.elapsed() > Duration::from_secs(SUN2000_NIGHT_WATCHDOG_SECS) {
+                                    night_watchdog = Instant::now();
+                                    self.night_watchdog();
+                                }
+                                let due = settings.due_tiers(&last_polls.map(|last| last.map(|t| t.elapsed())));
+                                if !due.is_empty() {
+                                    let poll_start = Instant::now();
+                                    for (tier, last) in PERIODIC_TIERS.iter().zip(last_polls.iter_mut()) {
+                                        if due.contains(tier) {
+                                            *last = Some(poll_start);
+                                        }
+                                    }
+                                    let expected: usize = parameters
+                                        .iter()
+                                        .map(|pb| pb.parameters.iter().filter(|p| due.iter().any(|&t| settings.is_polled(p, pb, t))).count())
+                                        .sum();
                                     // let mut active_power: Option<i32> = None;
         
                                     
-                                    //obtaining all parameters from inverter
-                                    match self.read_params(ctx, &parameters, false).await {
+                                    //obtaining the parameters of all due tiers from inverter
+                                    match self.read_params(ctx, &parameters, &due).await {
                                         Ok((new_ctx, params)) => {
                                             ctx = new_ctx;
                                             for p in &params {
@@ -1159,10 +1603,13 @@ impl Sun2000 {
                                             
 
                                             // let param_count = parameters.iter().map(|x| x.parameters.iter()).flatten().filter(|s| (s.save_to_influx && !s.initial_read)).count();
-                                            if params.len() <= self.reconnect_params_threshold {
-                                                error!("<i>{}</>: reconnection because the number of obtained parameters is too low ({})", self.name, params.len());
+                                            if params.len() <= settings.reconnect_params_threshold && expected > settings.reconnect_params_threshold {
+                                                self.log_read_error(&format!("<i>{}</>: reconnection because the number of obtained parameters is too low ({})", self.name, params.len()), true);
+                                                self.stats.connection_error(format!("too few parameters obtained: {}", params.len()));
+                                                self.stats.poll_errors.fetch_add(1, Ordering::Relaxed);
+                                                self.stats.reconnects.fetch_add(1, Ordering::Relaxed);
 
-                                                tokio::time::sleep(Duration::from_secs_f32(self.reconnect_params_wait)).await;                
+                                                tokio::time::sleep(Duration::from_secs_f32(settings.reconnect_params_wait)).await;                
                                                 continue 'mainloop;    
                                             }
                                             // if params.len() != param_count {
@@ -1173,6 +1620,23 @@ impl Sun2000 {
                                             //     self.poll_ok += 1;
                                             // }
                 
+                                            self.stats.poll_ok.fetch_add(1, Ordering::Relaxed);
+                                            if let Some(time) = params.iter().map(|p| p.time).max() {
+                                                self.stats.last_poll_ms.store(time as u64, Ordering::Relaxed);
+                                            }
+                                            let events = rules.evaluate(&settings.rules, &device_info, &params);
+                                            notifier.check(&settings.notify, &device_info, &params);
+                                            notifier.rule_events(&settings.notify, &events);
+                                            self.dispatch(&device_info, params.clone(), false, &mut deadband, events);
+                                            if let Some(mode_change) = &mut mode_change {
+                                                mode_change.check(&params);
+                                            }
+                                            systemd::notify(&format!(
+                                                "WATCHDOG=1\nSTATUS=Polling, ok: {}, errors: {}",
+                                                self.stats.poll_ok.load(Ordering::Relaxed),
+                                                self.stats.poll_errors.load(Ordering::Relaxed),
+                                            ));
+
                                             //process obtained parameters
                                             debug!("Query complete, dump results:");
                                             for p in &params {
@@ -1186,8 +1650,11 @@ impl Sun2000 {
                                             }
                                         }, 
                                         Err(err) => {
-                                            error!("<i>{}</>: error: <b>{}</>", self.name, err);
-                                            tokio::time::sleep(Duration::from_secs(2)).await;                
+                                            self.log_read_error(&format!("<i>{}</>: error: <b>{}</>", self.name, err), true);
+                                            self.stats.connection_error(err.to_string());
+                                            self.stats.poll_errors.fetch_add(1, Ordering::Relaxed);
+                                            self.stats.reconnects.fetch_add(1, Ordering::Relaxed);
+                                            self.reconnect_wait(&settings, &worker_cancel_flag).await;
                                             continue 'mainloop;
                                         }
                                     }
@@ -1197,21 +1664,119 @@ impl Sun2000 {
                             }  
                         },
                         Err(err) => {
-                            error!("<i>{}</>: error: <b>{}</>", self.name, err);
-                            tokio::time::sleep(Duration::from_secs(2)).await;                
+                            self.log_read_error(&format!("<i>{}</>: error: <b>{}</>", self.name, err), true);
+                            self.stats.connection_error(err.to_string());
+                            self.reconnect_wait(&settings, &worker_cancel_flag).await;
                             continue;
                         }
                     };
                     
                 }
                 Err(e) => {
-                    error!("<i>{}</>: connection error: <b>{}</>", self.name, e);
-                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    self.log_read_error(&format!("<i>{}</>: connection error: <b>{}</>", self.name, e), true);
+                    self.stats.connection_error(format!("connection error: {}", e));
+                    self.reconnect_wait(&settings, &worker_cancel_flag).await;
                 }
             }
         }
 
+        self.stats.connected.store(false, Ordering::Relaxed);
         info!("{}: task stopped", self.name);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::daemon::poll_settings;
+
+    fn settings() -> PollSettings {
+        //10, 60 and 300 secs
+        let mut settings = poll_settings(&Config::default());
+        settings.poll_interval_normal_sec = 60.0;
+        settings.poll_interval_slow_sec = 300.0;
+        settings
+    }
+
+    fn secs(secs: u64) -> Option<Duration> {
+        Some(Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn due_tiers() {
+        let settings = settings();
+        //everything after connecting
+        assert_eq!(settings.due_tiers(&[None; 3]), PERIODIC_TIERS.to_vec());
+        assert!(settings.due_tiers(&[secs(9), secs(9), secs(9)]).is_empty());
+        assert_eq!(settings.due_tiers(&[secs(11), secs(40), secs(40)]), vec![PollTier::Fast]);
+        //normal would be due in 4 secs, before the next fast poll
+        assert_eq!(settings.due_tiers(&[secs(11), secs(56), secs(40)]), vec![PollTier::Fast, PollTier::Normal]);
+        assert_eq!(settings.due_tiers(&[secs(11), secs(54), secs(296)]), vec![PollTier::Fast, PollTier::Slow]);
+        //not grouped when only a slower tier is due
+        assert_eq!(settings.due_tiers(&[secs(6), secs(61), secs(40)]), vec![PollTier::Fast, PollTier::Normal]);
+        assert_eq!(settings.due_tiers(&[secs(4), secs(61), secs(40)]), vec![PollTier::Normal]);
+
+        //the night slow mode stretches all the intervals
+        let mut settings = settings;
+        settings.night = Some(NightSettings {
+            lat: 52.0,
+            lon: 21.0,
+            mode: NightMode::Slow,
+            poll_interval_sec: 120.0,
+            margin: Duration::ZERO,
+        });
+        settings.at_night = true;
+        assert!(settings.due_tiers(&[secs(61), secs(61), secs(61)]).is_empty());
+        assert_eq!(settings.due_tiers(&[secs(121), secs(121), secs(61)]), vec![PollTier::Fast, PollTier::Normal]);
+    }
+
+    #[test]
+    fn is_polled() {
+        let table = Sun2000::param_table();
+        let find = |name: &str| -> (&ParameterBlock, &Parameter) {
+            table
+                .iter()
+                .find_map(|pb| pb.parameters.iter().find(|p| p.name == name).map(|p| (pb, p)))
+                .unwrap()
+        };
+        let tiers = |settings: &PollSettings, name: &str| -> Vec<PollTier> {
+            let (pb, p) = find(name);
+            [PollTier::OnConnect, PollTier::Fast, PollTier::Normal, PollTier::Slow]
+                .iter()
+                .copied()
+                .filter(|&tier| settings.is_polled(p, pb, tier))
+                .collect()
+        };
+
+        let mut settings = settings();
+        //only once, never in the periodic polls
+        assert_eq!(tiers(&settings, "model_name"), vec![PollTier::OnConnect]);
+        assert_eq!(tiers(&settings, "active_power"), vec![PollTier::Fast]);
+
+        settings.param_tiers.insert("active_power".into(), PollTier::Slow);
+        settings.param_tiers.insert("internal_temperature".into(), PollTier::OnConnect);
+        assert_eq!(tiers(&settings, "active_power"), vec![PollTier::Slow]);
+        assert_eq!(tiers(&settings, "internal_temperature"), vec![PollTier::OnConnect]);
+        //the identity is still read after connecting when moved to a periodic tier
+        settings.param_tiers.insert("model_name".into(), PollTier::Fast);
+        assert_eq!(tiers(&settings, "model_name"), vec![PollTier::OnConnect, PollTier::Fast]);
+
+        settings.disabled_params.push("active_power".into());
+        assert!(tiers(&settings, "active_power").is_empty());
+
+        //only the battery and the meter while the inverter is asleep
+        settings.night = Some(NightSettings {
+            lat: 52.0,
+            lon: 21.0,
+            mode: NightMode::Storage,
+            poll_interval_sec: 120.0,
+            margin: Duration::ZERO,
+        });
+        settings.at_night = true;
+        assert_eq!(tiers(&settings, "storage1_current_day_charge_capacity"), vec![PollTier::Normal]);
+        assert_eq!(tiers(&settings, "internal_temperature"), vec![PollTier::OnConnect]);
+        assert!(tiers(&settings, "input_power").is_empty());
+    }
+}
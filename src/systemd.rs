@@ -0,0 +1,72 @@
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+use simplelog::*;
+
+/// Sends the state (eg. `READY=1`, `WATCHDOG=1`, `STATUS=...`) to the service manager,
+/// see sd_notify(3). Does nothing when not started by systemd (no `NOTIFY_SOCKET`).
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = send(&path.to_string_lossy(), state) {
+        debug!("sd_notify {:?} error: {}", state, e);
+    }
+}
+
+/// Sends the state to the socket path, `@` is starting an abstract socket name
+fn send(path: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        //abstract namespace socket
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::linux::net::SocketAddrExt;
+    use std::time::Duration;
+
+    fn received(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn socket_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let path = path.to_str().unwrap();
+        send(path, "READY=1").unwrap();
+        send(path, "WATCHDOG=1\nSTATUS=Polling").unwrap();
+        assert_eq!(received(&socket), "READY=1");
+        assert_eq!(received(&socket), "WATCHDOG=1\nSTATUS=Polling");
+
+        assert!(send(dir.path().join("missing").to_str().unwrap(), "READY=1").is_err());
+    }
+
+    #[test]
+    fn abstract_socket() {
+        let name = format!("hard-test-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        send(&format!("@{}", name), "STOPPING=1").unwrap();
+        assert_eq!(received(&socket), "STOPPING=1");
+    }
+}
@@ -0,0 +1,322 @@
+//! Local stand-ins of the servers which the sinks and the notifications are talking to
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::HeaderMap;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
+
+pub const TEST_TIMEOUT_SECS: u64 = 5; //max wait for the stand-in to receive something
+
+/// Request received by the `http_server`
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    /// path with the query
+    pub uri: String,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+/// Starts a HTTP server answering every request with the status and the body,
+/// the received requests are coming from the returned channel
+pub fn http_server(status: u16, response: &'static str) -> (SocketAddr, UnboundedReceiver<HttpRequest>) {
+    http_server_with_headers(status, &[], response)
+}
+
+pub fn http_server_with_headers(
+    status: u16,
+    headers: &'static [(&'static str, &'static str)],
+    response: &'static str,
+) -> (SocketAddr, UnboundedReceiver<HttpRequest>) {
+    let (tx, rx) = unbounded_channel();
+    let make_service = make_service_fn(move |_| {
+        let tx = tx.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let tx = tx.clone();
+                async move {
+                    let (parts, body) = request.into_parts();
+                    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+                    let _ = tx.send(HttpRequest {
+                        method: parts.method.to_string(),
+                        uri: parts.uri.to_string(),
+                        headers: parts.headers,
+                        body: String::from_utf8_lossy(&body).into_owned(),
+                    });
+                    let mut builder = Response::builder().status(status);
+                    for (name, value) in headers {
+                        builder = builder.header(*name, *value);
+                    }
+                    Ok::<_, Infallible>(builder.body(Body::from(response)).unwrap())
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let address = server.local_addr();
+    tokio::spawn(server);
+    (address, rx)
+}
+
+/// Next item received by a stand-in, panics when nothing is coming
+pub async fn next<T>(rx: &mut UnboundedReceiver<T>) -> T {
+    timeout(Duration::from_secs(TEST_TIMEOUT_SECS), rx.recv())
+        .await
+        .expect("nothing received")
+        .expect("stand-in stopped")
+}
+
+/// Packet received by the `MqttBroker`
+#[derive(Debug, PartialEq)]
+pub enum MqttPacket {
+    Connect {
+        client_id: String,
+        username: Option<String>,
+        password: Option<String>,
+        /// topic and payload of the last will
+        will: Option<(String, String)>,
+    },
+    Publish {
+        topic: String,
+        payload: String,
+        qos: u8,
+        retain: bool,
+    },
+    Subscribe {
+        topic: String,
+    },
+    Disconnect,
+}
+
+/// Minimal MQTT 3.1.1 broker serving a single client at a time
+pub struct MqttBroker {
+    pub address: SocketAddr,
+    /// packets from the client
+    pub rx: UnboundedReceiver<MqttPacket>,
+    /// topics and payloads published to the client
+    pub tx: UnboundedSender<(String, String)>,
+}
+
+/// Reads the length prefixed string, moving the slice past it
+fn mqtt_string(data: &mut &[u8]) -> String {
+    let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    let s = String::from_utf8_lossy(&data[2..2 + len]).into_owned();
+    *data = &data[2 + len..];
+    s
+}
+
+fn mqtt_packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        packet.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+async fn mqtt_read(stream: &mut OwnedReadHalf) -> std::io::Result<(u8, Vec<u8>)> {
+    let kind = stream.read_u8().await?;
+    let (mut len, mut shift) = (0usize, 0);
+    loop {
+        let byte = stream.read_u8().await?;
+        len += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    Ok((kind, body))
+}
+
+async fn mqtt_session(
+    stream: TcpStream,
+    packets: &UnboundedSender<MqttPacket>,
+    outgoing: &mut UnboundedReceiver<(String, String)>,
+) -> std::io::Result<()> {
+    let (mut reader, mut stream) = stream.into_split();
+    //reading in its own task, an unfinished read would be lost by select!
+    let (tx_incoming, mut incoming) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(packet) = mqtt_read(&mut reader).await {
+            if tx_incoming.send(packet).is_err() {
+                break;
+            }
+        }
+    });
+    loop {
+        let (kind, body) = tokio::select! {
+            packet = incoming.recv() => match packet {
+                Some(packet) => packet,
+                None => return Ok(()),
+            },
+            Some((topic, payload)) = outgoing.recv() => {
+                let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+                body.extend_from_slice(topic.as_bytes());
+                body.extend_from_slice(payload.as_bytes());
+                stream.write_all(&mqtt_packet(0x30, &body)).await?;
+                continue;
+            }
+        };
+        let mut data = &body[..];
+        let packet = match kind >> 4 {
+            1 => {
+                let _protocol = mqtt_string(&mut data);
+                let (flags, rest) = (data[1], &data[4..]);
+                data = rest;
+                let client_id = mqtt_string(&mut data);
+                let will = match flags & 0x04 {
+                    0 => None,
+                    _ => Some((mqtt_string(&mut data), mqtt_string(&mut data))),
+                };
+                let username = (flags & 0x80 != 0).then(|| mqtt_string(&mut data));
+                let password = (flags & 0x40 != 0).then(|| mqtt_string(&mut data));
+                stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await?;
+                MqttPacket::Connect { client_id, username, password, will }
+            }
+            3 => {
+                let qos = (kind >> 1) & 0x03;
+                let topic = mqtt_string(&mut data);
+                if qos > 0 {
+                    //PUBACK or PUBREC, the PUBREL is answered below
+                    let reply = if qos == 1 { 0x40 } else { 0x50 };
+                    stream.write_all(&[reply, 0x02, data[0], data[1]]).await?;
+                    data = &data[2..];
+                }
+                MqttPacket::Publish {
+                    topic,
+                    payload: String::from_utf8_lossy(data).into_owned(),
+                    qos,
+                    retain: kind & 0x01 != 0,
+                }
+            }
+            6 => {
+                stream.write_all(&[0x70, 0x02, data[0], data[1]]).await?;
+                continue;
+            }
+            8 => {
+                let id = [data[0], data[1]];
+                data = &data[2..];
+                let topic = mqtt_string(&mut data);
+                stream.write_all(&[0x90, 0x03, id[0], id[1], data[0]]).await?;
+                MqttPacket::Subscribe { topic }
+            }
+            12 => {
+                stream.write_all(&[0xd0, 0x00]).await?;
+                continue;
+            }
+            14 => {
+                let _ = packets.send(MqttPacket::Disconnect);
+                return Ok(());
+            }
+            _ => continue,
+        };
+        let _ = packets.send(packet);
+    }
+}
+
+impl MqttBroker {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (packets, rx) = unbounded_channel();
+        let (tx, mut outgoing) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = mqtt_session(stream, &packets, &mut outgoing).await;
+            }
+        });
+        MqttBroker { address, rx, tx }
+    }
+}
+
+/// Mail received by the `smtp_server`
+#[derive(Debug)]
+pub struct SmtpMail {
+    /// commands of the session, without the data
+    pub commands: Vec<String>,
+    /// the data as sent, ending with the `.` line
+    pub data: String,
+}
+
+async fn smtp_session(stream: TcpStream, password: &str) -> std::io::Result<SmtpMail> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut mail = SmtpMail {
+        commands: vec![],
+        data: String::new(),
+    };
+    writer.write_all(b"220 localhost ESMTP test\r\n").await?;
+    while let Some(command) = lines.next_line().await? {
+        mail.commands.push(command.clone());
+        let reply = match command.split(' ').next().unwrap_or_default() {
+            "EHLO" => "250-localhost\r\n250 AUTH PLAIN\r\n".to_string(),
+            "AUTH" => {
+                let credentials = STANDARD.encode(format!("\0hard\0{}", password));
+                if command == format!("AUTH PLAIN {}", credentials) {
+                    "235 2.7.0 accepted\r\n".into()
+                } else {
+                    "535 5.7.8 authentication failed\r\n".into()
+                }
+            }
+            "MAIL" | "RCPT" => "250 2.1.0 ok\r\n".into(),
+            "DATA" => {
+                writer.write_all(b"354 go ahead\r\n").await?;
+                while let Some(line) = lines.next_line().await? {
+                    mail.data.push_str(&line);
+                    mail.data.push('\n');
+                    if line == "." {
+                        break;
+                    }
+                }
+                "250 2.0.0 queued\r\n".into()
+            }
+            "QUIT" => {
+                writer.write_all(b"221 bye\r\n").await?;
+                break;
+            }
+            _ => "502 5.5.2 unknown command\r\n".into(),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(mail)
+}
+
+/// Starts a plain text SMTP server accepting the `hard` user with the password,
+/// the received mails are coming from the returned channel
+pub async fn smtp_server(password: &'static str) -> (SocketAddr, UnboundedReceiver<SmtpMail>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, rx) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            if let Ok(mail) = smtp_session(stream, password).await {
+                let _ = tx.send(mail);
+            }
+        }
+    });
+    (address, rx)
+}
@@ -0,0 +1,435 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::sink::{ReadingBatch, Result, Sink};
+use crate::sun2000::{DeviceInfo, Parameter};
+use async_trait::async_trait;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde_json::json;
+use simplelog::*;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+pub const TSDB_RECONNECT_INTERVAL_SECS: u64 = 10;
+pub const TSDB_TIMEOUT_SECS: u64 = 5;
+pub const TSDB_UDP_MAX_DATAGRAM: usize = 1400; //stay below the common MTU
+pub const OPENTSDB_HTTP_BATCH: usize = 50; //datapoints per /api/put request
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+    Http,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "tcp" => Ok(Transport::Tcp),
+            "udp" => Ok(Transport::Udp),
+            "http" => Ok(Transport::Http),
+            other => Err(format!("unknown transport: {:?}", other)),
+        }
+    }
+}
+
+/// Metric path / tag safe name
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_') { c } else { '_' })
+        .collect()
+}
+
+/// Numeric parameters only, the text ones cannot be stored in these databases
+fn numeric(batch: &ReadingBatch) -> impl Iterator<Item = (&Parameter, f64)> {
+    batch
+        .parameters
+        .iter()
+        .filter(|p| p.save_to_influx && p.unit != Some("epoch"))
+        .filter_map(|p| p.get_float_value().map(|v| (p, v)))
+}
+
+/// Plaintext line connection, reconnected on demand
+pub struct LineSocket {
+    pub name: String,
+    pub addr: String,
+    pub transport: Transport,
+    tcp: Option<TcpStream>,
+    udp: Option<UdpSocket>,
+    reconnect_interval: Option<Instant>,
+}
+
+impl LineSocket {
+    pub fn new(name: String, addr: String, transport: Transport) -> Self {
+        Self {
+            name,
+            addr,
+            transport,
+            tcp: None,
+            udp: None,
+            reconnect_interval: None,
+        }
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        if self.tcp.is_some() || self.udp.is_some() {
+            return Ok(());
+        }
+        if self
+            .reconnect_interval
+            .is_some_and(|i| i.elapsed() < Duration::from_secs(TSDB_RECONNECT_INTERVAL_SECS))
+        {
+            return Err("not connected".into());
+        }
+        self.reconnect_interval = Some(Instant::now());
+        match self.transport {
+            Transport::Udp => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(&self.addr).await?;
+                self.udp = Some(socket);
+            }
+            Transport::Http => return Err("http is not a line protocol transport".into()),
+            Transport::Tcp => {
+                let stream = timeout(Duration::from_secs(TSDB_TIMEOUT_SECS), TcpStream::connect(&self.addr)).await??;
+                info!("<i>{}</>: connected to <u>{}</>", self.name, self.addr);
+                self.tcp = Some(stream);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the newline terminated lines
+    pub async fn send(&mut self, lines: &[String]) -> Result<()> {
+        self.connect().await?;
+        if let Some(socket) = &self.udp {
+            let mut datagram = String::new();
+            for line in lines {
+                if !datagram.is_empty() && datagram.len() + line.len() > TSDB_UDP_MAX_DATAGRAM {
+                    socket.send(datagram.as_bytes()).await?;
+                    datagram.clear();
+                }
+                datagram.push_str(line);
+            }
+            if !datagram.is_empty() {
+                socket.send(datagram.as_bytes()).await?;
+            }
+        }
+        if let Some(stream) = &mut self.tcp {
+            let res: Result<()> =
+                match timeout(Duration::from_secs(TSDB_TIMEOUT_SECS), stream.write_all(lines.concat().as_bytes())).await {
+                    Ok(r) => r.map_err(|e| e.into()),
+                    Err(e) => Err(e.into()),
+                };
+            if let Err(e) = res {
+                //reconnect on the next write
+                self.tcp = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct GraphiteSink {
+    pub name: String,
+    pub prefix: String,
+    socket: LineSocket,
+}
+
+impl GraphiteSink {
+    pub fn new(name: String, addr: String, transport: Transport, prefix: String) -> Self {
+        Self {
+            socket: LineSocket::new(name.clone(), addr, transport),
+            name,
+            prefix,
+        }
+    }
+
+    fn lines(&self, batch: &ReadingBatch) -> Vec<String> {
+        let path = format!("{}.{}", self.prefix, sanitize(&batch.device.name));
+        numeric(batch)
+            .map(|(p, value)| format!("{}.{} {} {}\n", path, sanitize(&p.name), value, p.time / 1000))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Sink for GraphiteSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        if batch.metadata.initial_read {
+            return Ok(());
+        }
+        let lines = self.lines(batch);
+        self.socket.send(&lines).await
+    }
+}
+
+pub struct OpenTsdbSink {
+    pub name: String,
+    pub prefix: String,
+    /// telnet `host:port` or the HTTP base url
+    pub addr: String,
+    pub transport: Transport,
+    socket: LineSocket,
+    client: Client,
+}
+
+fn opentsdb_tags(device: &DeviceInfo) -> Vec<(&'static str, String)> {
+    let mut tags = vec![("inverter", sanitize(&device.name))];
+    if let Some(sn) = &device.serial_number {
+        tags.push(("serial_number", sanitize(sn)));
+    }
+    tags
+}
+
+impl OpenTsdbSink {
+    pub fn new(name: String, addr: String, transport: Transport, prefix: String) -> Self {
+        Self {
+            socket: LineSocket::new(name.clone(), addr.clone(), transport),
+            client: Client::new(),
+            name,
+            prefix,
+            addr,
+            transport,
+        }
+    }
+
+    fn metric(&self, p: &Parameter) -> String {
+        format!("{}.{}", self.prefix, sanitize(&p.name))
+    }
+
+    async fn put_http(&self, batch: &ReadingBatch) -> Result<()> {
+        let tags: serde_json::Map<String, serde_json::Value> = opentsdb_tags(&batch.device)
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.into()))
+            .collect();
+        let datapoints: Vec<_> = numeric(batch)
+            .map(|(p, value)| {
+                json!({
+                    "metric": self.metric(p),
+                    "timestamp": p.time as u64,
+                    "value": value,
+                    "tags": tags,
+                })
+            })
+            .collect();
+        for chunk in datapoints.chunks(OPENTSDB_HTTP_BATCH) {
+            let response = self
+                .client
+                .post(format!("{}/api/put", self.addr.trim_end_matches('/')))
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::Value::from(chunk.to_vec()).to_string())
+                .timeout(Duration::from_secs(TSDB_TIMEOUT_SECS))
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                let status = response.status();
+                return Err(format!("HTTP {}: {}", status, response.text().await.unwrap_or_default().trim()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for OpenTsdbSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        if batch.metadata.initial_read {
+            return Ok(());
+        }
+        if self.transport == Transport::Http {
+            return self.put_http(batch).await;
+        }
+        let tags: Vec<String> = opentsdb_tags(&batch.device)
+            .into_iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        let tags = tags.join(" ");
+        let lines: Vec<String> = numeric(batch)
+            .map(|(p, value)| format!("put {} {} {} {}\n", self.metric(p), p.time, value, tags))
+            .collect();
+        self.socket.send(&lines).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::BatchMetadata;
+    use crate::sun2000::ParamKind;
+    use crate::testutil::{http_server, next};
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    const TIME: u128 = 1_700_000_000_123;
+
+    fn batch(parameters: Vec<Parameter>) -> ReadingBatch {
+        ReadingBatch {
+            device: Arc::new(DeviceInfo {
+                name: "sun2000 #1".into(),
+                serial_number: Some("HV2150012345".into()),
+                ..Default::default()
+            }),
+            timestamp: TIME,
+            parameters,
+            metadata: BatchMetadata {
+                initial_read: false,
+                query_time_ms: 100,
+            },
+            events: vec![],
+        }
+    }
+
+    fn param(name: &str, value: i32, unit: &'static str, gain: u16) -> Parameter {
+        let value = ParamKind::NumberI32(Some(value));
+        Parameter::new_from_string(name.into(), value, TIME, None, Some(unit), gain, 32080, 2, false, true)
+    }
+
+    /// Numeric parameters with one of each kind which is skipped
+    fn parameters() -> Vec<Parameter> {
+        vec![
+            param("active_power", 4250, "W", 1),
+            param("internal_temperature", 453, "°C", 10),
+            param("startup_time", 1_700_000_000, "epoch", 1),
+            Parameter::new("model_name", ParamKind::Text(Some("SUN2000".into())), TIME, None, None, 1, 30000, 15, false, true),
+            Parameter::new("power_factor", ParamKind::NumberI16(Some(999)), TIME, None, None, 1000, 32084, 1, false, false),
+        ]
+    }
+
+    async fn tcp_lines(listener: &TcpListener, count: usize) -> Vec<String> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut received = vec![];
+        while received.len() < count {
+            received.push(lines.next_line().await.unwrap().unwrap());
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn graphite_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut sink = GraphiteSink::new("graphite".into(), addr, Transport::Tcp, "solar".into());
+        let mut initial = batch(parameters());
+        initial.metadata.initial_read = true;
+        sink.write(&initial).await.unwrap();
+        sink.write(&batch(parameters())).await.unwrap();
+        assert_eq!(
+            tcp_lines(&listener, 2).await,
+            vec![
+                "solar.sun2000__1.active_power 4250 1700000000",
+                "solar.sun2000__1.internal_temperature 45.3 1700000000",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn graphite_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let mut sink = GraphiteSink::new("graphite".into(), addr, Transport::Udp, "solar".into());
+        sink.write(&batch(parameters())).await.unwrap();
+        let mut buf = [0; 2048];
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..len]),
+            "solar.sun2000__1.active_power 4250 1700000000\nsolar.sun2000__1.internal_temperature 45.3 1700000000\n"
+        );
+
+        //split to datagrams on the line boundaries
+        let voltages = (0..100).map(|i| param(&format!("pv_{:02}_voltage", i), 2301, "V", 10)).collect();
+        sink.write(&batch(voltages)).await.unwrap();
+        let mut received = 0;
+        while received < 100 {
+            let len = socket.recv(&mut buf).await.unwrap();
+            assert!(len <= TSDB_UDP_MAX_DATAGRAM, "{} bytes datagram", len);
+            let datagram = String::from_utf8_lossy(&buf[..len]);
+            for line in datagram.lines() {
+                assert_eq!(line, format!("solar.sun2000__1.pv_{:02}_voltage 230.1 1700000000", received));
+                received += 1;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect_interval() {
+        //a closed port
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let mut socket = LineSocket::new("graphite".into(), addr, Transport::Tcp);
+        let lines = ["a 1 1\n".to_string()];
+        assert!(socket.send(&lines).await.is_err());
+        assert_eq!(socket.send(&lines).await.unwrap_err().to_string(), "not connected");
+
+        let mut socket = LineSocket::new("graphite".into(), "127.0.0.1:2003".into(), Transport::Http);
+        assert!(socket.send(&lines).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn opentsdb_telnet() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut sink = OpenTsdbSink::new("opentsdb".into(), addr, Transport::Tcp, "solar".into());
+        sink.write(&batch(parameters())).await.unwrap();
+        assert_eq!(
+            tcp_lines(&listener, 2).await,
+            vec![
+                "put solar.active_power 1700000000123 4250 inverter=sun2000__1 serial_number=HV2150012345",
+                "put solar.internal_temperature 1700000000123 45.3 inverter=sun2000__1 serial_number=HV2150012345",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn opentsdb_http() {
+        let (address, mut requests) = http_server(204, "");
+        let url = format!("http://{}/", address);
+        let mut sink = OpenTsdbSink::new("opentsdb".into(), url, Transport::Http, "solar".into());
+        sink.write(&batch(parameters())).await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!((request.method.as_str(), request.uri.as_str()), ("POST", "/api/put"));
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        let datapoints: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(
+            datapoints[0],
+            json!({
+                "metric": "solar.active_power",
+                "timestamp": 1_700_000_000_123u64,
+                "value": 4250.0,
+                "tags": {"inverter": "sun2000__1", "serial_number": "HV2150012345"},
+            })
+        );
+        assert_eq!(datapoints[1]["value"], 45.3);
+        assert_eq!(datapoints.as_array().unwrap().len(), 2);
+
+        //split to batches
+        let parameters = (0..60).map(|i| param(&format!("param_{}", i), 1, "W", 1)).collect();
+        sink.write(&batch(parameters)).await.unwrap();
+        let sizes = [next(&mut requests).await, next(&mut requests).await]
+            .map(|r| serde_json::from_str::<Value>(&r.body).unwrap().as_array().unwrap().len());
+        assert_eq!(sizes, [OPENTSDB_HTTP_BATCH, 60 - OPENTSDB_HTTP_BATCH]);
+    }
+
+    #[tokio::test]
+    async fn opentsdb_http_error() {
+        let (address, _requests) = http_server(400, r#"{"error":{"code":400,"message":"Unknown metric"}}"#);
+        let url = format!("http://{}", address);
+        let mut sink = OpenTsdbSink::new("opentsdb".into(), url, Transport::Http, "solar".into());
+        let e = sink.write(&batch(parameters())).await.unwrap_err().to_string();
+        assert!(e.starts_with("HTTP 400 Bad Request: {\"error\""), "{}", e);
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::sink::{ReadingBatch, Result, Sink};
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde_json::{json, Map, Value};
+use simplelog::*;
+
+pub const WEBHOOK_RETRY_INTERVAL_SECS: f32 = 10.0; //secs between resending the failed requests
+pub const WEBHOOK_MAX_PENDING: usize = 1000; //failed requests kept for resending
+pub const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebhookFormat {
+    Json,
+    Emoncms,
+}
+
+impl FromStr for WebhookFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "json" => Ok(WebhookFormat::Json),
+            "emoncms" => Ok(WebhookFormat::Emoncms),
+            other => Err(format!("unknown webhook format: {:?}", other)),
+        }
+    }
+}
+
+pub enum WebhookAuth {
+    Basic { username: String, password: Option<String> },
+    Bearer(String),
+}
+
+pub struct WebhookSettings {
+    pub url: String,
+    pub format: WebhookFormat,
+    /// additional request headers
+    pub headers: Vec<(String, String)>,
+    pub auth: Option<WebhookAuth>,
+    /// json payload with `%placeholders%`, see hard.conf
+    pub template: Option<String>,
+    /// emoncms node name
+    pub node: String,
+}
+
+/// Request body ready to be (re)sent
+enum Payload {
+    Json(String),
+    Form(Vec<(String, String)>),
+}
+
+/// Reason of the unsent request
+enum Failure {
+    /// transport error, 5xx, 408 or 429: worth resending
+    Temporary(String),
+    /// rejected by the server, resending would not help
+    Rejected(String),
+}
+
+impl From<reqwest::Error> for Failure {
+    fn from(e: reqwest::Error) -> Self {
+        Failure::Temporary(e.to_string())
+    }
+}
+
+pub struct WebhookSink {
+    pub name: String,
+    pub settings: WebhookSettings,
+    client: Client,
+    pending: VecDeque<Payload>,
+    dropped: u64,
+    retry_interval: Option<Instant>,
+}
+
+/// Parses the `Name: value, Name2: value2` header list
+pub fn parse_headers(list: &str) -> Vec<(String, String)> {
+    list.split(',')
+        .filter_map(|h| h.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+fn parameters_object(batch: &ReadingBatch) -> Map<String, Value> {
+    batch
+        .parameters
+        .iter()
+        .filter(|p| p.save_to_influx)
+        .map(|p| (p.name.clone(), p.get_json_value()))
+        .collect()
+}
+
+impl WebhookSink {
+    pub fn new(name: String, settings: WebhookSettings) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &settings.headers {
+            headers.insert(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+        }
+        let client = Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+            .build()?;
+        Ok(Self {
+            name,
+            settings,
+            client,
+            pending: VecDeque::new(),
+            dropped: 0,
+            retry_interval: None,
+        })
+    }
+
+    /// Replaces the `%placeholders%` in the template: `%time%` (ms), `%time_secs%`,
+    /// `%inverter%`, `%serial_number%`, `%parameters%` (json object) and `%<parameter name>%`
+    fn render_template(template: &str, batch: &ReadingBatch, parameters: &Map<String, Value>) -> String {
+        let mut out = template
+            .replace("%time%", &batch.timestamp.to_string())
+            .replace("%time_secs%", &(batch.timestamp / 1000).to_string())
+            .replace("%inverter%", &json!(batch.device.name).to_string())
+            .replace("%serial_number%", &json!(batch.device.serial_number).to_string())
+            .replace("%parameters%", &Value::Object(parameters.clone()).to_string());
+        for (name, value) in parameters {
+            out = out.replace(&format!("%{}%", name), &value.to_string());
+        }
+        out
+    }
+
+    fn payload(&self, batch: &ReadingBatch) -> Payload {
+        let parameters = parameters_object(batch);
+        match self.settings.format {
+            WebhookFormat::Json => Payload::Json(match &self.settings.template {
+                Some(template) => WebhookSink::render_template(template, batch, &parameters),
+                None => json!({
+                    "time": batch.timestamp as u64,
+                    "inverter": batch.device.name,
+                    "serial_number": batch.device.serial_number,
+                    "parameters": parameters,
+                })
+                .to_string(),
+            }),
+            WebhookFormat::Emoncms => {
+                //emoncms inputs have to be numeric
+                let inputs: Map<String, Value> = parameters.into_iter().filter(|(_, v)| v.is_number()).collect();
+                Payload::Form(vec![
+                    ("node".into(), self.settings.node.clone()),
+                    ("time".into(), (batch.timestamp / 1000).to_string()),
+                    ("fulljson".into(), Value::Object(inputs).to_string()),
+                ])
+            }
+        }
+    }
+
+    fn request(&self, payload: &Payload) -> RequestBuilder {
+        let request = self.client.post(&self.settings.url);
+        let request = match &self.settings.auth {
+            Some(WebhookAuth::Basic { username, password }) => request.basic_auth(username, password.as_ref()),
+            Some(WebhookAuth::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        };
+        match payload {
+            Payload::Json(body) => request.header(CONTENT_TYPE, "application/json").body(body.clone()),
+            Payload::Form(form) => request.form(form),
+        }
+    }
+
+    async fn send(&self, payload: &Payload) -> std::result::Result<(), Failure> {
+        let response = self.request(payload).send().await?;
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            let e = format!("HTTP {}: {}", status, body.trim());
+            return Err(match status {
+                StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => Failure::Temporary(e),
+                //bad payload, url or credentials
+                s if s.is_client_error() => Failure::Rejected(e),
+                _ => Failure::Temporary(e),
+            });
+        }
+        //emoncms is reporting the errors with 200 OK
+        if self.settings.format == WebhookFormat::Emoncms && body.replace(' ', "").contains("\"success\":false") {
+            return Err(Failure::Rejected(body.trim().to_string()));
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, payload: Payload) {
+        self.pending.push_back(payload);
+        if self.pending.len() > WEBHOOK_MAX_PENDING {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    /// Resends the failed requests in order, stops on the first temporary error
+    async fn resend_pending(&mut self) -> Result<()> {
+        let count = self.pending.len();
+        while let Some(payload) = self.pending.front() {
+            match self.send(payload).await {
+                Ok(()) => {}
+                Err(Failure::Temporary(e)) => return Err(e.into()),
+                Err(Failure::Rejected(e)) => {
+                    error!("<i>{}</>: pending request rejected, dropping: <b>{}</>", self.name, e);
+                    self.dropped += 1;
+                }
+            }
+            self.pending.pop_front();
+        }
+        info!("<i>{}</>: <b>{}</> pending requests sent", self.name, count);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write(&mut self, batch: &ReadingBatch) -> Result<()> {
+        if batch.metadata.initial_read {
+            return Ok(());
+        }
+        let payload = self.payload(batch);
+        //keep the order: new data is waiting for the older requests
+        if !self.pending.is_empty() {
+            self.queue(payload);
+            return Ok(());
+        }
+        match self.send(&payload).await {
+            Ok(()) => Ok(()),
+            Err(Failure::Temporary(e)) => {
+                self.queue(payload);
+                self.retry_interval = Some(Instant::now());
+                Err(e.into())
+            }
+            Err(Failure::Rejected(e)) => {
+                self.dropped += 1;
+                Err(format!("request rejected, dropping: {}", e).into())
+            }
+        }
+    }
+
+    async fn tick(&mut self) -> Result<()> {
+        if self.pending.is_empty()
+            || self
+                .retry_interval
+                .is_some_and(|i| i.elapsed() < Duration::from_secs_f32(WEBHOOK_RETRY_INTERVAL_SECS))
+        {
+            return Ok(());
+        }
+        self.retry_interval = Some(Instant::now());
+        self.resend_pending()
+            .await
+            .map_err(|e| format!("resend error ({} pending): {}", self.pending.len(), e).into())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        if !self.pending.is_empty() || self.dropped > 0 {
+            warn!(
+                "<i>{}</>: <b>{}</> requests not sent, <b>{}</> dropped",
+                self.name,
+                self.pending.len(),
+                self.dropped
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::BatchMetadata;
+    use crate::sun2000::{DeviceInfo, ParamKind, Parameter};
+    use crate::testutil::{http_server, next};
+    use std::sync::Arc;
+
+    fn settings(address: std::net::SocketAddr, format: WebhookFormat) -> WebhookSettings {
+        WebhookSettings {
+            url: format!("http://{}/input", address),
+            format,
+            headers: vec![],
+            auth: None,
+            template: None,
+            node: "solar".into(),
+        }
+    }
+
+    fn batch(power: i32) -> ReadingBatch {
+        ReadingBatch {
+            device: Arc::new(DeviceInfo {
+                name: "sun2000".into(),
+                serial_number: Some("HV2150012345".into()),
+                ..Default::default()
+            }),
+            timestamp: 1_700_000_000_123,
+            parameters: vec![
+                Parameter::new("active_power", ParamKind::NumberI32(Some(power)), 0, None, Some("W"), 1, 32080, 2, false, true),
+                Parameter::new("model_name", ParamKind::Text(Some("SUN2000".into())), 0, None, None, 1, 30000, 15, false, true),
+                Parameter::new("power_factor", ParamKind::NumberI16(Some(999)), 0, None, None, 1000, 32084, 1, false, false),
+            ],
+            metadata: BatchMetadata {
+                initial_read: false,
+                query_time_ms: 100,
+            },
+            events: vec![],
+        }
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(
+            parse_headers("X-Api-Key: abc, X-Empty:,: value, broken"),
+            vec![("X-Api-Key".into(), "abc".into()), ("X-Empty".into(), String::new())]
+        );
+    }
+
+    #[tokio::test]
+    async fn json() {
+        let (address, mut requests) = http_server(200, "");
+        let mut settings = settings(address, WebhookFormat::Json);
+        settings.headers = vec![("X-Api-Key".into(), "abc".into())];
+        settings.auth = Some(WebhookAuth::Bearer("token".into()));
+        let mut sink = WebhookSink::new("webhook".into(), settings).unwrap();
+        sink.write(&batch(4250)).await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!((request.method.as_str(), request.uri.as_str()), ("POST", "/input"));
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(request.header("x-api-key"), Some("abc"));
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "time": 1_700_000_000_123u64,
+                "inverter": "sun2000",
+                "serial_number": "HV2150012345",
+                "parameters": {"active_power": 4250, "model_name": "SUN2000"},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn template() {
+        let (address, mut requests) = http_server(200, "");
+        let mut settings = settings(address, WebhookFormat::Json);
+        settings.template = Some(r#"{"ts": %time_secs%, "id": %serial_number%, "power": %active_power%}"#.into());
+        settings.auth = Some(WebhookAuth::Basic {
+            username: "user".into(),
+            password: Some("pass".into()),
+        });
+        let mut sink = WebhookSink::new("webhook".into(), settings).unwrap();
+        sink.write(&batch(4250)).await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!(request.body, r#"{"ts": 1700000000, "id": "HV2150012345", "power": 4250}"#);
+        assert_eq!(request.header("authorization"), Some("Basic dXNlcjpwYXNz"));
+    }
+
+    #[tokio::test]
+    async fn emoncms() {
+        let (address, mut requests) = http_server(200, "ok");
+        let mut sink = WebhookSink::new("webhook".into(), settings(address, WebhookFormat::Emoncms)).unwrap();
+        sink.write(&batch(4250)).await.unwrap();
+        let request = next(&mut requests).await;
+        assert_eq!(request.header("content-type"), Some("application/x-www-form-urlencoded"));
+        //only the numeric inputs
+        assert_eq!(request.body, "node=solar&time=1700000000&fulljson=%7B%22active_power%22%3A4250%7D");
+
+        let (address, _requests) = http_server(200, r#"{"success": false, "message": "Invalid apikey"}"#);
+        let mut sink = WebhookSink::new("webhook".into(), settings(address, WebhookFormat::Emoncms)).unwrap();
+        assert!(sink.write(&batch(4250)).await.unwrap_err().to_string().contains("Invalid apikey"));
+    }
+
+    #[tokio::test]
+    async fn resending() {
+        let (address, mut requests) = http_server(503, "maintenance");
+        let mut sink = WebhookSink::new("webhook".into(), settings(address, WebhookFormat::Json)).unwrap();
+        let e = sink.write(&batch(1)).await.unwrap_err().to_string();
+        assert_eq!(e, "HTTP 503 Service Unavailable: maintenance");
+        next(&mut requests).await;
+        //queued behind the failed one, not sent now
+        sink.write(&batch(2)).await.unwrap();
+        sink.tick().await.unwrap();
+        assert!(requests.try_recv().is_err());
+        assert_eq!(sink.pending.len(), 2);
+
+        //the server is back, resent in order after the retry interval
+        let (address, mut requests) = http_server(200, "");
+        sink.settings.url = format!("http://{}/input", address);
+        sink.retry_interval = Some(Instant::now() - Duration::from_secs_f32(WEBHOOK_RETRY_INTERVAL_SECS));
+        sink.tick().await.unwrap();
+        for power in [1, 2] {
+            let body: Value = serde_json::from_str(&next(&mut requests).await.body).unwrap();
+            assert_eq!(body["parameters"]["active_power"], power);
+        }
+        assert!(sink.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected() {
+        let (address, mut requests) = http_server(400, "unknown field");
+        let mut sink = WebhookSink::new("webhook".into(), settings(address, WebhookFormat::Json)).unwrap();
+        let e = sink.write(&batch(1)).await.unwrap_err().to_string();
+        assert_eq!(e, "request rejected, dropping: HTTP 400 Bad Request: unknown field");
+        next(&mut requests).await;
+        assert!(sink.pending.is_empty());
+        assert_eq!(sink.dropped, 1);
+        //not waiting for the dropped one
+        assert!(sink.write(&batch(2)).await.is_err());
+        next(&mut requests).await;
+
+        //the server is asking to come back later
+        for status in [408, 429] {
+            let (address, mut requests) = http_server(status, "");
+            let mut sink = WebhookSink::new("webhook".into(), settings(address, WebhookFormat::Json)).unwrap();
+            assert!(sink.write(&batch(1)).await.is_err());
+            next(&mut requests).await;
+            assert_eq!(sink.pending.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_rejected() {
+        let (address, mut requests) = http_server(503, "");
+        let mut sink = WebhookSink::new("webhook".into(), settings(address, WebhookFormat::Json)).unwrap();
+        assert!(sink.write(&batch(1)).await.is_err());
+        sink.write(&batch(2)).await.unwrap();
+        next(&mut requests).await;
+
+        //eg. the credentials were revoked meanwhile
+        let (address, mut requests) = http_server(401, "");
+        sink.settings.url = format!("http://{}/input", address);
+        sink.retry_interval = None;
+        sink.tick().await.unwrap();
+        next(&mut requests).await;
+        next(&mut requests).await;
+        assert!(sink.pending.is_empty());
+        assert_eq!(sink.dropped, 2);
+    }
+}